@@ -0,0 +1,155 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::error::{Error, ErrorKind};
+use crate::parser::Expression;
+
+// Limits on what an evaluation may use, for running code that can't be
+// trusted to stop. A limit that is `None` doesn't apply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    // The number of procedure calls, builtins included. Every loop goes
+    // through calls, so this bounds the running time.
+    pub steps: Option<u64>,
+    // The number of values made: scopes for calls and `let` forms, and the
+    // elements of the lists and strings that builtins return
+    pub allocations: Option<u64>,
+    // The number of procedure calls that may be in progress at once. Tail
+    // calls don't add to it.
+    pub depth: Option<usize>,
+    pub deadline: Option<Instant>,
+}
+
+// How often the deadline is looked at, in steps
+const CLOCK_INTERVAL: u64 = 64;
+
+// The budget of the evaluation that is running, with what it has used so far
+struct Meter {
+    budget: Cell<Budget>,
+    steps: Cell<u64>,
+    allocations: Cell<u64>,
+}
+
+thread_local! {
+    static METER: Meter = const {
+        Meter {
+            budget: Cell::new(Budget {
+                steps: None,
+                allocations: None,
+                depth: None,
+                deadline: None,
+            }),
+            steps: Cell::new(0),
+            allocations: Cell::new(0),
+        }
+    };
+}
+
+thread_local! {
+    // Set from another thread, such as the one that handles Ctrl-C, to stop
+    // the evaluation running on this one
+    static INTERRUPT: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+// The flag that interrupts the evaluation running on the current thread when
+// it is set
+pub fn interrupt_flag() -> Arc<AtomicBool> {
+    INTERRUPT.with(Arc::clone)
+}
+
+fn exceeded(what: &str, limit: u64) -> Error {
+    Error::new(ErrorKind::Limit, format!("{} limit exceeded:", what))
+        .with_irritants(vec![Expression::Number(limit as f64)])
+}
+
+// Run an evaluation under a budget. Evaluations inside it, such as those of
+// macro transformers, share it.
+pub(crate) fn with_budget<R>(budget: Budget, run: impl FnOnce() -> R) -> R {
+    struct Restore(Budget, u64, u64);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            METER.with(|meter| {
+                meter.budget.set(self.0);
+                meter.steps.set(self.1);
+                meter.allocations.set(self.2);
+            });
+        }
+    }
+
+    // An interrupt that came while nothing was running is for nobody
+    INTERRUPT.with(|flag| flag.store(false, Ordering::Relaxed));
+
+    let _restore = METER.with(|meter| {
+        Restore(
+            meter.budget.replace(budget),
+            meter.steps.replace(0),
+            meter.allocations.replace(0),
+        )
+    });
+
+    run()
+}
+
+// Stop if the interrupt flag has been set since the last look
+pub(crate) fn check_interrupt() -> Result<(), Error> {
+    let interrupted =
+        INTERRUPT.with(|flag| flag.load(Ordering::Relaxed) && flag.swap(false, Ordering::Relaxed));
+    if interrupted {
+        return Err(Error::new(ErrorKind::Interrupted, "Interrupted"));
+    }
+    Ok(())
+}
+
+// Account for a procedure call or macro use made with `depth` calls in
+// progress, which is only counted when there is a limit on it
+pub(crate) fn step(depth: impl Fn() -> usize) -> Result<(), Error> {
+    check_interrupt()?;
+
+    METER.with(|meter| {
+        let budget = meter.budget.get();
+        let steps = meter.steps.get() + 1;
+        meter.steps.set(steps);
+
+        match budget {
+            Budget {
+                steps: Some(limit), ..
+            } if steps > limit => Err(exceeded("Step", limit)),
+            Budget {
+                allocations: Some(limit),
+                ..
+            } if meter.allocations.get() > limit => Err(exceeded("Allocation", limit)),
+            Budget {
+                depth: Some(limit), ..
+            } if depth() > limit => Err(exceeded("Depth", limit as u64)),
+            Budget {
+                deadline: Some(deadline),
+                ..
+            } if steps % CLOCK_INTERVAL == 0 && Instant::now() >= deadline => {
+                Err(Error::new(ErrorKind::Limit, "Time limit exceeded"))
+            }
+            _ => Ok(()),
+        }
+    })
+}
+
+// Account for `count` new values. Going over the limit is reported at the
+// next step.
+pub(crate) fn allocate(count: usize) {
+    METER.with(|meter| {
+        meter
+            .allocations
+            .set(meter.allocations.get().saturating_add(count as u64))
+    });
+}
+
+// Account for the values in the result of a builtin
+pub(crate) fn allocate_result(value: &Expression) {
+    match value {
+        Expression::List(list) => allocate(list.len()),
+        Expression::String(_) => allocate(1),
+        _ => {}
+    }
+}
@@ -0,0 +1,698 @@
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::env::Environment;
+use crate::error::{Error, ErrorKind, Span};
+use crate::eval::{form_name, grow_stack, is_definition, is_form, let_bindings, rewrite_do};
+use crate::parser::Expression;
+use crate::symbol::Symbol;
+use crate::vm::{Code, Instruction};
+
+// The compiler handles the core forms of the language. A program that uses
+// anything else, such as continuations, exceptions, extended parameter lists
+// or quasiquote, or that is malformed, is left to the tree-walking evaluator,
+// which also reports its errors, unless the engine is strict about it.
+pub(crate) struct Unsupported;
+
+type Compiled = Result<(), Unsupported>;
+
+struct Compiler<'a> {
+    globals: &'a Environment,
+    // The variables of the procedures being compiled, innermost last
+    scopes: Vec<Vec<Symbol>>,
+    instructions: Vec<Instruction>,
+    // The instructions of each call that has a span, innermost last
+    spans: Vec<(Range<usize>, Span)>,
+    // The form or procedure the compiler gave up on, and the innermost list
+    // around it that has a span
+    unsupported: Option<Symbol>,
+    unsupported_at: Option<Span>,
+}
+
+// Compile an expanded top-level program. The error tells what the compiler
+// doesn't handle, for when the program is not to be run another way.
+pub(crate) fn compile(expr: &Expression, globals: &Environment) -> Result<Rc<Code>, Error> {
+    let mut compiler = Compiler {
+        globals,
+        scopes: Vec::new(),
+        instructions: Vec::new(),
+        spans: Vec::new(),
+        unsupported: None,
+        unsupported_at: None,
+    };
+
+    if compiler
+        .sequence(std::slice::from_ref(expr), true, true)
+        .is_err()
+    {
+        let error = match compiler.unsupported {
+            Some(name) => Error::new(
+                ErrorKind::Unsupported,
+                "The bytecode compiler does not handle:",
+            )
+            .with_irritants(vec![Expression::Symbol(name)]),
+            None => Error::new(
+                ErrorKind::Unsupported,
+                "The bytecode compiler does not handle this program",
+            ),
+        };
+        return Err(Error {
+            span: compiler.unsupported_at,
+            ..error
+        });
+    }
+    compiler.instructions.push(Instruction::Return);
+
+    Ok(Rc::new(Code {
+        name: None,
+        required: 0,
+        rest: false,
+        frame_size: 0,
+        traced: false,
+        instructions: compiler.instructions,
+        spans: compiler.spans,
+        globals: globals.clone(),
+    }))
+}
+
+fn symbol(name: &str) -> Expression {
+    Expression::Symbol(name.into())
+}
+
+fn splice_begin(exprs: &[Expression], flat: &mut Vec<Expression>) {
+    for expr in exprs {
+        match expr {
+            Expression::List(list) if is_form(list, "begin") => splice_begin(&list[1..], flat),
+            expr => flat.push(expr.clone()),
+        }
+    }
+}
+
+// The names defined directly in a body, including inside spliced `begin` forms
+fn body_definitions(exprs: &[Expression], names: &mut Vec<Symbol>) {
+    for expr in exprs {
+        match expr {
+            Expression::List(list) if is_form(list, "begin") => body_definitions(&list[1..], names),
+            Expression::List(list) if is_form(list, "define") => {
+                let name = match list.get(1) {
+                    Some(Expression::Symbol(name)) => name,
+                    Some(Expression::List(func)) => match func.first() {
+                        Some(Expression::Symbol(name)) => name,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                // Redefining a parameter or an earlier definition reuses its slot
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Compiler<'_> {
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    // Emit a jump whose target is filled in later by `patch`
+    fn emit_jump(&mut self, jump: fn(usize) -> Instruction) -> usize {
+        self.instructions.push(jump(usize::MAX));
+        self.instructions.len() - 1
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.instructions.len();
+        match &mut self.instructions[at] {
+            Instruction::Jump(t)
+            | Instruction::JumpIfFalse(t)
+            | Instruction::JumpIfFalseKeep(t)
+            | Instruction::JumpIfTrueKeep(t)
+            | Instruction::JumpUnlessMember(_, t) => *t = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    // Find a variable in the scopes, as how many scopes out it is and its slot
+    fn resolve(&self, name: &Symbol) -> Option<(usize, usize)> {
+        self.scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                scope
+                    .iter()
+                    .rposition(|s| s == name)
+                    .map(|index| (depth, index))
+            })
+    }
+
+    fn expr(&mut self, expr: &Expression, tail: bool) -> Compiled {
+        match expr {
+            Expression::Symbol(name) => self.variable(name, false),
+            Expression::List(list) => {
+                let start = self.instructions.len();
+                let compiled = grow_stack(|| self.list(list, tail));
+                if compiled.is_err() && self.unsupported_at.is_none() {
+                    self.unsupported_at = list.span();
+                }
+                compiled?;
+                if let Some(span) = list.span() {
+                    self.spans.push((start..self.instructions.len(), span));
+                }
+                Ok(())
+            }
+            Expression::Function(_) | Expression::CaseLambda(_) | Expression::Macro(_) => {
+                Err(Unsupported)
+            }
+            _ => {
+                self.emit(Instruction::Const(expr.clone()));
+                Ok(())
+            }
+        }
+    }
+
+    fn variable(&mut self, name: &Symbol, operator: bool) -> Compiled {
+        match self.resolve(name) {
+            Some((depth, index)) => self.emit(Instruction::LocalRef {
+                depth,
+                index,
+                name: name.clone(),
+            }),
+            None => {
+                // Control procedures need the tree-walker's stack of frames
+                if matches!(
+                    self.globals.get(name),
+                    Some(Expression::Control(_) | Expression::Continuation(_))
+                ) {
+                    self.unsupported = Some(name.clone());
+                    return Err(Unsupported);
+                }
+                self.emit(Instruction::GlobalRef {
+                    name: name.clone(),
+                    operator,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Compile a sequence of expressions, leaving the value of the last one.
+    // In a body, `begin` forms are spliced.
+    fn sequence(&mut self, exprs: &[Expression], body: bool, tail: bool) -> Compiled {
+        let mut flat = Vec::new();
+        if body {
+            splice_begin(exprs, &mut flat);
+        } else {
+            flat.extend(exprs.iter().cloned());
+        }
+
+        if flat.is_empty() {
+            self.emit(Instruction::Const(Expression::Void));
+            return Ok(());
+        }
+
+        let last = flat.len() - 1;
+        for (i, expr) in flat.iter().enumerate() {
+            if i != last {
+                self.expr(expr, false)?;
+                self.emit(Instruction::Pop);
+            } else {
+                self.expr(expr, tail)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        let first = list.first().ok_or(Unsupported)?;
+
+        if let Some(form) = form_name(first) {
+            match form {
+                "define" => return self.define(list),
+                "set!" => return self.set(list),
+                "lambda" => return self.lambda(list, None),
+                "if" => return self.if_form(list, tail),
+                "cond" => return self.cond(list, tail),
+                "case" => return self.case(list, tail),
+                "when" | "unless" => return self.when(list, tail),
+                "and" => return self.and_or(&list[1..], true, tail),
+                "or" => return self.and_or(&list[1..], false, tail),
+                "begin" => return self.begin(list, tail),
+                "do" => {
+                    let expr = rewrite_do(list).map_err(|_| Unsupported)?;
+                    return self.expr(&expr, tail);
+                }
+                "let" => return self.let_form(list, tail),
+                "quote" => {
+                    return match list {
+                        [_, datum] => {
+                            self.emit(Instruction::Const(datum.clone()));
+                            Ok(())
+                        }
+                        _ => Err(Unsupported),
+                    }
+                }
+                "define*" | "lambda*" | "case-lambda" | "quasiquote" | "syntax" | "syntax-case"
+                | "macroexpand" | "macroexpand-1" | "guard" | "reset" | "shift" => {
+                    self.unsupported = Some(form.into());
+                    return Err(Unsupported);
+                }
+                _ => {}
+            }
+        }
+
+        let name = match first {
+            Expression::Symbol(s) => {
+                self.variable(s, true)?;
+                Some(s.clone())
+            }
+            operator => {
+                self.expr(operator, false)?;
+                None
+            }
+        };
+
+        for arg in &list[1..] {
+            self.expr(arg, false)?;
+        }
+
+        self.call(list.len() - 1, name, tail);
+        Ok(())
+    }
+
+    fn call(&mut self, argc: usize, name: Option<Symbol>, tail: bool) {
+        if tail {
+            self.emit(Instruction::TailCall(argc, name));
+        } else {
+            self.emit(Instruction::Call(argc, name));
+        }
+    }
+
+    fn define(&mut self, list: &[Expression]) -> Compiled {
+        if list.len() < 3 {
+            return Err(Unsupported);
+        }
+
+        let name = match &list[1] {
+            Expression::List(func) => match func.split_first() {
+                Some((Expression::Symbol(name), params)) => {
+                    let mut lambda =
+                        vec![symbol("lambda"), Expression::List(params.to_vec().into())];
+                    lambda.extend_from_slice(&list[2..]);
+                    self.lambda(&lambda, Some(name.clone()))?;
+                    name
+                }
+                _ => return Err(Unsupported),
+            },
+            Expression::Symbol(name) => {
+                self.expr(&list[2], false)?;
+                name
+            }
+            _ => return Err(Unsupported),
+        };
+
+        if self.scopes.is_empty() {
+            self.emit(Instruction::GlobalDefine(name.clone()));
+        } else {
+            // Only the definitions found in the body have a slot
+            let index = self
+                .scopes
+                .last()
+                .and_then(|scope| scope.iter().rposition(|s| s == name))
+                .ok_or(Unsupported)?;
+            self.emit(Instruction::LocalDefine {
+                index,
+                name: name.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, list: &[Expression]) -> Compiled {
+        let (name, expr) = match list {
+            [_, Expression::Symbol(name), expr] => (name, expr),
+            _ => return Err(Unsupported),
+        };
+
+        self.expr(expr, false)?;
+        match self.resolve(name) {
+            Some((depth, index)) => self.emit(Instruction::LocalSet {
+                depth,
+                index,
+                name: name.clone(),
+            }),
+            None => self.emit(Instruction::GlobalSet(name.clone())),
+        }
+        Ok(())
+    }
+
+    fn lambda(&mut self, list: &[Expression], name: Option<Symbol>) -> Compiled {
+        let code = self.procedure(list, name, true)?;
+        self.emit(Instruction::MakeClosure(Rc::new(code)));
+        Ok(())
+    }
+
+    // Compile the parameters and body of a `lambda` form into the code of a
+    // procedure
+    fn procedure(
+        &mut self,
+        list: &[Expression],
+        name: Option<Symbol>,
+        traced: bool,
+    ) -> Result<Code, Unsupported> {
+        if list.len() < 3 {
+            return Err(Unsupported);
+        }
+
+        let mut scope = Vec::new();
+        let mut rest = false;
+        match &list[1] {
+            Expression::Symbol(name) => {
+                scope.push(name.clone());
+                rest = true;
+            }
+            Expression::List(params) => {
+                let mut params = params.iter();
+                while let Some(param) = params.next() {
+                    match param {
+                        Expression::Symbol(s) if s == "." => match (params.next(), params.next()) {
+                            (Some(Expression::Symbol(name)), None) => {
+                                scope.push(name.clone());
+                                rest = true;
+                            }
+                            _ => return Err(Unsupported),
+                        },
+                        Expression::Symbol(name) => scope.push(name.clone()),
+                        _ => return Err(Unsupported),
+                    }
+                }
+            }
+            _ => return Err(Unsupported),
+        }
+
+        let required = scope.len() - usize::from(rest);
+        let body = &list[2..];
+        body_definitions(body, &mut scope);
+
+        let outer = mem::take(&mut self.instructions);
+        let outer_spans = mem::take(&mut self.spans);
+        self.scopes.push(scope);
+        let compiled = self.sequence(body, true, true);
+        let scope = self.scopes.pop().unwrap_or_default();
+        let mut instructions = mem::replace(&mut self.instructions, outer);
+        let spans = mem::replace(&mut self.spans, outer_spans);
+        compiled?;
+        instructions.push(Instruction::Return);
+
+        Ok(Code {
+            name: name.map(|name| name.to_string()),
+            required,
+            rest,
+            frame_size: scope.len(),
+            traced,
+            instructions,
+            spans,
+            globals: self.globals.clone(),
+        })
+    }
+
+    fn if_form(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        if list.len() < 3 || list.len() > 4 {
+            return Err(Unsupported);
+        }
+
+        self.expr(&list[1], false)?;
+        let to_alternative = self.emit_jump(Instruction::JumpIfFalse);
+        self.expr(&list[2], tail)?;
+        let to_end = self.emit_jump(Instruction::Jump);
+        self.patch(to_alternative);
+        match list.get(3) {
+            Some(alternative) => self.expr(alternative, tail)?,
+            None => self.emit(Instruction::Const(Expression::Void)),
+        }
+        self.patch(to_end);
+        Ok(())
+    }
+
+    // Call the procedure of a `=>` clause with the value below it on the stack
+    fn receiver(&mut self, receiver: &Expression, tail: bool) -> Compiled {
+        self.expr(receiver, false)?;
+        self.emit(Instruction::Swap);
+        let name = match receiver {
+            Expression::Symbol(s) => Some(s.clone()),
+            _ => None,
+        };
+        self.call(1, name, tail);
+        Ok(())
+    }
+
+    fn cond(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        let clauses = &list[1..];
+        let mut to_end = Vec::new();
+
+        for (i, clause) in clauses.iter().enumerate() {
+            let clause = match clause {
+                Expression::List(clause) if !clause.is_empty() => clause,
+                _ => return Err(Unsupported),
+            };
+
+            if clause[0] == symbol("else") {
+                if i != clauses.len() - 1 {
+                    return Err(Unsupported);
+                }
+                self.sequence(&clause[1..], false, tail)?;
+                for jump in to_end {
+                    self.patch(jump);
+                }
+                return Ok(());
+            }
+
+            self.expr(&clause[0], false)?;
+
+            if clause.get(1) == Some(&symbol("=>")) {
+                if clause.len() != 3 {
+                    return Err(Unsupported);
+                }
+                self.emit(Instruction::Dup);
+                let to_next = self.emit_jump(Instruction::JumpIfFalse);
+                self.receiver(&clause[2], tail)?;
+                to_end.push(self.emit_jump(Instruction::Jump));
+                self.patch(to_next);
+                self.emit(Instruction::Pop);
+            } else if clause.len() == 1 {
+                to_end.push(self.emit_jump(Instruction::JumpIfTrueKeep));
+            } else {
+                let to_next = self.emit_jump(Instruction::JumpIfFalse);
+                self.sequence(&clause[1..], false, tail)?;
+                to_end.push(self.emit_jump(Instruction::Jump));
+                self.patch(to_next);
+            }
+        }
+
+        self.emit(Instruction::Const(Expression::Void));
+        for jump in to_end {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    fn case(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        if list.len() < 2 {
+            return Err(Unsupported);
+        }
+
+        self.expr(&list[1], false)?;
+
+        let clauses = &list[2..];
+        let mut to_end = Vec::new();
+        let mut has_else = false;
+
+        for (i, clause) in clauses.iter().enumerate() {
+            let clause = match clause {
+                Expression::List(clause) if clause.len() >= 2 => clause,
+                _ => return Err(Unsupported),
+            };
+
+            let to_next = match &clause[0] {
+                Expression::Symbol(s) if s == "else" => {
+                    if i != clauses.len() - 1 {
+                        return Err(Unsupported);
+                    }
+                    has_else = true;
+                    None
+                }
+                Expression::List(data) => {
+                    let at = self.instructions.len();
+                    self.emit(Instruction::JumpUnlessMember(data.clone(), usize::MAX));
+                    Some(at)
+                }
+                _ => return Err(Unsupported),
+            };
+
+            if clause[1] == symbol("=>") {
+                if clause.len() != 3 {
+                    return Err(Unsupported);
+                }
+                self.receiver(&clause[2], tail)?;
+            } else {
+                self.emit(Instruction::Pop);
+                self.sequence(&clause[1..], false, tail)?;
+            }
+
+            if let Some(to_next) = to_next {
+                to_end.push(self.emit_jump(Instruction::Jump));
+                self.patch(to_next);
+            }
+        }
+
+        if !has_else {
+            self.emit(Instruction::Pop);
+            self.emit(Instruction::Const(Expression::Void));
+        }
+        for jump in to_end {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    fn when(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        if list.len() < 3 {
+            return Err(Unsupported);
+        }
+
+        self.expr(&list[1], false)?;
+        let to_else = self.emit_jump(Instruction::JumpIfFalse);
+
+        // 'when' runs its body on a true test, 'unless' on a false one
+        let (on_true, on_false) = if is_form(list, "when") {
+            (Some(&list[2..]), None)
+        } else {
+            (None, Some(&list[2..]))
+        };
+
+        match on_true {
+            Some(body) => self.sequence(body, false, tail)?,
+            None => self.emit(Instruction::Const(Expression::Void)),
+        }
+        let to_end = self.emit_jump(Instruction::Jump);
+        self.patch(to_else);
+        match on_false {
+            Some(body) => self.sequence(body, false, tail)?,
+            None => self.emit(Instruction::Const(Expression::Void)),
+        }
+        self.patch(to_end);
+        Ok(())
+    }
+
+    // The last expression of `and` and `or` is in tail position and its value
+    // is left as is
+    fn and_or(&mut self, exprs: &[Expression], and: bool, tail: bool) -> Compiled {
+        let last = match exprs.split_last() {
+            Some((last, _)) => last,
+            None => {
+                self.emit(Instruction::Const(Expression::Bool(and)));
+                return Ok(());
+            }
+        };
+
+        let mut to_end = Vec::new();
+        for expr in &exprs[..exprs.len() - 1] {
+            self.expr(expr, false)?;
+            if and {
+                to_end.push(self.emit_jump(Instruction::JumpIfFalseKeep));
+            } else {
+                to_end.push(self.emit_jump(Instruction::JumpIfTrueKeep));
+            }
+        }
+        self.expr(last, tail)?;
+
+        for jump in to_end {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    // `begin` in an expression context, as opposed to the top level or a body
+    fn begin(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        if list.len() < 2 {
+            return Err(Unsupported);
+        }
+
+        if list[1..].iter().any(is_definition) {
+            return Err(Unsupported);
+        }
+
+        self.sequence(&list[1..], false, tail)
+    }
+
+    fn let_form(&mut self, list: &[Expression], tail: bool) -> Compiled {
+        let (name, start) = match list.get(1) {
+            Some(Expression::Symbol(name)) => (Some(name), 2),
+            _ => (None, 1),
+        };
+
+        if list.len() < start + 2 {
+            return Err(Unsupported);
+        }
+
+        let (names, inits) = let_bindings(&list[start]).map_err(|_| Unsupported)?;
+        let body = &list[start + 1..];
+
+        match name {
+            // A named let defines its procedure in a scope of its own, which
+            // the initial values are passed into. The names of the temporaries
+            // contain a space, so they can't clash with a name in the program.
+            Some(name) => {
+                let temps: Vec<Expression> = (0..names.len())
+                    .map(|i| symbol(&format!("let init {}", i)))
+                    .collect();
+
+                let mut lambda = vec![
+                    symbol("lambda"),
+                    Expression::List(names.iter().cloned().map(Expression::Symbol).collect()),
+                ];
+                lambda.extend_from_slice(body);
+
+                let mut call = vec![Expression::Symbol(name.clone())];
+                call.extend(temps.iter().cloned());
+
+                let wrapper = vec![
+                    symbol("lambda"),
+                    Expression::List(temps.into()),
+                    Expression::List(
+                        vec![
+                            symbol("define"),
+                            Expression::Symbol(name.clone()),
+                            Expression::List(lambda.into()),
+                        ]
+                        .into(),
+                    ),
+                    Expression::List(call.into()),
+                ];
+
+                let code = self.procedure(&wrapper, None, false)?;
+                self.emit(Instruction::MakeClosure(Rc::new(code)));
+            }
+            None => {
+                let mut lambda = vec![
+                    symbol("lambda"),
+                    Expression::List(names.iter().cloned().map(Expression::Symbol).collect()),
+                ];
+                lambda.extend_from_slice(body);
+
+                let code = self.procedure(&lambda, None, false)?;
+                self.emit(Instruction::MakeClosure(Rc::new(code)));
+            }
+        }
+
+        for init in &inits {
+            self.expr(init, false)?;
+        }
+        self.call(inits.len(), None, tail);
+        Ok(())
+    }
+}
@@ -1,27 +1,221 @@
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::f64::consts::PI;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
+use crate::error::{Error, ErrorKind};
+use crate::eval::DEFAULT_PROMPT_TAG;
+use crate::expander::{
+    check_symbol_name, datum_to_syntax, free_identifier_eq, gensym, strip_syntax,
+};
+use crate::gc::{self, Traced, Tracer};
 use crate::operator_utils::*;
-use crate::parser::Expression;
+use crate::parser::{Arity, Builtin, Control, Expression};
+use crate::resolver::Reference;
+use crate::symbol::Symbol;
 
-#[derive(Debug, Clone, PartialEq)]
+// A chain of scopes. Procedures share the scopes they were created in rather
+// than copying them, so definitions made later are visible to them.
+#[derive(Clone)]
 pub struct Environment {
-    contents: HashMap<String, Expression>,
+    frame: Rc<Frame>,
+}
+
+struct Frame {
+    contents: RefCell<Bindings>,
+    parent: Option<Rc<Frame>>,
+}
+
+// The global scope holds the whole standard library, so it is a hash table.
+// The scope of a procedure call only holds its parameters and internal
+// definitions, which are quicker to find in a small vector.
+enum Bindings {
+    Global(HashMap<Symbol, Expression>),
+    Local(Vec<(Symbol, Expression)>),
+}
+
+impl Bindings {
+    fn get_mut(&mut self, k: &Symbol) -> Option<&mut Expression> {
+        match self {
+            Bindings::Global(map) => map.get_mut(k),
+            Bindings::Local(slots) => slots
+                .iter_mut()
+                .find(|(name, _)| name == k)
+                .map(|(_, value)| value),
+        }
+    }
+
+    fn get(&self, k: &Symbol) -> Option<&Expression> {
+        match self {
+            Bindings::Global(map) => map.get(k),
+            Bindings::Local(slots) => slots
+                .iter()
+                .find(|(name, _)| name == k)
+                .map(|(_, value)| value),
+        }
+    }
+
+    fn insert(&mut self, k: Symbol, v: Expression) {
+        match self.get_mut(&k) {
+            Some(value) => *value = v,
+            None => match self {
+                Bindings::Global(map) => {
+                    map.insert(k, v);
+                }
+                Bindings::Local(slots) => slots.push((k, v)),
+            },
+        }
+    }
+}
+
+impl Frame {
+    // Find a binding in this scope or the ones around it
+    fn get(&self, k: &Symbol) -> Option<Expression> {
+        let mut frame = Some(self);
+
+        while let Some(current) = frame {
+            if let Some(value) = current.contents.borrow().get(k) {
+                return Some(value.clone());
+            }
+            frame = current.parent.as_deref();
+        }
+
+        None
+    }
+}
+
+impl Traced for Frame {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(parent) = &self.parent {
+            tracer.edge(parent);
+        }
+        // A scope that is being changed is left out, which keeps what it
+        // refers to alive
+        if let Ok(contents) = self.contents.try_borrow() {
+            match &*contents {
+                Bindings::Global(map) => map.values().for_each(|value| tracer.expr(value)),
+                Bindings::Local(slots) => slots.iter().for_each(|(_, value)| tracer.expr(value)),
+            }
+        }
+    }
+
+    fn clear(&self) -> Option<Box<dyn Any>> {
+        let mut contents = self.contents.try_borrow_mut().ok()?;
+        Some(Box::new(mem::replace(
+            &mut *contents,
+            Bindings::Local(Vec::new()),
+        )))
+    }
+
+    fn size(&self) -> usize {
+        let entries = match self.contents.try_borrow().as_deref() {
+            Ok(Bindings::Global(map)) => map.capacity(),
+            Ok(Bindings::Local(slots)) => slots.capacity(),
+            Err(_) => 0,
+        };
+        mem::size_of::<Frame>() + entries * mem::size_of::<(Symbol, Expression)>()
+    }
 }
 
 impl Environment {
+    fn with_frame(frame: Frame) -> Self {
+        let frame = Rc::new(frame);
+        gc::register(&frame);
+        Self { frame }
+    }
+
     fn new() -> Self {
-        Self {
-            contents: HashMap::new(),
+        Self::with_frame(Frame {
+            contents: RefCell::new(Bindings::Global(HashMap::new())),
+            parent: None,
+        })
+    }
+
+    // A new scope inside this one, such as the one for a procedure call
+    pub fn extend(&self) -> Self {
+        Self::with_frame(Frame {
+            contents: RefCell::new(Bindings::Local(Vec::new())),
+            parent: Some(self.frame.clone()),
+        })
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.frame);
+    }
+
+    // Bind a name in the innermost scope
+    pub fn insert(&mut self, k: impl Into<Symbol>, v: Expression) {
+        self.frame.contents.borrow_mut().insert(k.into(), v);
+    }
+
+    // Change the value of an existing binding, in whichever scope holds it.
+    // Returns false if the name is not bound.
+    pub fn set(&self, k: impl Into<Symbol>, v: Expression) -> bool {
+        let k = k.into();
+        let mut frame = Some(&self.frame);
+
+        while let Some(current) = frame {
+            if let Some(value) = current.contents.borrow_mut().get_mut(&k) {
+                *value = v;
+                return true;
+            }
+            frame = current.parent.as_ref();
         }
+
+        false
+    }
+
+    pub fn get(&self, k: impl Into<Symbol>) -> Option<Expression> {
+        self.frame.get(&k.into())
+    }
+
+    // Look up a resolved reference, going straight to the scope it names. If
+    // the binding isn't where it was expected, it is looked up by name.
+    pub(crate) fn lookup(&self, reference: &Reference) -> Option<Expression> {
+        let mut frame = Some(&self.frame);
+        for _ in 0..reference.depth {
+            frame = frame.and_then(|frame| frame.parent.as_ref());
+        }
+
+        let found = frame.and_then(|frame| match reference.slot {
+            Some(slot) => match &*frame.contents.borrow() {
+                Bindings::Local(slots) => slots
+                    .get(slot)
+                    .filter(|(name, _)| *name == reference.name)
+                    .map(|(_, value)| value.clone()),
+                Bindings::Global(_) => None,
+            },
+            None => frame.get(&reference.name),
+        });
+
+        found.or_else(|| self.get(&reference.name))
+    }
+
+    fn insert_builtin(
+        &mut self,
+        name: &'static str,
+        arity: Arity,
+        func: fn(&[Expression]) -> Result<Expression, Error>,
+    ) {
+        let builtin = Builtin { name, arity, func };
+        self.insert(name.to_string(), Expression::Func(builtin));
     }
+}
 
-    pub fn insert(&mut self, k: String, v: Expression) {
-        self.contents.insert(k, v);
+// Environments can contain procedures that refer back to them, so they are
+// compared by identity and not printed
+impl PartialEq for Environment {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.frame, &other.frame)
     }
+}
 
-    pub fn get(&self, k: &str) -> Option<&Expression> {
-        self.contents.get(k)
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Environment")
     }
 }
 
@@ -30,84 +224,278 @@ pub fn standard_env() -> Environment {
     let mut environment = Environment::new();
 
     // Basic arithmetic operators
-    environment.insert(
-        "+".to_string(),
-        Expression::Func(|args: &[Expression]| match add(args) {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+    environment.insert_builtin("+", Arity::AtLeast(0), add);
+    environment.insert_builtin("-", Arity::AtLeast(1), subtract);
+    environment.insert_builtin("*", Arity::AtLeast(0), multiply);
+    environment.insert_builtin("/", Arity::AtLeast(1), divide);
+
+    // Exponent
+    environment.insert_builtin("pow", Arity::Exactly(2), power);
+
+    // Comparison operators
+    environment.insert_builtin("=", Arity::Exactly(2), |args: &[Expression]| {
+        compare(args, "=")
+    });
+    environment.insert_builtin(">", Arity::Exactly(2), |args: &[Expression]| {
+        compare(args, ">")
+    });
+    environment.insert_builtin("<", Arity::Exactly(2), |args: &[Expression]| {
+        compare(args, "<")
+    });
+    environment.insert_builtin(">=", Arity::Exactly(2), |args: &[Expression]| {
+        compare(args, ">=")
+    });
+    environment.insert_builtin("<=", Arity::Exactly(2), |args: &[Expression]| {
+        compare(args, "<=")
+    });
+
+    // Lists
+    environment.insert_builtin("list", Arity::AtLeast(0), |args: &[Expression]| {
+        Ok(Expression::List(args.to_vec().into()))
+    });
+    environment.insert_builtin("cons", Arity::Exactly(2), cons);
+    environment.insert_builtin("car", Arity::Exactly(1), car);
+    environment.insert_builtin("cdr", Arity::Exactly(1), cdr);
+
+    environment.insert_builtin("null?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(
+            matches!(&args[0], Expression::List(list) if list.is_empty()),
+        ))
+    });
+
+    // Type predicates
+    environment.insert_builtin("number?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(matches!(args[0], Expression::Number(_))))
+    });
+    environment.insert_builtin("symbol?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(matches!(args[0], Expression::Symbol(_))))
+    });
+    environment.insert_builtin("string?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(matches!(args[0], Expression::String(_))))
+    });
+
+    // Symbols
+    environment.insert_builtin("eq?", Arity::Exactly(2), eq);
+    environment.insert_builtin("symbol=?", Arity::AtLeast(1), |args: &[Expression]| {
+        let symbols = args
+            .iter()
+            .map(|arg| match arg {
+                Expression::Symbol(symbol) => Ok(symbol),
+                _ => Err(Error::new(
+                    ErrorKind::Type,
+                    "symbol=? requires symbols as its arguments",
+                )),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Expression::Bool(
+            symbols.windows(2).all(|pair| pair[0] == pair[1]),
+        ))
+    });
+    environment.insert_builtin(
+        "string->symbol",
+        Arity::Exactly(1),
+        |args: &[Expression]| match &args[0] {
+            Expression::String(name) => {
+                check_symbol_name(name)?;
+                Ok(Expression::Symbol(Symbol::intern(name)))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "string->symbol requires a string",
+            )),
+        },
     );
-    environment.insert(
-        "-".to_string(),
-        Expression::Func(|args: &[Expression]| match subtract(args) {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+    environment.insert_builtin(
+        "symbol->string",
+        Arity::Exactly(1),
+        |args: &[Expression]| match &args[0] {
+            Expression::Symbol(symbol) => Ok(Expression::String(symbol.to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "symbol->string requires a symbol",
+            )),
+        },
     );
-    environment.insert(
-        "*".to_string(),
-        Expression::Func(|args: &[Expression]| match multiply(args) {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+
+    // Syntax objects, for procedural macros
+    environment.insert_builtin("identifier?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(matches!(args[0], Expression::Symbol(_))))
+    });
+    environment.insert_builtin(
+        "free-identifier=?",
+        Arity::Exactly(2),
+        |args: &[Expression]| match (&args[0], &args[1]) {
+            (Expression::Symbol(a), Expression::Symbol(b)) => {
+                Ok(Expression::Bool(free_identifier_eq(a, b)))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "free-identifier=? requires two identifiers",
+            )),
+        },
     );
-    environment.insert(
-        "/".to_string(),
-        Expression::Func(|args: &[Expression]| match divide(args) {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+    environment.insert_builtin(
+        "syntax->datum",
+        Arity::Exactly(1),
+        |args: &[Expression]| Ok(strip_syntax(&args[0])),
+    );
+    environment.insert_builtin(
+        "datum->syntax",
+        Arity::Exactly(2),
+        |args: &[Expression]| match &args[0] {
+            Expression::Symbol(context) => Ok(datum_to_syntax(context, &args[1])),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "datum->syntax requires an identifier as its context",
+            )),
+        },
     );
 
-    // Exponent
+    // Continuations
     environment.insert(
-        "pow".to_string(),
-        Expression::Func(|args: &[Expression]| match power(args) {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+        "call-with-current-continuation".to_string(),
+        Expression::Control(Control::CallCC),
     );
-
-    // Comparison operators
+    environment.insert("call/cc".to_string(), Expression::Control(Control::CallCC));
     environment.insert(
-        "=".to_string(),
-        Expression::Func(|args: &[Expression]| match compare(args, "=") {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+        "dynamic-wind".to_string(),
+        Expression::Control(Control::DynamicWind),
     );
 
+    // Delimited continuations
     environment.insert(
-        ">".to_string(),
-        Expression::Func(|args: &[Expression]| match compare(args, ">") {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+        "call-with-continuation-prompt".to_string(),
+        Expression::Control(Control::CallWithPrompt),
     );
-
     environment.insert(
-        "<".to_string(),
-        Expression::Func(|args: &[Expression]| match compare(args, "<") {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+        "abort-current-continuation".to_string(),
+        Expression::Control(Control::Abort),
+    );
+    environment.insert_builtin(
+        "make-continuation-prompt-tag",
+        Arity::Between(0, 1),
+        |args: &[Expression]| match args.first() {
+            Some(Expression::Symbol(name)) => Ok(gensym(name)),
+            Some(_) => Err(Error::new(
+                ErrorKind::Type,
+                "make-continuation-prompt-tag requires a symbol as its name",
+            )),
+            None => Ok(gensym("prompt")),
+        },
+    );
+    environment.insert_builtin(
+        "default-continuation-prompt-tag",
+        Arity::Exactly(0),
+        |_: &[Expression]| Ok(Expression::Symbol(DEFAULT_PROMPT_TAG.into())),
     );
 
-    environment.insert(
-        ">=".to_string(),
-        Expression::Func(|args: &[Expression]| match compare(args, ">=") {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+    // Exceptions
+    for control in [
+        Control::WithHandler,
+        Control::Raise,
+        Control::RaiseContinuable,
+        Control::Error,
+    ] {
+        environment.insert(control.name().to_string(), Expression::Control(control));
+    }
+    environment.insert_builtin(
+        "error-object?",
+        Arity::Exactly(1),
+        |args: &[Expression]| {
+            Ok(Expression::Bool(matches!(
+                args[0],
+                Expression::ErrorObject(_)
+            )))
+        },
+    );
+    environment.insert_builtin(
+        "error-object-message",
+        Arity::Exactly(1),
+        |args: &[Expression]| match &args[0] {
+            Expression::ErrorObject(error) => Ok(Expression::String(error.message.clone())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "error-object-message requires an error object",
+            )),
+        },
     );
+    environment.insert_builtin(
+        "error-object-irritants",
+        Arity::Exactly(1),
+        |args: &[Expression]| match &args[0] {
+            Expression::ErrorObject(error) => Ok(Expression::List(error.irritants.clone().into())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "error-object-irritants requires an error object",
+            )),
+        },
+    );
+    environment.insert_builtin("file-error?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(
+            matches!(&args[0], Expression::ErrorObject(error) if error.kind == ErrorKind::File),
+        ))
+    });
+    environment.insert_builtin("read-error?", Arity::Exactly(1), |args: &[Expression]| {
+        Ok(Expression::Bool(
+            matches!(&args[0], Expression::ErrorObject(error) if error.kind == ErrorKind::Read),
+        ))
+    });
 
-    environment.insert(
-        "<=".to_string(),
-        Expression::Func(|args: &[Expression]| match compare(args, "<=") {
-            Ok(expr) => expr,
-            Err(e) => panic!("{}", e),
-        }),
+    // Fresh uninterned symbols, for define-macro transformers among others
+    environment.insert_builtin(
+        "gensym",
+        Arity::Between(0, 1),
+        |args: &[Expression]| match args.first() {
+            Some(Expression::Symbol(prefix)) => Ok(gensym(prefix)),
+            Some(_) => Err(Error::new(
+                ErrorKind::Type,
+                "gensym requires a symbol as its prefix",
+            )),
+            None => Ok(gensym("g")),
+        },
     );
+    environment.insert_builtin(
+        "generate-uninterned-symbol",
+        Arity::Between(0, 1),
+        |args: &[Expression]| match args.first() {
+            Some(Expression::Symbol(prefix)) => Ok(gensym(prefix)),
+            Some(Expression::String(prefix)) => {
+                check_symbol_name(prefix)?;
+                Ok(gensym(prefix))
+            }
+            Some(_) => Err(Error::new(
+                ErrorKind::Type,
+                "generate-uninterned-symbol requires a symbol or string as its prefix",
+            )),
+            None => Ok(gensym("g")),
+        },
+    );
+
+    // Garbage collection
+    environment.insert_builtin("gc", Arity::Exactly(0), |_: &[Expression]| {
+        Ok(Expression::Number(gc::collect() as f64))
+    });
+    environment.insert_builtin("gc-stats", Arity::Exactly(0), |_: &[Expression]| {
+        let stats = gc::stats();
+        let entry = |name: &str, value: usize| {
+            Expression::List(
+                vec![
+                    Expression::Symbol(name.into()),
+                    Expression::Number(value as f64),
+                ]
+                .into(),
+            )
+        };
+        Ok(Expression::List(
+            vec![
+                entry("objects", stats.objects),
+                entry("bytes", stats.bytes),
+                entry("collections", stats.collections),
+                entry("collected", stats.collected),
+            ]
+            .into(),
+        ))
+    });
 
     // PI constant
     environment.insert("pi".to_string(), Expression::Number(PI));
@@ -0,0 +1,111 @@
+use std::cell::Cell;
+use std::fmt;
+use std::num::NonZeroUsize;
+
+use crate::parser::Expression;
+
+// The error type of the whole interpreter. The same value is what Scheme code
+// sees as an error object when it catches an error with `guard` or
+// `with-exception-handler`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub irritants: Vec<Expression>,
+    pub span: Option<Span>,
+    // The names of the Scheme procedures that were running, innermost first
+    pub stack: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // Malformed source text
+    Parse,
+    // A special form or macro used with the wrong shape
+    Syntax,
+    UnboundVariable,
+    Type,
+    Arity,
+    DivideByZero,
+    // An error signalled by `error`
+    User,
+    // A raised object that no handler caught
+    Raise,
+    // A continuation or prompt used where it cannot be
+    Continuation,
+    // Recursion deeper than the evaluator allows
+    Recursion,
+    // A limit of the evaluation's budget was reached. Handlers don't see
+    // these errors, so code under a budget can't ignore them.
+    Limit,
+    // The evaluation was stopped from outside, as by Ctrl-C in the REPL.
+    // Handlers don't see this either.
+    Interrupted,
+    // A program the bytecode compiler doesn't handle, run on the strict
+    // bytecode engine
+    Unsupported,
+    File,
+    Read,
+}
+
+// A region of the source text, with the line and column where it starts.
+// `source` is the number of the text it is in, which is never zero so that an
+// error with a span takes no more room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub source: NonZeroUsize,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+thread_local! {
+    // Each text that is read on a thread gets the next number
+    static NEXT_SOURCE: Cell<NonZeroUsize> = const { Cell::new(NonZeroUsize::MIN) };
+}
+
+// The number that the next text read on this thread will get, so that the
+// spans in it can be told from those of code read before
+pub fn next_source() -> NonZeroUsize {
+    NEXT_SOURCE.with(Cell::get)
+}
+
+pub(crate) fn new_source() -> NonZeroUsize {
+    NEXT_SOURCE.with(|next| next.replace(next.get().saturating_add(1)))
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Error {
+            kind,
+            message: message.into(),
+            irritants: Vec::new(),
+            span: None,
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn with_irritants(self, irritants: Vec<Expression>) -> Self {
+        Error { irritants, ..self }
+    }
+
+    pub fn with_span(self, span: Span) -> Self {
+        Error {
+            span: Some(span),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for irritant in &self.irritants {
+            write!(f, " {}", irritant)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,136 +1,2405 @@
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::budget::{self, with_budget, Budget};
+use crate::compiler::compile;
 use crate::env::Environment;
-use crate::parser::{parse, Expression, Procedure};
+use crate::error::{Error, ErrorKind, Span};
+use crate::expander::{expand, instantiate_syntax, macroexpand, match_syntax};
+use crate::gc::{Traced, Tracer};
+use crate::parser::{parse, Arity, Control, Expression, Parameters, Procedure};
+use crate::resolver::resolve;
+use crate::symbol::Symbol;
+use crate::vm::{Exit, Vm};
 
-fn eval_define(list: &[Expression], env: &mut Environment) -> Result<Expression, String> {
-    if list.len() < 3 {
-        return Err("'define' requires at least two arguments".into());
+// The evaluator is a machine that either evaluates an expression or returns a
+// value to the innermost frame of its stack. The stack holds the rest of the
+// computation on the heap instead of on the Rust stack, which is what lets
+// call/cc capture it as a value and resume it any number of times.
+enum State {
+    Eval(Expression, Environment),
+    Return(Expression),
+}
+
+// What to do with the value of the expression being evaluated. Lists of
+// expressions that are evaluated one at a time are kept in reverse order so the
+// next one can be popped off. Sequences share the body they run instead, and
+// keep the position of the next expression.
+#[derive(Clone)]
+enum Frame {
+    Sequence {
+        exprs: Rc<[Expression]>,
+        next: usize,
+        body: bool,
+        env: Environment,
+    },
+    Define {
+        name: Symbol,
+        env: Environment,
+    },
+    Set {
+        name: Symbol,
+        env: Environment,
+    },
+    If {
+        consequent: Expression,
+        alternative: Option<Expression>,
+        env: Environment,
+    },
+    Cond {
+        clause: Vec<Expression>,
+        clauses: Vec<Expression>,
+        env: Environment,
+    },
+    Case {
+        clauses: Vec<Expression>,
+        env: Environment,
+    },
+    When {
+        body: Rc<[Expression]>,
+        when: bool,
+        env: Environment,
+    },
+    And {
+        exprs: Vec<Expression>,
+        env: Environment,
+    },
+    Or {
+        exprs: Vec<Expression>,
+        env: Environment,
+    },
+    // Call the procedure of a `=>` clause with the value that selected it
+    Receiver {
+        name: Option<Symbol>,
+        value: Expression,
+    },
+    Let {
+        name: Option<Symbol>,
+        names: Vec<Symbol>,
+        values: Vec<Expression>,
+        inits: Vec<Expression>,
+        body: Rc<[Expression]>,
+        env: Environment,
+    },
+    // The frames of a call keep where it was read from, for errors in its
+    // arguments or in applying it to point at
+    Operator {
+        args: Vec<Expression>,
+        env: Environment,
+        span: Option<Span>,
+    },
+    Arguments {
+        name: Option<Symbol>,
+        proc: Expression,
+        values: Vec<Expression>,
+        args: Vec<Expression>,
+        env: Environment,
+        span: Option<Span>,
+    },
+    Quasiquote {
+        template: Expression,
+        values: Vec<Expression>,
+        exprs: Vec<Expression>,
+        env: Environment,
+    },
+    SyntaxCase {
+        literals: Vec<String>,
+        clauses: Vec<Expression>,
+        env: Environment,
+    },
+    Fender {
+        form: Expression,
+        literals: Vec<String>,
+        clauses: Vec<Expression>,
+        output: Expression,
+        clause_env: Environment,
+        env: Environment,
+    },
+    Macroexpand {
+        once: bool,
+        env: Environment,
+    },
+    // The before thunk of a `dynamic-wind` is running, and the main thunk
+    // runs next
+    WindBefore {
+        winder: Rc<Winder>,
+        thunk: Expression,
+    },
+    // The main thunk of a `dynamic-wind` is running. Continuations that leave
+    // or re-enter it run its after or before thunk.
+    Wind(Rc<Winder>),
+    WindAfter {
+        value: Expression,
+    },
+    // A before or after thunk is running on the way to the frames of a
+    // continuation, with the remaining thunks in `steps`
+    Rewind {
+        steps: Vec<(Expression, Vec<Frame>)>,
+        target: Vec<Frame>,
+        transfer: Transfer,
+    },
+    // Delimits the continuations captured by `shift` and the ones abandoned
+    // by `abort-current-continuation`
+    Prompt {
+        tag: Expression,
+        handler: Option<Expression>,
+    },
+    // An exception handler installed by `with-exception-handler`
+    Handler(Expression),
+    // The body of a `guard` is running. A raised object unwinds to here and
+    // is bound to `var` in the clauses.
+    Guard {
+        var: Symbol,
+        clauses: Vec<Expression>,
+        env: Environment,
+    },
+    // A handler is running, and only the handlers installed before it, `skip`
+    // frames further down the stack, are visible to it
+    Handling {
+        skip: usize,
+    },
+    // The handler of a non-continuable `raise` is running
+    Raised,
+    // A `guard` had no clause for the object it caught, which is raised again
+    // from the dynamic environment of the original `raise`
+    Reraise,
+    // The body of a procedure is running. A tail call replaces the name
+    // rather than pushing another frame, so these make up the call stack
+    // that is reported with errors. `depth` counts the calls in progress,
+    // this one included.
+    Call {
+        name: String,
+        depth: usize,
+    },
+    // Compiled code is waiting for the value of a procedure it handed over
+    Bytecode(Vm),
+}
+
+impl Frame {
+    // Pass the environments and values the frame holds to the garbage
+    // collector. The shared bodies of procedures are left out.
+    fn trace(&self, tracer: &mut Tracer) {
+        let exprs = |tracer: &mut Tracer, exprs: &[Expression]| {
+            exprs.iter().for_each(|expr| tracer.expr(expr))
+        };
+
+        match self {
+            Frame::Sequence { env, .. }
+            | Frame::Define { env, .. }
+            | Frame::Set { env, .. }
+            | Frame::When { env, .. }
+            | Frame::Macroexpand { env, .. } => env.trace(tracer),
+            Frame::If {
+                consequent,
+                alternative,
+                env,
+            } => {
+                tracer.expr(consequent);
+                alternative.iter().for_each(|expr| tracer.expr(expr));
+                env.trace(tracer);
+            }
+            Frame::Cond {
+                clause,
+                clauses,
+                env,
+            } => {
+                exprs(tracer, clause);
+                exprs(tracer, clauses);
+                env.trace(tracer);
+            }
+            Frame::Case { clauses, env }
+            | Frame::SyntaxCase { clauses, env, .. }
+            | Frame::Guard { clauses, env, .. } => {
+                exprs(tracer, clauses);
+                env.trace(tracer);
+            }
+            Frame::And { exprs: items, env }
+            | Frame::Or { exprs: items, env }
+            | Frame::Operator {
+                args: items, env, ..
+            } => {
+                exprs(tracer, items);
+                env.trace(tracer);
+            }
+            Frame::Receiver { value, .. } | Frame::WindAfter { value } => tracer.expr(value),
+            Frame::Let {
+                values, inits, env, ..
+            } => {
+                exprs(tracer, values);
+                exprs(tracer, inits);
+                env.trace(tracer);
+            }
+            Frame::Arguments {
+                proc,
+                values,
+                args,
+                env,
+                ..
+            } => {
+                tracer.expr(proc);
+                exprs(tracer, values);
+                exprs(tracer, args);
+                env.trace(tracer);
+            }
+            Frame::Quasiquote {
+                template,
+                values,
+                exprs: items,
+                env,
+            } => {
+                tracer.expr(template);
+                exprs(tracer, values);
+                exprs(tracer, items);
+                env.trace(tracer);
+            }
+            Frame::Fender {
+                form,
+                clauses,
+                output,
+                clause_env,
+                env,
+                ..
+            } => {
+                tracer.expr(form);
+                exprs(tracer, clauses);
+                tracer.expr(output);
+                clause_env.trace(tracer);
+                env.trace(tracer);
+            }
+            Frame::WindBefore { winder, thunk } => {
+                tracer.edge(winder);
+                tracer.expr(thunk);
+            }
+            Frame::Wind(winder) => tracer.edge(winder),
+            Frame::Rewind {
+                steps,
+                target,
+                transfer,
+            } => {
+                for (thunk, frames) in steps {
+                    tracer.expr(thunk);
+                    frames.trace(tracer);
+                }
+                target.trace(tracer);
+                match transfer {
+                    Transfer::Return(value) => tracer.expr(value),
+                    Transfer::Apply(proc, args) => {
+                        tracer.expr(proc);
+                        exprs(tracer, args);
+                    }
+                    Transfer::Eval(expr, env) => {
+                        tracer.expr(expr);
+                        env.trace(tracer);
+                    }
+                }
+            }
+            Frame::Prompt { tag, handler } => {
+                tracer.expr(tag);
+                handler.iter().for_each(|handler| tracer.expr(handler));
+            }
+            Frame::Handler(handler) => tracer.expr(handler),
+            Frame::Bytecode(vm) => vm.trace(tracer),
+            Frame::Handling { .. } | Frame::Raised | Frame::Reraise | Frame::Call { .. } => {}
+        }
+    }
+}
+
+impl Traced for Vec<Frame> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.iter().for_each(|frame| frame.trace(tracer));
     }
 
-    // Define a new function or variable
-    if let Expression::List(func) = list.get(1).unwrap() {
-        if let Some(Expression::Symbol(func_name)) = func.get(0) {
-            let params = func[1..].to_vec();
-            let body = list.get(2..).ok_or("Invalid define syntax")?.to_vec();
+    fn size(&self) -> usize {
+        self.capacity() * std::mem::size_of::<Frame>()
+    }
+}
 
-            let proc = Procedure {
-                params,
-                body,
-                env: env.clone(),
-            };
+// What happens once control has reached the frames of a continuation
+#[derive(Clone)]
+enum Transfer {
+    Return(Expression),
+    Apply(Expression, Vec<Expression>),
+    Eval(Expression, Environment),
+}
 
-            let function = Expression::Function(proc);
+// The tag of the prompts installed by `reset`, and by
+// `call-with-continuation-prompt` unless it is given one
+pub(crate) const DEFAULT_PROMPT_TAG: &str = "#:default-prompt-tag";
 
-            env.insert(func_name.clone(), function);
-            Ok(Expression::Symbol(func_name.clone()))
-        } else {
-            Err("Invalid define syntax".into())
+fn default_prompt_tag() -> Expression {
+    Expression::Symbol(DEFAULT_PROMPT_TAG.into())
+}
+
+// The before and after thunks of a `dynamic-wind`. A winder is identified by
+// its address, since copies of a stack share it.
+struct Winder {
+    before: Expression,
+    after: Expression,
+}
+
+impl Traced for Winder {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.expr(&self.before);
+        tracer.expr(&self.after);
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Winder>()
+    }
+}
+
+// The winders active in a stack, with the positions of their frames
+fn winders(stack: &[Frame]) -> Vec<(usize, Rc<Winder>)> {
+    stack
+        .iter()
+        .enumerate()
+        .filter_map(|(i, frame)| match frame {
+            Frame::Wind(winder) => Some((i, winder.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+// The rest of a computation, as captured by call/cc, or the part of it up to
+// the nearest prompt, as captured by `shift`
+#[derive(Clone)]
+pub struct Continuation {
+    frames: Rc<Vec<Frame>>,
+    delimited: bool,
+}
+
+impl Continuation {
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.frames);
+    }
+}
+
+// Continuations are only equal to themselves
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.frames, &other.frames)
+    }
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Continuation")
+    }
+}
+
+// Keywords such as `width:` name SRFI 89 named arguments and evaluate to themselves
+pub(crate) fn is_keyword(s: &str) -> bool {
+    s.len() > 1 && s.ends_with(':')
+}
+
+fn is_keyword_value(expr: &Expression) -> bool {
+    matches!(expr, Expression::Symbol(s) if is_keyword(s))
+}
+
+// Parse a parameter list such as `(a b)`, `(a . rest)` or `args`. Extended
+// parameter lists, used by `lambda*` and `define*`, may also contain optional
+// parameters `(name default)` and named parameters `(key: name default)`.
+pub(crate) fn parse_params(formals: &Expression, extended: bool) -> Result<Parameters, Error> {
+    let mut params = Parameters::default();
+
+    let items = match formals {
+        Expression::Symbol(rest) => {
+            params.rest = Some(rest.clone());
+            return Ok(params);
+        }
+        Expression::List(items) => items,
+        _ => return Err(Error::new(ErrorKind::Syntax, "Invalid parameter list")),
+    };
+
+    let mut iter = items.iter();
+
+    while let Some(item) = iter.next() {
+        match item {
+            Expression::Symbol(s) if s == "." => match (iter.next(), iter.next()) {
+                (Some(Expression::Symbol(rest)), None) => params.rest = Some(rest.clone()),
+                _ => return Err(Error::new(ErrorKind::Syntax, "Invalid rest parameter")),
+            },
+            Expression::Symbol(name) => {
+                if !params.optional.is_empty() || !params.named.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Required parameters must come before optional ones",
+                    ));
+                }
+                params.required.push(name.clone());
+            }
+            Expression::List(spec) if extended => match spec.as_slice() {
+                [Expression::Symbol(name), default] if !is_keyword(name) => {
+                    if !params.named.is_empty() {
+                        return Err(Error::new(
+                            ErrorKind::Syntax,
+                            "Optional parameters must come before named ones",
+                        ));
+                    }
+                    params.optional.push((name.clone(), default.clone()));
+                }
+                [Expression::Symbol(key), Expression::Symbol(name), default] if is_keyword(key) => {
+                    params
+                        .named
+                        .push((key.clone(), name.clone(), default.clone()));
+                }
+                _ => return Err(Error::new(ErrorKind::Syntax, "Invalid optional parameter")),
+            },
+            _ => return Err(Error::new(ErrorKind::Syntax, "Invalid parameter name")),
         }
-    } else if let Expression::Symbol(var_name) = list.get(1).unwrap() {
-        let value = eval_expr(list[2].clone(), env)?;
-        env.insert(var_name.clone(), value.clone());
-        return Ok(Expression::Symbol(var_name.clone()));
-    } else {
-        return Err("Invalid define syntax".into());
     }
+
+    Ok(params)
+}
+
+// Every value except #f counts as true in a conditional test
+pub(crate) fn is_true(value: &Expression) -> bool {
+    !matches!(value, Expression::Bool(false))
 }
 
-fn eval_if(list: &[Expression], env: &mut Environment) -> Result<Expression, String> {
-    if list.len() < 4 {
-        return Err("'if' requires at least three arguments".into());
+// The name of the special form a list starting with `head` would be. Local
+// variables have uninterned names, so one named like a special form is called
+// like any other procedure.
+pub(crate) fn form_name(head: &Expression) -> Option<&str> {
+    match head {
+        Expression::Symbol(s) if s.is_interned() => Some(s.as_str()),
+        _ => None,
     }
+}
 
-    let condition = eval_expr(list[1].clone(), env)?;
+pub(crate) fn is_form(list: &[Expression], name: &str) -> bool {
+    list.first().and_then(form_name) == Some(name)
+}
 
-    match condition {
-        Expression::Bool(true) => eval_expr(list[2].clone(), env),
-        Expression::Bool(false) => eval_expr(list[3].clone(), env),
-        _ => Err("Invalid condition in if expression".into()),
+pub(crate) fn is_definition(expr: &Expression) -> bool {
+    match expr {
+        Expression::List(list) if is_form(list, "define") => true,
+        Expression::List(list) if is_form(list, "begin") => list[1..].iter().any(is_definition),
+        _ => false,
     }
 }
 
-fn eval_list(list: &[Expression], env: &mut Environment) -> Result<Expression, String> {
-    let first = &list[0];
-    if let Expression::Symbol(s) = first {
-        match s.as_str() {
-            "define" => eval_define(&list, env),
-            "if" => eval_if(&list, env),
+fn reversed(exprs: &[Expression]) -> Vec<Expression> {
+    exprs.iter().rev().cloned().collect()
+}
+
+// The arguments of a form in reverse order, moved out of the form rather
+// than copied
+fn reversed_args(mut list: Vec<Expression>) -> Vec<Expression> {
+    list.drain(1..).rev().collect()
+}
+
+// Rewrite `(do ((var init step) ...) (test result ...) command ...)` into a
+// named let that loops until the test is true. The loop name contains a space,
+// so it can't clash with a name in the program.
+pub(crate) fn rewrite_do(list: &[Expression]) -> Result<Expression, Error> {
+    if list.len() < 3 {
+        return Err(Error::new(
+            ErrorKind::Syntax,
+            "'do' requires a list of bindings and a termination clause",
+        ));
+    }
+
+    let bindings = match &list[1] {
+        Expression::List(bindings) => bindings,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "Invalid bindings in do expression",
+            ))
+        }
+    };
+
+    let exit_clause = match &list[2] {
+        Expression::List(clause) if !clause.is_empty() => clause,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "Invalid termination clause in do expression",
+            ))
+        }
+    };
+
+    let loop_name = Expression::Symbol("do loop".into());
+    let mut inits = Vec::new();
+    let mut steps = vec![loop_name.clone()];
+
+    for binding in bindings {
+        match binding {
+            Expression::List(binding) if binding.len() == 2 || binding.len() == 3 => {
+                if !matches!(binding[0], Expression::Symbol(_)) {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid variable name in do expression",
+                    ));
+                }
+                inits.push(Expression::List(binding[..2].to_vec().into()));
+                // Variables without a step keep their value
+                steps.push(binding.get(2).unwrap_or(&binding[0]).clone());
+            }
             _ => {
-                if let Some(exp) = env.get(s) {
-                    match exp {
-                        Expression::Func(f) => {
-                            let function = f.clone();
-                            let args: Result<Vec<Expression>, String> = list[1..]
-                                .iter()
-                                .map(|x| eval_expr(x.clone(), env))
-                                .collect();
-                            Ok(function(&args?))
-                        }
-                        Expression::Function(proc) => {
-                            let env_clone = &mut env.clone();
-
-                            let args: Result<Vec<Expression>, String> = list[1..]
-                                .iter()
-                                .map(|x| eval_expr(x.clone(), env_clone))
-                                .collect();
-
-                            // Create a new execution environment for the function
-                            let mut local_env = proc.env.clone();
-
-                            // Insert the function name into the new environment
-                            local_env.insert(s.clone(), exp.clone());
-
-                            for (param, arg) in proc.params.iter().zip(args?) {
-                                if let Expression::Symbol(param_name) = param {
-                                    local_env.insert(param_name.clone(), arg);
-                                } else {
-                                    return Err("Invalid parameter name".into());
-                                }
-                            }
-
-                            let mut result = Expression::Bool(false);
-
-                            for exp in proc.body.clone() {
-                                result = eval_expr(exp.clone(), &mut local_env)?;
-                            }
-
-                            Ok(result)
-                        }
-                        _ => Err(format!("Undefined function: {}", s)),
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Invalid binding in do expression",
+                ))
+            }
+        }
+    }
+
+    let result = match &exit_clause[1..] {
+        [] => Expression::Void,
+        exprs => {
+            let mut result = vec![Expression::Symbol("begin".into())];
+            result.extend_from_slice(exprs);
+            Expression::List(result.into())
+        }
+    };
+
+    let mut iteration = vec![Expression::Symbol("begin".into())];
+    iteration.extend_from_slice(&list[3..]);
+    iteration.push(Expression::List(steps.into()));
+
+    Ok(Expression::List(
+        vec![
+            Expression::Symbol("let".into()),
+            loop_name,
+            Expression::List(inits.into()),
+            Expression::List(
+                vec![
+                    Expression::Symbol("if".into()),
+                    exit_clause[0].clone(),
+                    result,
+                    Expression::List(iteration.into()),
+                ]
+                .into(),
+            ),
+        ]
+        .into(),
+    ))
+}
+
+// Split the bindings of a `let` such as `((x 1) (y 2))` into names and
+// initial value expressions
+pub(crate) fn let_bindings(bindings: &Expression) -> Result<(Vec<Symbol>, Vec<Expression>), Error> {
+    let bindings = match bindings {
+        Expression::List(bindings) => bindings,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "Invalid bindings in let expression",
+            ))
+        }
+    };
+
+    let mut names = Vec::new();
+    let mut inits = Vec::new();
+
+    for binding in bindings {
+        match binding {
+            Expression::List(binding) if binding.len() == 2 => {
+                match &binding[0] {
+                    Expression::Symbol(s) => names.push(s.clone()),
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Syntax,
+                            "Invalid variable name in let expression",
+                        ))
                     }
+                }
+                inits.push(binding[1].clone());
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Invalid binding in let expression",
+                ))
+            }
+        }
+    }
+
+    Ok((names, inits))
+}
+
+// Collect the expressions unquoted at the outermost level of a quasiquote
+// template, in the order they are evaluated
+fn unquoted_exprs(template: &Expression, depth: usize, exprs: &mut Vec<Expression>) {
+    let items = match template {
+        Expression::List(items) => items,
+        _ => return,
+    };
+
+    if let [Expression::Symbol(form), inner] = items.as_slice() {
+        match form.as_str() {
+            "unquote" | "unquote-splicing" if depth == 1 => {
+                exprs.push(inner.clone());
+                return;
+            }
+            "unquote" | "unquote-splicing" => return unquoted_exprs(inner, depth - 1, exprs),
+            "quasiquote" => return unquoted_exprs(inner, depth + 1, exprs),
+            _ => {}
+        }
+    }
+
+    for item in items {
+        grow_stack(|| unquoted_exprs(item, depth, exprs));
+    }
+}
+
+// Build the data of a quasiquote template from the values of its unquoted
+// expressions
+fn fill_quasiquote(
+    template: &Expression,
+    depth: usize,
+    values: &mut impl Iterator<Item = Expression>,
+) -> Result<Expression, Error> {
+    let items = match template {
+        Expression::List(items) => items,
+        _ => return Ok(template.clone()),
+    };
+
+    if let [Expression::Symbol(form), inner] = items.as_slice() {
+        match form.as_str() {
+            "unquote" if depth == 1 => return Ok(values.next().unwrap_or(Expression::Void)),
+            "unquote" | "unquote-splicing" | "quasiquote" => {
+                let depth = if form == "quasiquote" {
+                    depth + 1
                 } else {
-                    Err(format!("Undefined function: {}", s))
+                    depth - 1
+                };
+                return Ok(Expression::List(
+                    vec![
+                        Expression::Symbol(form.clone()),
+                        fill_quasiquote(inner, depth, values)?,
+                    ]
+                    .into(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    let mut result = Vec::new();
+
+    for item in items {
+        match item {
+            Expression::List(splice) if depth == 1 && is_form(splice, "unquote-splicing") => {
+                match values.next() {
+                    Some(Expression::List(values)) => result.extend(values),
+                    Some(value) => {
+                        return Err(
+                            Error::new(ErrorKind::Type, "Cannot splice a non-list value:")
+                                .with_irritants(vec![value.clone()]),
+                        )
+                    }
+                    None => {}
                 }
             }
+            _ => result.push(grow_stack(|| fill_quasiquote(item, depth, values))?),
         }
-    } else {
-        Err("Expected a symbol".into())
     }
+
+    Ok(Expression::List(result.into()))
 }
 
-fn eval_expr(expr: Expression, env: &mut Environment) -> Result<Expression, String> {
-    match expr {
-        Expression::Bool(_) => Ok(expr),
-        Expression::Symbol(s) => env
-            .get(&s)
-            .cloned()
-            .ok_or_else(|| format!("Undefined symbol: {}", s)),
-        Expression::Number(_) => Ok(expr),
-        Expression::List(list) => eval_list(&list, env),
-        Expression::Func(_) => Ok(expr),
-        Expression::Function(_) => Err("Unexpected function definition".into()),
+// `(syntax template ((var depth) ...))`, where the expander has listed the
+// pattern variables the template uses
+fn eval_syntax(list: &[Expression], env: &Environment) -> Result<Expression, Error> {
+    let (template, vars) = match list {
+        [_, template, Expression::List(vars)] => (template, vars),
+        _ => return Err(Error::new(ErrorKind::Syntax, "Invalid syntax template")),
+    };
+
+    let mut values = Vec::new();
+    for var in vars {
+        match var {
+            Expression::List(var) => match var.as_slice() {
+                [Expression::Symbol(name), Expression::Number(depth)] => {
+                    let value = env.get(name).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::Syntax,
+                            format!("Undefined pattern variable: {}", name),
+                        )
+                    })?;
+                    values.push((name.to_string(), *depth as usize, value));
+                }
+                _ => return Err(Error::new(ErrorKind::Syntax, "Invalid syntax template")),
+            },
+            _ => return Err(Error::new(ErrorKind::Syntax, "Invalid syntax template")),
+        }
     }
+
+    instantiate_syntax(template, values)
 }
 
-pub fn eval(program: &str, env: &mut Environment) -> Result<Expression, String> {
-    let parsed_expr = match parse(program) {
-        Ok(expr) => expr,
-        Err(error) => {
-            eprintln!("Error during parsing: {}", error);
-            std::process::exit(1);
+// Bind the arguments of a call to the parameters of a procedure
+fn bind_arguments(
+    params: &Parameters,
+    args: Vec<Expression>,
+    local_env: &mut Environment,
+) -> Result<(), Error> {
+    let mut args = args.into_iter().peekable();
+
+    for param_name in &params.required {
+        if let Some(arg) = args.next() {
+            local_env.insert(param_name.clone(), arg);
+        }
+    }
+
+    // Default expressions can refer to the parameters before them
+    for (param_name, default) in &params.optional {
+        let value = match args.next_if(|arg| params.named.is_empty() || !is_keyword_value(arg)) {
+            Some(arg) => arg,
+            None => eval_expr(default.clone(), local_env.clone())?,
+        };
+        local_env.insert(param_name.clone(), value);
+    }
+
+    let mut remaining: Vec<Expression> = args.collect();
+
+    if !params.named.is_empty() {
+        let mut values: Vec<Option<Expression>> = vec![None; params.named.len()];
+        let mut i = 0;
+
+        while let Some(Expression::Symbol(key)) = remaining.get(i) {
+            if !is_keyword(key) {
+                break;
+            }
+
+            let position = match params.named.iter().position(|(k, _, _)| k == key) {
+                Some(position) => position,
+                None if params.rest.is_some() => break,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Arity,
+                        format!("Unknown keyword argument: {}", key),
+                    ))
+                }
+            };
+
+            let value = remaining.get(i + 1).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::Arity,
+                    format!("Missing value for keyword argument: {}", key),
+                )
+            })?;
+
+            if values[position].is_some() {
+                return Err(Error::new(
+                    ErrorKind::Arity,
+                    format!("Duplicate keyword argument: {}", key),
+                ));
+            }
+
+            values[position] = Some(value.clone());
+            i += 2;
+        }
+
+        remaining.drain(..i);
+
+        for ((_, param_name, default), value) in params.named.iter().zip(values) {
+            let value = match value {
+                Some(value) => value,
+                None => eval_expr(default.clone(), local_env.clone())?,
+            };
+            local_env.insert(param_name.clone(), value);
         }
+    }
+
+    if let Some(rest) = &params.rest {
+        local_env.insert(rest.clone(), Expression::List(remaining.into()));
+    } else if !remaining.is_empty() {
+        return Err(
+            Error::new(ErrorKind::Arity, "Unexpected extra arguments:").with_irritants(remaining)
+        );
+    }
+
+    Ok(())
+}
+
+pub(crate) fn check_arity(name: &str, arity: Arity, count: usize) -> Result<(), Error> {
+    if arity.accepts(count) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::Arity,
+            format!(
+                "Wrong number of arguments to {}: expected {}, got {}",
+                name, arity, count
+            ),
+        ))
+    }
+}
+
+fn eval_lambda(list: &[Expression], env: Environment, extended: bool) -> Result<State, Error> {
+    if list.len() < 3 {
+        return Err(Error::new(
+            ErrorKind::Syntax,
+            "'lambda' requires a parameter list and a body",
+        ));
+    }
+
+    let proc = Procedure {
+        name: None,
+        params: Rc::new(parse_params(&list[1], extended)?),
+        body: list[2..].into(),
+        env,
     };
 
-    let evaluated_expression = eval_expr(parsed_expr, env)?;
+    Ok(State::Return(Expression::Function(proc)))
+}
+
+fn eval_case_lambda(list: &[Expression], env: Environment) -> Result<State, Error> {
+    let mut clauses = Vec::new();
+
+    for clause in &list[1..] {
+        match clause {
+            Expression::List(clause) if clause.len() >= 2 => clauses.push(Procedure {
+                name: None,
+                params: Rc::new(parse_params(&clause[0], false)?),
+                body: clause[1..].into(),
+                env: env.clone(),
+            }),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Invalid clause in case-lambda expression",
+                ))
+            }
+        }
+    }
+
+    Ok(State::Return(Expression::CaseLambda(clauses)))
+}
+
+fn eval_quote(list: &[Expression]) -> Result<State, Error> {
+    match list {
+        [_, datum] => Ok(State::Return(datum.clone())),
+        _ => Err(Error::new(
+            ErrorKind::Syntax,
+            "'quote' requires exactly one argument",
+        )),
+    }
+}
+
+// How many frames the stack of a machine, or the call frames of the virtual
+// machine, may grow to. The stacks are on the heap, so this is only there to
+// report runaway recursion as an error before it uses up all memory.
+pub(crate) const MAX_DEPTH: usize = 10_000_000;
+
+// How many machines may be running inside each other. This happens when
+// macros run during expansion, when the defaults of optional parameters are
+// evaluated, and when errors unwind through `dynamic-wind`.
+const MAX_NESTING: usize = 10_000;
+
+// When less than this much native stack is left, a nested run or a recursive
+// walk over a deeply nested expression continues on a new stack segment of
+// the second size
+const RED_ZONE: usize = 64 * 1024;
+const STACK_SEGMENT: usize = 1024 * 1024;
+
+thread_local! {
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+pub(crate) fn recursion_error() -> Error {
+    Error::new(ErrorKind::Recursion, "Maximum recursion depth exceeded")
+}
+
+// Run a recursive step, moving to a new native stack segment if the current
+// one is nearly used up
+pub(crate) fn grow_stack<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, STACK_SEGMENT, f)
+}
+
+// Run a machine inside another one
+pub(crate) fn nested<R>(run: impl FnOnce() -> Result<R, Error>) -> Result<R, Error> {
+    struct Level;
+
+    impl Drop for Level {
+        fn drop(&mut self) {
+            NESTING.with(|nesting| nesting.set(nesting.get() - 1));
+        }
+    }
+
+    let depth = NESTING.with(|nesting| {
+        nesting.set(nesting.get() + 1);
+        nesting.get()
+    });
+    let _level = Level;
+
+    if depth > MAX_NESTING {
+        return Err(recursion_error());
+    }
+    grow_stack(run)
+}
+
+struct Machine {
+    stack: Vec<Frame>,
+}
+
+impl Machine {
+    fn new() -> Self {
+        Machine { stack: Vec::new() }
+    }
+
+    fn run(&mut self, state: State) -> Result<Expression, Error> {
+        let mut state = state;
+
+        loop {
+            let next = match state {
+                State::Eval(..) if self.stack.len() > MAX_DEPTH => Err(recursion_error()),
+                State::Eval(expr, env) => self.eval(expr, env),
+                State::Return(value) => match self.stack.pop() {
+                    Some(frame) => self.resume(frame, value),
+                    None => return Ok(value),
+                },
+            };
+
+            state = match next.or_else(|error| self.handle_error(error)) {
+                Ok(state) => state,
+                Err(error) => return Err(self.unwind(error)),
+            };
+        }
+    }
+
+    // Give the handlers a chance to deal with an evaluation error, by raising
+    // it as an error object
+    fn handle_error(&mut self, error: Error) -> Result<State, Error> {
+        let error = self.locate(error);
+        let uncatchable = matches!(error.kind, ErrorKind::Limit | ErrorKind::Interrupted);
+        if uncatchable || self.find_handler().is_none() {
+            return Err(error);
+        }
+
+        self.raise(Expression::ErrorObject(Box::new(error)), false)
+    }
+
+    // The position of the innermost exception handler or `guard` that is
+    // visible from the top of the stack
+    fn find_handler(&self) -> Option<usize> {
+        let mut i = self.stack.len();
+
+        while i > 0 {
+            i -= 1;
+            match &self.stack[i] {
+                Frame::Handler(_) | Frame::Guard { .. } => return Some(i),
+                Frame::Handling { skip } => i -= skip,
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    // Pass a raised object to the current handler. A handler is called in the
+    // dynamic context of the `raise`, while a `guard` is unwound to first.
+    fn raise(&mut self, obj: Expression, continuable: bool) -> Result<State, Error> {
+        let i = match self.find_handler() {
+            Some(i) => i,
+            // An error object knows where it was first raised, so it leaves
+            // right away rather than being located again here
+            None => {
+                return match obj {
+                    Expression::ErrorObject(error) => Err(self.unwind(*error)),
+                    obj => Err(Error::new(ErrorKind::Raise, "Uncaught exception:")
+                        .with_irritants(vec![obj])),
+                }
+            }
+        };
+
+        match self.stack[i].clone() {
+            Frame::Guard { var, clauses, env } => {
+                let mut clause_env = env.extend();
+                clause_env.insert(var.clone(), obj);
+
+                let else_clause = Expression::Symbol("else".into());
+                let has_else = matches!(
+                    clauses.last(),
+                    Some(Expression::List(clause)) if clause.first() == Some(&else_clause)
+                );
+
+                let mut cond = vec![Expression::Symbol("cond".into())];
+                cond.extend(clauses);
+
+                // Without a matching clause, the object is raised again with
+                // `raise-continuable` where it was first raised, hiding this
+                // guard from the handlers
+                if !has_else {
+                    let mut frames = self.stack.clone();
+                    frames.push(Frame::Handling {
+                        skip: frames.len() - i,
+                    });
+                    if !continuable {
+                        frames.push(Frame::Raised);
+                    }
+                    frames.push(Frame::Reraise);
+                    let reraise = Expression::Continuation(Continuation {
+                        frames: Rc::new(frames),
+                        delimited: false,
+                    });
+
+                    cond.push(Expression::List(
+                        vec![
+                            else_clause,
+                            Expression::List(vec![reraise, Expression::Symbol(var)].into()),
+                        ]
+                        .into(),
+                    ));
+                }
+
+                let transfer = Transfer::Eval(Expression::List(cond.into()), clause_env);
+                self.wind_to(self.stack[..i].to_vec(), transfer)
+            }
+            Frame::Handler(handler) => {
+                let skip = self.stack.len() - i;
+                self.stack.push(Frame::Handling { skip });
+                if !continuable {
+                    self.stack.push(Frame::Raised);
+                }
+                self.apply(None, handler, vec![obj])
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Abandon the computation after an error, running the after thunks of the
+    // `dynamic-wind` forms it is inside of. An error in one of them takes the
+    // place of the original error.
+    fn unwind(&mut self, error: Error) -> Error {
+        let mut error = error;
+
+        while let Some(frame) = self.stack.pop() {
+            if let Frame::Wind(winder) = frame {
+                if let Err(mut after_error) = apply_procedure(&winder.after, Vec::new()) {
+                    after_error.stack.extend(self.call_stack());
+                    error = after_error;
+                }
+            }
+        }
+
+        error
+    }
+
+    // Record where an error happened: the procedures being called, and the
+    // innermost call in progress unless the error already has a span
+    fn locate(&self, mut error: Error) -> Error {
+        error.stack.extend(self.call_stack());
+        error.span = error.span.or_else(|| self.call_site());
+        error
+    }
+
+    // The span of the innermost call whose arguments are being evaluated, or
+    // that compiled code is waiting on
+    fn call_site(&self) -> Option<Span> {
+        self.stack.iter().rev().find_map(|frame| match frame {
+            Frame::Operator { span, .. } | Frame::Arguments { span, .. } => *span,
+            Frame::Bytecode(vm) => vm.call_site(false),
+            _ => None,
+        })
+    }
+
+    // The names of the procedures being called, innermost first
+    fn call_stack(&self) -> Vec<String> {
+        self.stack
+            .iter()
+            .rev()
+            .flat_map(|frame| match frame {
+                Frame::Call { name, .. } => vec![name.clone()],
+                Frame::Bytecode(vm) => vm.call_stack(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    // Evaluate `expr` with `frame` waiting for its value
+    fn push(&mut self, frame: Frame, expr: Expression, env: Environment) -> State {
+        self.stack.push(frame);
+        State::Eval(expr, env)
+    }
+
+    // Evaluate a single step of an expression
+    fn eval(&mut self, expr: Expression, env: Environment) -> Result<State, Error> {
+        match expr {
+            Expression::Symbol(s) => match env.get(&s) {
+                Some(value) => Ok(State::Return(value)),
+                None if is_keyword(&s) => Ok(State::Return(Expression::Symbol(s))),
+                None => Err(Error::new(ErrorKind::UnboundVariable, "Undefined symbol:")
+                    .with_irritants(vec![Expression::Symbol(s)])),
+            },
+            Expression::Reference(reference) => match env.lookup(&reference) {
+                Some(value) => Ok(State::Return(value)),
+                None => Err(Error::new(ErrorKind::UnboundVariable, "Undefined symbol:")
+                    .with_irritants(vec![Expression::Symbol(reference.name)])),
+            },
+            Expression::List(list) => {
+                let span = list.span();
+                self.eval_list(list.into_vec(), span, env)
+            }
+            Expression::Function(_) | Expression::CaseLambda(_) => Err(Error::new(
+                ErrorKind::Syntax,
+                "Unexpected function definition",
+            )),
+            Expression::Macro(_) => Err(Error::new(ErrorKind::Syntax, "Unexpected macro")),
+            _ => Ok(State::Return(expr)),
+        }
+    }
+
+    fn eval_list(
+        &mut self,
+        list: Vec<Expression>,
+        span: Option<Span>,
+        env: Environment,
+    ) -> Result<State, Error> {
+        let first = match list.first() {
+            Some(first) => first,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Cannot evaluate an empty list",
+                ))
+            }
+        };
+
+        if let Some(form) = form_name(first) {
+            match form {
+                "define" => return self.eval_define(&list, env, false),
+                "define*" => return self.eval_define(&list, env, true),
+                "set!" => return self.eval_set(list, env),
+                "lambda" => return eval_lambda(&list, env, false),
+                "lambda*" => return eval_lambda(&list, env, true),
+                "case-lambda" => return eval_case_lambda(&list, env),
+                "if" => return self.eval_if(list, env),
+                "cond" => return self.next_cond_clause(reversed_args(list), env),
+                "case" => return self.eval_case(&list, env),
+                "when" | "unless" => return self.eval_when(&list, env),
+                "and" => return Ok(self.next_and(reversed_args(list), env)),
+                "or" => return Ok(self.next_or(reversed_args(list), env)),
+                "begin" => return self.eval_begin(&list, env),
+                "do" => return Ok(State::Eval(rewrite_do(&list)?, env)),
+                "let" => return self.eval_let(&list, env),
+                "quote" => return eval_quote(&list),
+                "quasiquote" => return self.eval_quasiquote(&list, env),
+                "syntax" => return Ok(State::Return(eval_syntax(&list, &env)?)),
+                "syntax-case" => return self.eval_syntax_case(&list, env),
+                "macroexpand" | "macroexpand-1" => return self.eval_macroexpand(&list, env),
+                // The expander wraps the bodies of these forms in procedures
+                "guard" => return self.eval_guard(&list, env),
+                "reset" => {
+                    let proc = Expression::Control(Control::CallWithPrompt);
+                    let args = reversed_args(list);
+                    return self.next_argument(None, proc, Vec::new(), args, env, span);
+                }
+                "shift" => {
+                    let proc = Expression::Control(Control::Shift);
+                    let args = reversed_args(list);
+                    return self.next_argument(None, proc, Vec::new(), args, env, span);
+                }
+                _ => {}
+            }
+        }
+
+        let mut list = list;
+        let args: Vec<Expression> = list.drain(1..).rev().collect();
+        let first = list.swap_remove(0);
+
+        match first {
+            Expression::Symbol(s) => match env.get(&s) {
+                Some(proc) => self.next_argument(Some(s), proc, Vec::new(), args, env, span),
+                None => Err(
+                    Error::new(ErrorKind::UnboundVariable, "Undefined function:")
+                        .with_irritants(vec![Expression::Symbol(s)]),
+                ),
+            },
+            Expression::Reference(reference) => match env.lookup(&reference) {
+                Some(proc) => {
+                    self.next_argument(Some(reference.name), proc, Vec::new(), args, env, span)
+                }
+                None => Err(
+                    Error::new(ErrorKind::UnboundVariable, "Undefined function:")
+                        .with_irritants(vec![Expression::Symbol(reference.name)]),
+                ),
+            },
+            first => {
+                let frame = Frame::Operator {
+                    args,
+                    env: env.clone(),
+                    span,
+                };
+                Ok(self.push(frame, first, env))
+            }
+        }
+    }
+
+    // Continue the computation waiting in `frame` with a value
+    fn resume(&mut self, frame: Frame, value: Expression) -> Result<State, Error> {
+        match frame {
+            Frame::Sequence {
+                exprs,
+                next,
+                body,
+                env,
+            } => Ok(self.next_in_sequence(exprs, next, body, env)),
+            Frame::Define { name, mut env } => {
+                let value = match value {
+                    // Name anonymous procedures after the variable they are bound to
+                    Expression::Function(proc) if proc.name.is_none() => {
+                        Expression::Function(Procedure {
+                            name: Some(name.to_string()),
+                            ..proc
+                        })
+                    }
+                    value => value,
+                };
+                env.insert(name.clone(), value);
+                Ok(State::Return(Expression::Symbol(name)))
+            }
+            Frame::Set { name, env } => {
+                if !env.set(&name, value) {
+                    return Err(
+                        Error::new(ErrorKind::UnboundVariable, "Undefined variable:")
+                            .with_irritants(vec![Expression::Symbol(name)]),
+                    );
+                }
+                Ok(State::Return(Expression::Void))
+            }
+            Frame::If {
+                consequent,
+                alternative,
+                env,
+            } => {
+                if is_true(&value) {
+                    Ok(State::Eval(consequent, env))
+                } else if let Some(alternative) = alternative {
+                    Ok(State::Eval(alternative, env))
+                } else {
+                    Ok(State::Return(Expression::Void))
+                }
+            }
+            Frame::Cond {
+                clause,
+                clauses,
+                env,
+            } => self.select_cond_clause(clause, clauses, value, env),
+            Frame::Case { clauses, env } => self.select_case_clause(&clauses, value, env),
+            Frame::When { body, when, env } => {
+                // 'when' runs its body on a true test, 'unless' on a false one
+                if is_true(&value) == when {
+                    Ok(self.sequence(body, false, env))
+                } else {
+                    Ok(State::Return(Expression::Void))
+                }
+            }
+            Frame::And { exprs, env } => {
+                if is_true(&value) {
+                    Ok(self.next_and(exprs, env))
+                } else {
+                    Ok(State::Return(value))
+                }
+            }
+            Frame::Or { exprs, env } => {
+                if is_true(&value) {
+                    Ok(State::Return(value))
+                } else {
+                    Ok(self.next_or(exprs, env))
+                }
+            }
+            Frame::Receiver { name, value: arg } => self.apply(name, value, vec![arg]),
+            Frame::Let {
+                name,
+                names,
+                mut values,
+                inits,
+                body,
+                env,
+            } => {
+                values.push(value);
+                self.next_let_binding(name, names, values, inits, body, env)
+            }
+            Frame::Operator { args, env, span } => {
+                self.next_argument(None, value, Vec::new(), args, env, span)
+            }
+            Frame::Arguments {
+                name,
+                proc,
+                mut values,
+                args,
+                env,
+                span,
+            } => {
+                values.push(value);
+                self.next_argument(name, proc, values, args, env, span)
+            }
+            Frame::Quasiquote {
+                template,
+                mut values,
+                exprs,
+                env,
+            } => {
+                values.push(value);
+                self.next_unquoted(template, values, exprs, env)
+            }
+            Frame::SyntaxCase {
+                literals,
+                clauses,
+                env,
+            } => self.next_syntax_clause(value, literals, clauses, env),
+            Frame::Fender {
+                form,
+                literals,
+                clauses,
+                output,
+                clause_env,
+                env,
+            } => {
+                if is_true(&value) {
+                    Ok(State::Eval(output, clause_env))
+                } else {
+                    self.next_syntax_clause(form, literals, clauses, env)
+                }
+            }
+            Frame::Macroexpand { once, mut env } => {
+                Ok(State::Return(macroexpand(&value, &mut env, once)?))
+            }
+            Frame::WindBefore { winder, thunk } => {
+                self.stack.push(Frame::Wind(winder));
+                self.apply(None, thunk, Vec::new())
+            }
+            Frame::Wind(winder) => {
+                self.stack.push(Frame::WindAfter { value });
+                self.apply(None, winder.after.clone(), Vec::new())
+            }
+            Frame::WindAfter { value } => Ok(State::Return(value)),
+            Frame::Rewind {
+                steps,
+                target,
+                transfer,
+            } => self.next_rewind(steps, target, transfer),
+            Frame::Prompt { .. }
+            | Frame::Handler(_)
+            | Frame::Guard { .. }
+            | Frame::Handling { .. }
+            | Frame::Call { .. } => Ok(State::Return(value)),
+            Frame::Reraise => self.raise(value, true),
+            Frame::Raised => Err(Error::new(
+                ErrorKind::Raise,
+                "An exception handler returned from a non-continuable raise",
+            )),
+            Frame::Bytecode(mut vm) => {
+                vm.resume(value);
+                self.run_vm(vm)
+            }
+        }
+    }
+
+    // The number of procedure calls in progress, counting those of compiled
+    // code waiting on the stack
+    fn call_depth(&self) -> usize {
+        self.stack
+            .iter()
+            .rev()
+            .find_map(|frame| match frame {
+                Frame::Call { depth, .. } => Some(*depth),
+                Frame::Bytecode(vm) => Some(vm.call_depth()),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    // Run compiled code until it returns or hands a procedure over. The
+    // procedure is called like any other, with the machine waiting for its
+    // value on the stack unless it was called in tail position.
+    fn run_vm(&mut self, mut vm: Vm) -> Result<State, Error> {
+        match vm.execute() {
+            Ok(Exit::Return(value)) => Ok(State::Return(value)),
+            Ok(Exit::Apply { proc, args, name }) => {
+                if !vm.is_done() {
+                    self.stack.push(Frame::Bytecode(vm));
+                }
+                self.apply(name, proc, args)
+            }
+            Err(mut error) => {
+                error.stack.extend(vm.call_stack());
+                error.span = error.span.or_else(|| vm.call_site(true));
+                Err(error)
+            }
+        }
+    }
+
+    // Run a compiled program, with the machine there for the procedures it
+    // hands over
+    fn run_bytecode(&mut self, vm: Vm) -> Result<Expression, Error> {
+        match self.run_vm(vm).or_else(|error| self.handle_error(error)) {
+            Ok(state) => self.run(state),
+            Err(error) => Err(self.unwind(error)),
+        }
+    }
+
+    // Evaluate a sequence of expressions, leaving the last one in tail position.
+    // In a body, where definitions are allowed, `begin` forms are spliced in so
+    // that any definitions they contain land in the enclosing scope.
+    fn sequence(&mut self, exprs: Rc<[Expression]>, body: bool, env: Environment) -> State {
+        self.next_in_sequence(exprs, 0, body, env)
+    }
+
+    fn next_in_sequence(
+        &mut self,
+        exprs: Rc<[Expression]>,
+        next: usize,
+        body: bool,
+        env: Environment,
+    ) -> State {
+        let expr = match exprs.get(next) {
+            Some(expr) => expr,
+            None => return State::Return(Expression::Void),
+        };
+        let last = next + 1 == exprs.len();
+
+        // The expressions of a spliced `begin` run before the rest of the body
+        if let Expression::List(list) = expr {
+            if body && is_form(list, "begin") {
+                let spliced: Rc<[Expression]> = list[1..].into();
+                if !last {
+                    self.stack.push(Frame::Sequence {
+                        exprs: exprs.clone(),
+                        next: next + 1,
+                        body,
+                        env: env.clone(),
+                    });
+                }
+                return self.next_in_sequence(spliced, 0, body, env);
+            }
+        }
+
+        let expr = expr.clone();
+        if last {
+            return State::Eval(expr, env);
+        }
+
+        let frame = Frame::Sequence {
+            exprs,
+            next: next + 1,
+            body,
+            env: env.clone(),
+        };
+        self.push(frame, expr, env)
+    }
+
+    fn eval_define(
+        &mut self,
+        list: &[Expression],
+        mut env: Environment,
+        extended: bool,
+    ) -> Result<State, Error> {
+        if list.len() < 3 {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "'define' requires at least two arguments",
+            ));
+        }
+
+        // Define a new function or variable
+        match &list[1] {
+            Expression::List(func) => {
+                if let Some(Expression::Symbol(func_name)) = func.first() {
+                    let proc = Procedure {
+                        name: Some(func_name.to_string()),
+                        params: Rc::new(parse_params(&Expression::List(func.tail(1)), extended)?),
+                        body: list[2..].into(),
+                        env: env.clone(),
+                    };
+
+                    env.insert(func_name.clone(), Expression::Function(proc));
+                    Ok(State::Return(Expression::Symbol(func_name.clone())))
+                } else {
+                    Err(Error::new(ErrorKind::Syntax, "Invalid define syntax"))
+                }
+            }
+            Expression::Symbol(var_name) => {
+                let frame = Frame::Define {
+                    name: var_name.clone(),
+                    env: env.clone(),
+                };
+                Ok(self.push(frame, list[2].clone(), env))
+            }
+            _ => Err(Error::new(ErrorKind::Syntax, "Invalid define syntax")),
+        }
+    }
+
+    fn eval_set(&mut self, list: Vec<Expression>, env: Environment) -> Result<State, Error> {
+        match <[Expression; 3]>::try_from(list) {
+            Ok([_, Expression::Symbol(name), expr]) => {
+                let frame = Frame::Set {
+                    name,
+                    env: env.clone(),
+                };
+                Ok(self.push(frame, expr, env))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Syntax,
+                "'set!' requires a variable and an expression",
+            )),
+        }
+    }
+
+    fn eval_guard(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        match list {
+            [_, Expression::List(spec), body @ ..] if !body.is_empty() => {
+                match spec.split_first() {
+                    Some((Expression::Symbol(var), clauses)) => {
+                        self.stack.push(Frame::Guard {
+                            var: var.clone(),
+                            clauses: clauses.to_vec(),
+                            env: env.clone(),
+                        });
+                        Ok(self.sequence(body.into(), true, env.extend()))
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'guard' requires a variable to bind the raised object to",
+                    )),
+                }
+            }
+            _ => Err(Error::new(
+                ErrorKind::Syntax,
+                "'guard' requires a variable, clauses and a body",
+            )),
+        }
+    }
+
+    fn eval_if(&mut self, list: Vec<Expression>, env: Environment) -> Result<State, Error> {
+        let mut parts = list.into_iter().skip(1);
+
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(test), Some(consequent), alternative, None) => {
+                let frame = Frame::If {
+                    consequent,
+                    alternative,
+                    env: env.clone(),
+                };
+                Ok(self.push(frame, test, env))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Syntax,
+                "'if' requires two or three arguments",
+            )),
+        }
+    }
+
+    fn next_cond_clause(
+        &mut self,
+        mut clauses: Vec<Expression>,
+        env: Environment,
+    ) -> Result<State, Error> {
+        let clause = match clauses.pop() {
+            Some(Expression::List(clause)) if !clause.is_empty() => clause,
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Invalid clause in cond expression",
+                ))
+            }
+            None => return Ok(State::Return(Expression::Void)),
+        };
+
+        if clause[0] == Expression::Symbol("else".into()) {
+            if !clauses.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'else' must be the last clause in cond expression",
+                ));
+            }
+            return Ok(self.sequence(clause[1..].into(), false, env));
+        }
+
+        let test = clause[0].clone();
+        let frame = Frame::Cond {
+            clause: clause.into_vec(),
+            clauses,
+            env: env.clone(),
+        };
+        Ok(self.push(frame, test, env))
+    }
+
+    fn select_cond_clause(
+        &mut self,
+        clause: Vec<Expression>,
+        clauses: Vec<Expression>,
+        test: Expression,
+        env: Environment,
+    ) -> Result<State, Error> {
+        if !is_true(&test) {
+            return self.next_cond_clause(clauses, env);
+        }
+
+        if clause.get(1) == Some(&Expression::Symbol("=>".into())) {
+            if clause.len() != 3 {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'=>' in cond expression requires exactly one procedure",
+                ));
+            }
+            return Ok(self.receiver(&clause[2], test, env));
+        }
+
+        if clause.len() == 1 {
+            return Ok(State::Return(test));
+        }
+
+        Ok(self.sequence(clause[1..].into(), false, env))
+    }
+
+    fn eval_case(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        if list.len() < 2 {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "'case' requires a key expression",
+            ));
+        }
+
+        let frame = Frame::Case {
+            clauses: list[2..].to_vec(),
+            env: env.clone(),
+        };
+        Ok(self.push(frame, list[1].clone(), env))
+    }
+
+    fn select_case_clause(
+        &mut self,
+        clauses: &[Expression],
+        key: Expression,
+        env: Environment,
+    ) -> Result<State, Error> {
+        for (i, clause) in clauses.iter().enumerate() {
+            let clause = match clause {
+                Expression::List(clause) if clause.len() >= 2 => clause,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid clause in case expression",
+                    ))
+                }
+            };
+
+            let matched = match &clause[0] {
+                Expression::Symbol(s) if s == "else" => {
+                    if i != clauses.len() - 1 {
+                        return Err(Error::new(
+                            ErrorKind::Syntax,
+                            "'else' must be the last clause in case expression",
+                        ));
+                    }
+                    true
+                }
+                Expression::List(data) => data.contains(&key),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid clause in case expression",
+                    ))
+                }
+            };
+
+            if !matched {
+                continue;
+            }
+
+            if clause[1] == Expression::Symbol("=>".into()) {
+                if clause.len() != 3 {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'=>' in case expression requires exactly one procedure",
+                    ));
+                }
+                return Ok(self.receiver(&clause[2], key, env));
+            }
+
+            return Ok(self.sequence(clause[1..].into(), false, env));
+        }
+
+        Ok(State::Return(Expression::Void))
+    }
+
+    fn eval_when(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        if list.len() < 3 {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                format!("'{}' requires a test and at least one expression", list[0]),
+            ));
+        }
+
+        let frame = Frame::When {
+            body: list[2..].into(),
+            when: is_form(list, "when"),
+            env: env.clone(),
+        };
+        Ok(self.push(frame, list[1].clone(), env))
+    }
+
+    // The last expression of `and` and `or` is in tail position and its value
+    // is returned as is
+    fn next_and(&mut self, mut exprs: Vec<Expression>, env: Environment) -> State {
+        match exprs.pop() {
+            None => State::Return(Expression::Bool(true)),
+            Some(expr) if exprs.is_empty() => State::Eval(expr, env),
+            Some(expr) => {
+                let frame = Frame::And {
+                    exprs,
+                    env: env.clone(),
+                };
+                self.push(frame, expr, env)
+            }
+        }
+    }
+
+    fn next_or(&mut self, mut exprs: Vec<Expression>, env: Environment) -> State {
+        match exprs.pop() {
+            None => State::Return(Expression::Bool(false)),
+            Some(expr) if exprs.is_empty() => State::Eval(expr, env),
+            Some(expr) => {
+                let frame = Frame::Or {
+                    exprs,
+                    env: env.clone(),
+                };
+                self.push(frame, expr, env)
+            }
+        }
+    }
+
+    // Call the procedure of a `=>` clause with the value that selected the clause
+    fn receiver(&mut self, receiver: &Expression, value: Expression, env: Environment) -> State {
+        let name = match receiver {
+            Expression::Symbol(s) => Some(s.clone()),
+            Expression::Reference(reference) => Some(reference.name.clone()),
+            _ => None,
+        };
+
+        self.push(Frame::Receiver { name, value }, receiver.clone(), env)
+    }
+
+    // `begin` in an expression context, as opposed to the top level or a body
+    fn eval_begin(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        if list.len() < 2 {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "'begin' requires at least one expression",
+            ));
+        }
+
+        if list[1..].iter().any(is_definition) {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "Definitions are not allowed in a 'begin' expression",
+            ));
+        }
+
+        Ok(self.sequence(list[1..].into(), false, env))
+    }
+
+    fn eval_let(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        // A named let binds a procedure that can be called to run the body again
+        let (name, start) = match list.get(1) {
+            Some(Expression::Symbol(name)) => (Some(name.clone()), 2),
+            _ => (None, 1),
+        };
+
+        if list.len() < start + 2 {
+            return Err(match name {
+                Some(_) => Error::new(
+                    ErrorKind::Syntax,
+                    "Named 'let' requires bindings and a body",
+                ),
+                None => Error::new(ErrorKind::Syntax, "'let' requires bindings and a body"),
+            });
+        }
+
+        let (names, inits) = let_bindings(&list[start])?;
+        let body = list[start + 1..].into();
+
+        self.next_let_binding(name, names, Vec::new(), reversed(&inits), body, env)
+    }
+
+    fn next_let_binding(
+        &mut self,
+        name: Option<Symbol>,
+        names: Vec<Symbol>,
+        values: Vec<Expression>,
+        mut inits: Vec<Expression>,
+        body: Rc<[Expression]>,
+        env: Environment,
+    ) -> Result<State, Error> {
+        if let Some(init) = inits.pop() {
+            let frame = Frame::Let {
+                name,
+                names,
+                values,
+                inits,
+                body,
+                env: env.clone(),
+            };
+            return Ok(self.push(frame, init, env));
+        }
+
+        let mut let_env = env.extend();
+
+        match name {
+            Some(name) => {
+                let proc = Expression::Function(Procedure {
+                    name: Some(name.to_string()),
+                    params: Rc::new(Parameters {
+                        required: names,
+                        ..Parameters::default()
+                    }),
+                    body,
+                    env: let_env.clone(),
+                });
+                let_env.insert(name.clone(), proc.clone());
+                self.apply(Some(name), proc, values)
+            }
+            None => {
+                for (name, value) in names.into_iter().zip(values) {
+                    let_env.insert(name, value);
+                }
+                Ok(self.sequence(body, true, let_env))
+            }
+        }
+    }
+
+    fn eval_quasiquote(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        let template = match list {
+            [_, template] => template.clone(),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'quasiquote' requires exactly one argument",
+                ))
+            }
+        };
+
+        let mut exprs = Vec::new();
+        unquoted_exprs(&template, 1, &mut exprs);
+        exprs.reverse();
+
+        self.next_unquoted(template, Vec::new(), exprs, env)
+    }
+
+    fn next_unquoted(
+        &mut self,
+        template: Expression,
+        values: Vec<Expression>,
+        mut exprs: Vec<Expression>,
+        env: Environment,
+    ) -> Result<State, Error> {
+        match exprs.pop() {
+            Some(expr) => {
+                let frame = Frame::Quasiquote {
+                    template,
+                    values,
+                    exprs,
+                    env: env.clone(),
+                };
+                Ok(self.push(frame, expr, env))
+            }
+            None => {
+                let mut values = values.into_iter();
+                Ok(State::Return(fill_quasiquote(&template, 1, &mut values)?))
+            }
+        }
+    }
+
+    fn eval_syntax_case(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        let (expr, literals) = match list.get(1..3) {
+            Some([expr, Expression::List(literals)]) => (expr, literals),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'syntax-case' requires an expression and a list of literals",
+                ))
+            }
+        };
+
+        let literals = literals
+            .iter()
+            .filter_map(|literal| match literal {
+                Expression::Symbol(s) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect();
+
+        let frame = Frame::SyntaxCase {
+            literals,
+            clauses: reversed(&list[3..]),
+            env: env.clone(),
+        };
+        Ok(self.push(frame, expr.clone(), env))
+    }
+
+    // Match a syntax object against the patterns of `syntax-case` in turn, and
+    // evaluate the output of the first clause that matches and whose fender,
+    // if it has one, is true
+    fn next_syntax_clause(
+        &mut self,
+        form: Expression,
+        literals: Vec<String>,
+        mut clauses: Vec<Expression>,
+        env: Environment,
+    ) -> Result<State, Error> {
+        while let Some(clause) = clauses.pop() {
+            let (pattern, fender, output) = match clause {
+                Expression::List(clause) => match clause.as_slice() {
+                    [pattern, output] => (pattern.clone(), None, output.clone()),
+                    [pattern, fender, output] => {
+                        (pattern.clone(), Some(fender.clone()), output.clone())
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Syntax,
+                            "Invalid clause in syntax-case expression",
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid clause in syntax-case expression",
+                    ))
+                }
+            };
+
+            let bindings = match match_syntax(&pattern, &form, &literals) {
+                Some(bindings) => bindings,
+                None => continue,
+            };
+
+            let mut clause_env = env.extend();
+            for (name, value) in bindings {
+                clause_env.insert(name, value);
+            }
+
+            return Ok(match fender {
+                Some(fender) => {
+                    let frame = Frame::Fender {
+                        form,
+                        literals,
+                        clauses,
+                        output,
+                        clause_env: clause_env.clone(),
+                        env,
+                    };
+                    self.push(frame, fender, clause_env)
+                }
+                None => State::Eval(output, clause_env),
+            });
+        }
+
+        Err(Error::new(
+            ErrorKind::Syntax,
+            format!("No matching syntax-case clause for {}", form),
+        ))
+    }
+
+    // `(macroexpand form)` and `(macroexpand-1 form)` show the expansion of a
+    // quoted macro use
+    fn eval_macroexpand(&mut self, list: &[Expression], env: Environment) -> Result<State, Error> {
+        match list {
+            [_, form] => {
+                let frame = Frame::Macroexpand {
+                    once: is_form(list, "macroexpand-1"),
+                    env: env.clone(),
+                };
+                Ok(self.push(frame, form.clone(), env))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Syntax,
+                format!("'{}' requires exactly one argument", list[0]),
+            )),
+        }
+    }
+
+    fn next_argument(
+        &mut self,
+        name: Option<Symbol>,
+        proc: Expression,
+        values: Vec<Expression>,
+        mut args: Vec<Expression>,
+        env: Environment,
+        span: Option<Span>,
+    ) -> Result<State, Error> {
+        match args.pop() {
+            Some(arg) => {
+                let frame = Frame::Arguments {
+                    name,
+                    proc,
+                    values,
+                    args,
+                    env: env.clone(),
+                    span,
+                };
+                Ok(self.push(frame, arg, env))
+            }
+            None => self.apply(name, proc, values).map_err(|mut error| {
+                error.span = error.span.or(span);
+                error
+            }),
+        }
+    }
+
+    fn call_procedure(
+        &mut self,
+        name: Option<&str>,
+        function: &Procedure,
+        args: Vec<Expression>,
+    ) -> Result<State, Error> {
+        let proc_name = function
+            .name
+            .as_deref()
+            .or(name)
+            .unwrap_or("anonymous procedure");
+
+        check_arity(proc_name, function.params.arity(), args.len())?;
+
+        // Create a new execution environment for the function
+        let mut local_env = function.env.extend();
+
+        bind_arguments(&function.params, args, &mut local_env)?;
+
+        let name = proc_name.to_string();
+        match self.stack.last_mut() {
+            Some(Frame::Call { name: caller, .. }) => *caller = name,
+            _ => {
+                let depth = self.call_depth() + 1;
+                self.stack.push(Frame::Call { name, depth });
+            }
+        }
+
+        Ok(self.sequence(function.body.clone(), true, local_env))
+    }
+
+    // Call a procedure. The body of a compound procedure is evaluated without
+    // pushing a frame, so tail calls run in constant space.
+    fn apply(
+        &mut self,
+        name: Option<Symbol>,
+        proc: Expression,
+        args: Vec<Expression>,
+    ) -> Result<State, Error> {
+        budget::step(|| self.call_depth())?;
+
+        match &proc {
+            Expression::Func(builtin) => {
+                check_arity(builtin.name, builtin.arity, args.len())?;
+                let value = (builtin.func)(&args).map_err(|e| Error {
+                    message: format!("{}: {}", builtin.name, e.message),
+                    stack: vec![builtin.name.to_string()],
+                    ..e
+                })?;
+                budget::allocate_result(&value);
+                Ok(State::Return(value))
+            }
+            Expression::Function(function) => self.call_procedure(name.as_deref(), function, args),
+            Expression::Closure(closure) => {
+                let vm = Vm::calling(closure, args, name.as_deref(), self.call_depth())?;
+                self.run_vm(vm)
+            }
+            Expression::CaseLambda(clauses) => {
+                // Dispatch on the number of arguments to the first matching clause
+                let function = clauses
+                    .iter()
+                    .find(|clause| clause.params.arity().accepts(args.len()))
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::Arity,
+                            format!(
+                                "No clause of {} accepts {} arguments",
+                                name.as_deref().unwrap_or("case-lambda"),
+                                args.len()
+                            ),
+                        )
+                    })?;
+
+                self.call_procedure(name.as_deref(), function, args)
+            }
+            Expression::Control(control) => {
+                check_arity(control.name(), control.arity(), args.len())?;
+                self.apply_control(*control, args)
+            }
+            Expression::Continuation(k) => {
+                check_arity("continuation", Arity::Between(0, 1), args.len())?;
+                let value = args.into_iter().next().unwrap_or(Expression::Void);
+
+                let target = if k.delimited {
+                    // Run the captured frames on top of the current ones, under
+                    // a prompt of their own
+                    let mut target = self.stack.clone();
+                    target.push(Frame::Prompt {
+                        tag: default_prompt_tag(),
+                        handler: None,
+                    });
+                    target.extend(k.frames.iter().cloned());
+                    target
+                } else {
+                    // Abandon the current computation for the captured one
+                    k.frames.as_ref().clone()
+                };
+
+                self.wind_to(target, Transfer::Return(value))
+            }
+            _ => {
+                Err(Error::new(ErrorKind::Type, "Not a procedure:")
+                    .with_irritants(vec![proc.clone()]))
+            }
+        }
+    }
+
+    fn apply_control(&mut self, control: Control, args: Vec<Expression>) -> Result<State, Error> {
+        let mut args = args.into_iter();
+
+        match control {
+            Control::CallCC => {
+                let k = Continuation {
+                    frames: Rc::new(self.stack.clone()),
+                    delimited: false,
+                };
+                let receiver = args.next().unwrap_or(Expression::Void);
+                self.apply(None, receiver, vec![Expression::Continuation(k)])
+            }
+            Control::DynamicWind => {
+                let (before, thunk, after) = match (args.next(), args.next(), args.next()) {
+                    (Some(before), Some(thunk), Some(after)) => (before, thunk, after),
+                    _ => unreachable!(),
+                };
+                let winder = Rc::new(Winder {
+                    before: before.clone(),
+                    after,
+                });
+                self.stack.push(Frame::WindBefore { winder, thunk });
+                self.apply(None, before, Vec::new())
+            }
+            Control::CallWithPrompt => {
+                let thunk = args.next().unwrap_or(Expression::Void);
+                let tag = args.next().unwrap_or_else(default_prompt_tag);
+                let handler = args.next();
+                self.stack.push(Frame::Prompt { tag, handler });
+                self.apply(None, thunk, Vec::new())
+            }
+            Control::Abort => {
+                let tag = args.next().unwrap_or(Expression::Void);
+                let values: Vec<Expression> = args.collect();
+
+                let i = self.find_prompt(&tag)?;
+                let handler = match &self.stack[i] {
+                    Frame::Prompt { handler, .. } => handler.clone(),
+                    _ => unreachable!(),
+                };
+
+                // Without a handler, the prompt returns the first value
+                let transfer = match handler {
+                    Some(handler) => Transfer::Apply(handler, values),
+                    None => Transfer::Return(values.into_iter().next().unwrap_or(Expression::Void)),
+                };
+
+                self.wind_to(self.stack[..i].to_vec(), transfer)
+            }
+            Control::Shift => {
+                let receiver = args.next().unwrap_or(Expression::Void);
+
+                let i = self.find_prompt(&default_prompt_tag())?;
+                let k = Continuation {
+                    frames: Rc::new(self.stack[i + 1..].to_vec()),
+                    delimited: true,
+                };
+
+                // The receiver runs under the prompt, without the captured frames
+                let transfer = Transfer::Apply(receiver, vec![Expression::Continuation(k)]);
+                self.wind_to(self.stack[..=i].to_vec(), transfer)
+            }
+            Control::WithHandler => {
+                let handler = args.next().unwrap_or(Expression::Void);
+                let thunk = args.next().unwrap_or(Expression::Void);
+                self.stack.push(Frame::Handler(handler));
+                self.apply(None, thunk, Vec::new())
+            }
+            Control::Raise | Control::RaiseContinuable => {
+                let obj = args.next().unwrap_or(Expression::Void);
+                self.raise(obj, control == Control::RaiseContinuable)
+            }
+            Control::Error => {
+                let message = match args.next() {
+                    Some(Expression::String(message)) => message,
+                    Some(message) => message.to_string(),
+                    None => String::new(),
+                };
+                let mut error = Error::new(ErrorKind::User, message).with_irritants(args.collect());
+                // Nothing has added the span of the call to `error` yet, so only the
+                // procedures are recorded here
+                error.stack.extend(self.call_stack());
+                self.raise(Expression::ErrorObject(Box::new(error)), false)
+            }
+        }
+    }
+
+    // The position of the innermost prompt with a tag
+    fn find_prompt(&self, tag: &Expression) -> Result<usize, Error> {
+        self.stack
+            .iter()
+            .rposition(|frame| matches!(frame, Frame::Prompt { tag: t, .. } if t == tag))
+            .ok_or_else(|| {
+                Error::new(ErrorKind::Continuation, "No continuation prompt with tag")
+                    .with_irritants(vec![tag.clone()])
+            })
+    }
+
+    // Replace the stack with the frames of a continuation and return a value
+    // to them or call a procedure in them. On the way, the after thunks of the `dynamic-wind` forms being
+    // left run from the innermost out, then the before thunks of the ones
+    // being entered from the outermost in, each in its own dynamic context.
+    fn wind_to(&mut self, target: Vec<Frame>, transfer: Transfer) -> Result<State, Error> {
+        let current = winders(&self.stack);
+        let wanted = winders(&target);
+
+        let common = current
+            .iter()
+            .zip(&wanted)
+            .take_while(|((_, a), (_, b))| Rc::ptr_eq(a, b))
+            .count();
+
+        let mut steps = Vec::new();
+        for (i, winder) in current[common..].iter().rev() {
+            steps.push((winder.after.clone(), self.stack[..*i].to_vec()));
+        }
+        for (i, winder) in &wanted[common..] {
+            steps.push((winder.before.clone(), target[..*i].to_vec()));
+        }
+
+        // The steps are popped off the end
+        steps.reverse();
+
+        self.next_rewind(steps, target, transfer)
+    }
+
+    fn next_rewind(
+        &mut self,
+        mut steps: Vec<(Expression, Vec<Frame>)>,
+        target: Vec<Frame>,
+        transfer: Transfer,
+    ) -> Result<State, Error> {
+        match steps.pop() {
+            Some((thunk, context)) => {
+                self.stack = context;
+                self.stack.push(Frame::Rewind {
+                    steps,
+                    target,
+                    transfer,
+                });
+                self.apply(None, thunk, Vec::new())
+            }
+            None => {
+                self.stack = target;
+                match transfer {
+                    Transfer::Return(value) => Ok(State::Return(value)),
+                    Transfer::Apply(proc, args) => self.apply(None, proc, args),
+                    Transfer::Eval(expr, env) => Ok(State::Eval(expr, env)),
+                }
+            }
+        }
+    }
+}
+
+// Evaluate an expression on a machine of its own
+fn eval_expr(expr: Expression, env: Environment) -> Result<Expression, Error> {
+    nested(|| Machine::new().run(State::Eval(expr, env)))
+}
+
+// Evaluate the expanded transformer expression of a syntax definition
+pub(crate) fn eval_transformer(
+    expr: Expression,
+    env: &mut Environment,
+) -> Result<Expression, Error> {
+    match eval_expr(expr, env.clone())? {
+        proc @ (Expression::Func(_)
+        | Expression::Function(_)
+        | Expression::CaseLambda(_)
+        | Expression::Closure(_)) => Ok(proc),
+        _ => Err(Error::new(
+            ErrorKind::Type,
+            "A macro transformer must be syntax-rules or a procedure",
+        )),
+    }
+}
+
+// Call a procedure on a machine of its own
+pub(crate) fn apply_procedure(
+    proc: &Expression,
+    args: Vec<Expression>,
+) -> Result<Expression, Error> {
+    nested(|| {
+        let mut machine = Machine::new();
+        let state = machine.apply(None, proc.clone(), args)?;
+        machine.run(state)
+    })
+}
+
+// Call a procedure transformer to compute the expansion of a macro use
+pub(crate) fn apply_transformer(
+    transformer: &Expression,
+    args: Vec<Expression>,
+) -> Result<Expression, Error> {
+    apply_procedure(transformer, args)
+}
+
+// The evaluators that can run a program. The bytecode engine compiles a
+// program and runs it on a virtual machine, and leaves the programs with forms
+// it doesn't compile to the tree-walker. The strict bytecode engine reports
+// those as errors instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    #[default]
+    TreeWalker,
+    Bytecode,
+    BytecodeOnly,
+}
+
+pub fn eval(program: &str, env: &mut Environment) -> Result<Expression, Error> {
+    eval_with(program, env, Engine::TreeWalker)
+}
+
+pub fn eval_with(
+    program: &str,
+    env: &mut Environment,
+    engine: Engine,
+) -> Result<Expression, Error> {
+    eval_with_budget(program, env, engine, Budget::default())
+}
+
+// Evaluate a program that must stay within `budget`. Going over it stops the
+// evaluation with an error of kind `ErrorKind::Limit`, and leaves the
+// environment with whatever definitions were made until then.
+pub fn eval_with_budget(
+    program: &str,
+    env: &mut Environment,
+    engine: Engine,
+    budget: Budget,
+) -> Result<Expression, Error> {
+    with_budget(budget, || run_program(program, env, engine))
+}
+
+fn run_program(program: &str, env: &mut Environment, engine: Engine) -> Result<Expression, Error> {
+    let parsed_expr = parse(program)?;
+
+    // Macros are expanded away before evaluation
+    let expanded_expr = expand(&parsed_expr, env)?;
+
+    if engine != Engine::TreeWalker {
+        match compile(&expanded_expr, env) {
+            Ok(code) => return Machine::new().run_bytecode(Vm::program(code)),
+            Err(error) if engine == Engine::BytecodeOnly => return Err(error),
+            Err(_) => {}
+        }
+    }
 
-    Ok(evaluated_expression)
+    // A program is evaluated as a top-level body, splicing any `begin` forms,
+    // once its local variables are resolved to the slots they are bound in
+    let mut machine = Machine::new();
+    let state = machine.sequence([resolve(&expanded_expr)].into(), true, env.clone());
+    machine.run(state)
 }
@@ -0,0 +1,1933 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::budget;
+use crate::env::Environment;
+use crate::error::{Error, ErrorKind};
+use crate::eval::{apply_transformer, eval_transformer, grow_stack, is_keyword};
+use crate::parser::{Expression, List, Macro, SyntaxRules};
+use crate::symbol::Symbol;
+
+// Identifiers introduced by a macro expansion are renamed by appending a mark,
+// so that `tmp` in a template becomes `tmp 3`. The lexer never produces a
+// symbol containing a space, so a renamed identifier can't clash with a name
+// written by the user, which is what keeps macro-introduced bindings from
+// capturing user variables.
+const MARK_SEPARATOR: char = ' ';
+
+static NEXT_MARK: AtomicUsize = AtomicUsize::new(1);
+
+static NEXT_GENSYM: AtomicUsize = AtomicUsize::new(1);
+
+// The scope each mark's macro was defined in. Marks that are missing belong to
+// macros defined at the top level.
+type MarkScopes = Rc<RefCell<HashMap<usize, Rc<Scope>>>>;
+
+// The expansion a procedure transformer is running for, so that identifiers it
+// compares can be looked up where its output will go
+#[derive(Clone)]
+struct Transforming {
+    env: Environment,
+    root: Rc<Scope>,
+    marks: MarkScopes,
+    mark: usize,
+    use_scope: Rc<Scope>,
+}
+
+thread_local! {
+    static TRANSFORMING: RefCell<Vec<Transforming>> = const { RefCell::new(Vec::new()) };
+}
+
+// The special forms understood by the evaluator, plus the syntax definitions
+// handled here. Expansion rewrites every program into these forms.
+pub(crate) const CORE_FORMS: &[&str] = &[
+    "define",
+    "define*",
+    "set!",
+    "lambda",
+    "lambda*",
+    "case-lambda",
+    "if",
+    "cond",
+    "case",
+    "when",
+    "unless",
+    "and",
+    "or",
+    "begin",
+    "do",
+    "let",
+    "quote",
+    "quasiquote",
+    "unquote",
+    "unquote-splicing",
+    "macroexpand",
+    "macroexpand-1",
+    "reset",
+    "shift",
+    "guard",
+    "define-syntax",
+    "define-macro",
+    "let-syntax",
+    "letrec-syntax",
+    "syntax-rules",
+    "syntax",
+    "syntax-case",
+    "with-syntax",
+];
+
+#[derive(Clone)]
+enum Binding {
+    // A local variable, under the fresh name the expansion gives it
+    Variable(Symbol),
+    Macro(Macro, Weak<Scope>),
+    // A pattern variable of `syntax-case`, bound under this many ellipses
+    PatternVariable(usize),
+}
+
+// A lexical scope seen during expansion. Top-level variables and macros live in
+// the `Environment` instead, so the root scope never holds any bindings.
+struct Scope {
+    bindings: RefCell<HashMap<String, Binding>>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    fn new(parent: Option<Rc<Scope>>) -> Rc<Scope> {
+        Rc::new(Scope {
+            bindings: RefCell::new(HashMap::new()),
+            parent,
+        })
+    }
+
+    fn bind(&self, name: &str, binding: Binding) {
+        self.bindings.borrow_mut().insert(name.to_string(), binding);
+    }
+
+    // Bind a local variable and return its new name. Local variables get
+    // uninterned names, so a free identifier that a macro introduces, which
+    // keeps its plain name, can't be captured by one of them.
+    fn bind_variable(&self, name: &str) -> Symbol {
+        let renamed = Symbol::uninterned(strip_marks(name));
+        self.bind(name, Binding::Variable(renamed.clone()));
+        renamed
+    }
+
+    // Bind a variable for an internal definition. Defining a name again in
+    // the same scope, such as that of a parameter, assigns the same variable.
+    fn define_variable(&self, name: &str) -> Symbol {
+        let bound = self.bindings.borrow().get(name).cloned();
+        match bound {
+            Some(Binding::Variable(renamed)) => renamed,
+            _ => self.bind_variable(name),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Binding> {
+        match self.bindings.borrow().get(name) {
+            Some(binding) => Some(binding.clone()),
+            None => self.parent.as_ref().and_then(|parent| parent.lookup(name)),
+        }
+    }
+}
+
+// What an identifier refers to where it appears
+enum Denotation {
+    Variable(Symbol),
+    Macro(Macro, Rc<Scope>),
+    Core(String),
+    PatternVariable(String, usize),
+}
+
+// The values matched by the pattern variables of a syntax rule. Variables
+// followed by an ellipsis match a sequence of values.
+#[derive(Clone)]
+enum MatchTree {
+    One(Expression),
+    Many(Vec<MatchTree>),
+}
+
+impl MatchTree {
+    fn depth(&self) -> usize {
+        match self {
+            MatchTree::One(_) => 0,
+            MatchTree::Many(items) => 1 + items.first().map_or(0, MatchTree::depth),
+        }
+    }
+
+    // Pattern variables of `syntax-case` are bound to ordinary values at run
+    // time, with matched sequences as lists
+    fn into_value(self) -> Expression {
+        match self {
+            MatchTree::One(expr) => expr,
+            MatchTree::Many(items) => {
+                Expression::List(items.into_iter().map(MatchTree::into_value).collect())
+            }
+        }
+    }
+
+    fn from_value(value: Expression, depth: usize) -> Option<MatchTree> {
+        if depth == 0 {
+            return Some(MatchTree::One(value));
+        }
+
+        match value {
+            Expression::List(items) => items
+                .into_iter()
+                .map(|item| MatchTree::from_value(item, depth - 1))
+                .collect::<Option<Vec<MatchTree>>>()
+                .map(MatchTree::Many),
+            _ => None,
+        }
+    }
+}
+
+type Bindings = HashMap<String, MatchTree>;
+
+fn split_mark(name: &str) -> Option<(&str, usize)> {
+    let (base, mark) = name.rsplit_once(MARK_SEPARATOR)?;
+    Some((base, mark.parse().ok()?))
+}
+
+fn strip_marks(name: &str) -> &str {
+    name.split(MARK_SEPARATOR).next().unwrap_or(name)
+}
+
+// A symbol without its marks. Unmarked symbols are returned as they are, so an
+// uninterned symbol stays apart from interned ones with the same name.
+fn unmarked(symbol: &Symbol) -> Symbol {
+    match strip_marks(symbol) {
+        name if name.len() == symbol.len() => symbol.clone(),
+        name => name.into(),
+    }
+}
+
+// Turn a piece of syntax back into plain data, as for the data of `case`
+pub(crate) fn strip_syntax(expr: &Expression) -> Expression {
+    match expr {
+        Expression::Symbol(s) => Expression::Symbol(unmarked(s)),
+        Expression::List(list) => {
+            Expression::List(grow_stack(|| list.iter().map(strip_syntax).collect()))
+        }
+        _ => expr.clone(),
+    }
+}
+
+// Give the symbols of a datum the lexical context of the identifier `context`,
+// so that they behave as if they had been written where it was
+pub(crate) fn datum_to_syntax(context: &str, datum: &Expression) -> Expression {
+    let marks = context.find(MARK_SEPARATOR).map_or("", |i| &context[i..]);
+
+    match datum {
+        Expression::Symbol(s) if s == "." || is_keyword(s) => datum.clone(),
+        Expression::Symbol(s) => Expression::Symbol(format!("{}{}", strip_marks(s), marks).into()),
+        Expression::List(list) => Expression::List(grow_stack(|| {
+            list.iter()
+                .map(|item| datum_to_syntax(context, item))
+                .collect()
+        })),
+        _ => datum.clone(),
+    }
+}
+
+// Symbols made from strings at run time can't have the mark separator in
+// their names, or they would pass for identifiers renamed by a macro
+pub(crate) fn check_symbol_name(name: &str) -> Result<(), Error> {
+    if name.contains(MARK_SEPARATOR) {
+        return Err(
+            Error::new(ErrorKind::Type, "Symbol names cannot contain spaces:")
+                .with_irritants(vec![Expression::String(name.to_string())]),
+        );
+    }
+    Ok(())
+}
+
+// A fresh symbol for `define-macro` transformers to bind without capturing
+// anything. The `#:` prefix keeps it apart from ordinary names.
+pub(crate) fn gensym(prefix: &str) -> Expression {
+    let id = NEXT_GENSYM.fetch_add(1, Ordering::Relaxed);
+    Expression::Symbol(Symbol::uninterned(&format!("#:{}{}", prefix, id)))
+}
+
+// Whether two identifiers would refer to the same binding if they were free.
+// While a procedure transformer runs they are resolved as part of its output,
+// so a literal shadowed where the macro is used no longer matches. Elsewhere
+// there are no scopes to look in and only the names are compared.
+pub(crate) fn free_identifier_eq(a: &str, b: &str) -> bool {
+    match TRANSFORMING.with(|transforming| transforming.borrow().last().cloned()) {
+        Some(mut context) => {
+            let expander = Expander {
+                env: &mut context.env,
+                root: context.root,
+                marks: context.marks,
+            };
+            let a = toggle_name(a, context.mark);
+            let b = toggle_name(b, context.mark);
+            expander.same_binding(&a, &context.use_scope, &b, &context.use_scope)
+        }
+        None => strip_marks(a) == strip_marks(b),
+    }
+}
+
+fn toggle_name(name: &str, mark: usize) -> String {
+    match split_mark(name) {
+        Some((base, m)) if m == mark => base.to_string(),
+        _ => format!("{}{}{}", name, MARK_SEPARATOR, mark),
+    }
+}
+
+// Flip a mark on every identifier of a piece of syntax. A procedure transformer
+// sees its input with the mark added, so anything it builds from that input
+// gets the mark removed again on the way out, while identifiers introduced by
+// the transformer keep the mark like those of a `syntax-rules` template.
+fn toggle_mark(expr: &Expression, mark: usize) -> Expression {
+    match expr {
+        Expression::Symbol(s) if s == "." || is_keyword(s) => expr.clone(),
+        Expression::Symbol(s) => Expression::Symbol(toggle_name(s, mark).into()),
+        Expression::List(list) => Expression::List(grow_stack(|| {
+            list.iter().map(|item| toggle_mark(item, mark)).collect()
+        })),
+        _ => expr.clone(),
+    }
+}
+
+// Collect the pattern variables of a `syntax-case` pattern along with the
+// number of ellipses each one is nested under
+fn pattern_var_depths(
+    pattern: &Expression,
+    literals: &[String],
+    depth: usize,
+    vars: &mut Vec<(String, usize)>,
+) {
+    match pattern {
+        Expression::Symbol(s)
+            if s != "_"
+                && s != "."
+                && s != "..."
+                && !literals.iter().any(|literal| **s == **literal) =>
+        {
+            vars.push((s.to_string(), depth));
+        }
+        Expression::List(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let repeated = items.get(i + 1).is_some_and(|next| is_symbol(next, "..."));
+                pattern_var_depths(item, literals, depth + repeated as usize, vars);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The number of values in a piece of code, counting nested lists
+fn size(expr: &Expression) -> usize {
+    let mut pending = vec![expr];
+    let mut count = 0;
+
+    while let Some(expr) = pending.pop() {
+        count += 1;
+        if let Expression::List(items) = expr {
+            pending.extend(items.iter());
+        }
+    }
+
+    count
+}
+
+fn is_symbol(expr: &Expression, name: &str) -> bool {
+    matches!(expr, Expression::Symbol(s) if s == name)
+}
+
+struct Expander<'a> {
+    env: &'a mut Environment,
+    root: Rc<Scope>,
+    marks: MarkScopes,
+}
+
+impl Expander<'_> {
+    fn resolve(&self, name: &str, scope: &Rc<Scope>) -> Denotation {
+        if let Some(binding) = scope.lookup(name) {
+            return match binding {
+                Binding::Variable(renamed) => Denotation::Variable(renamed),
+                Binding::Macro(mac, def_scope) => Denotation::Macro(
+                    mac,
+                    def_scope.upgrade().unwrap_or_else(|| self.root.clone()),
+                ),
+                Binding::PatternVariable(depth) => {
+                    Denotation::PatternVariable(name.to_string(), depth)
+                }
+            };
+        }
+
+        // An identifier introduced by a macro means what its original name
+        // means where the macro was defined
+        if let Some((base, mark)) = split_mark(name) {
+            let def_scope = self.marks.borrow().get(&mark).cloned();
+            return self.resolve(base, def_scope.as_ref().unwrap_or(&self.root));
+        }
+
+        match self.env.get(name) {
+            Some(Expression::Macro(mac)) => Denotation::Macro(mac, self.root.clone()),
+            _ if CORE_FORMS.contains(&name) => Denotation::Core(name.to_string()),
+            _ => Denotation::Variable(name.into()),
+        }
+    }
+
+    // Whether an identifier refers to an auxiliary keyword such as `else`,
+    // rather than to a local variable with its name
+    fn refers_to(&self, expr: &Expression, scope: &Rc<Scope>, keyword: &str) -> bool {
+        match expr {
+            Expression::Symbol(s) => matches!(
+                self.resolve(s, scope),
+                Denotation::Variable(name) if name.is_interned() && name == keyword
+            ),
+            _ => false,
+        }
+    }
+
+    fn same_binding(&self, a: &str, a_scope: &Rc<Scope>, b: &str, b_scope: &Rc<Scope>) -> bool {
+        match (self.resolve(a, a_scope), self.resolve(b, b_scope)) {
+            (Denotation::Variable(a), Denotation::Variable(b)) => a == b,
+            (Denotation::Core(a), Denotation::Core(b)) => a == b,
+            (Denotation::Macro(..), Denotation::Macro(..)) => strip_marks(a) == strip_marks(b),
+            _ => false,
+        }
+    }
+
+    fn expand_toplevel(&mut self, expr: &Expression) -> Result<Expression, Error> {
+        let root = self.root.clone();
+        let expr = self.expand_head(expr, &root)?;
+
+        if let Expression::List(list) = &expr {
+            if let Some(Expression::Symbol(head)) = list.first() {
+                match self.resolve(head, &root) {
+                    Denotation::Core(form) if form == "begin" => {
+                        let mut forms = vec![Expression::Symbol("begin".into())];
+                        for form in &list[1..] {
+                            forms.push(self.expand_toplevel(form)?);
+                        }
+                        return Ok(Expression::List(forms.into()));
+                    }
+                    Denotation::Core(form) if form == "define-syntax" || form == "define-macro" => {
+                        let (name, mac) = self.parse_syntax_definition(&form, list, &root)?;
+                        self.env
+                            .insert(strip_marks(&name).to_string(), Expression::Macro(mac));
+                        return Ok(Expression::Void);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.expand_expr(&expr, &root)
+    }
+
+    // Expand macro uses at the head of a form until it is no longer one
+    fn expand_head(&mut self, expr: &Expression, scope: &Rc<Scope>) -> Result<Expression, Error> {
+        let mut expr = expr.clone();
+
+        while let Expression::List(list) = &expr {
+            let (mac, def_scope) = match list.first() {
+                Some(Expression::Symbol(head)) => match self.resolve(head, scope) {
+                    Denotation::Macro(mac, def_scope) => (mac, def_scope),
+                    _ => break,
+                },
+                _ => break,
+            };
+
+            // An expansion points errors at the macro use it came from
+            expr = match self.apply_macro(&mac, list, &def_scope, scope)? {
+                Expression::List(expansion) if expansion.span().is_none() => {
+                    Expression::List(expansion.with_span(list.span()))
+                }
+                expansion => expansion,
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn expand_expr(&mut self, expr: &Expression, scope: &Rc<Scope>) -> Result<Expression, Error> {
+        match expr {
+            Expression::Symbol(s) => match self.resolve(s, scope) {
+                // A free identifier written as it is stays the same symbol,
+                // which it may not be if it is uninterned
+                Denotation::Variable(name) if name.is_interned() && name == s.as_str() => {
+                    Ok(expr.clone())
+                }
+                Denotation::Variable(name) => Ok(Expression::Symbol(name)),
+                Denotation::PatternVariable(..) => Err(Error::new(
+                    ErrorKind::Syntax,
+                    format!(
+                        "Pattern variable used outside of a syntax template: {}",
+                        strip_marks(s)
+                    ),
+                )),
+                _ => Err(Error::new(
+                    ErrorKind::Syntax,
+                    format!("Invalid use of syntax keyword: {}", strip_marks(s)),
+                )),
+            },
+            Expression::List(list) => grow_stack(|| {
+                // A large program takes a while to expand even without macros
+                budget::check_interrupt()?;
+
+                if let Some(Expression::Symbol(head)) = list.first() {
+                    match self.resolve(head, scope) {
+                        Denotation::Macro(..) => {
+                            let expansion = self.expand_head(expr, scope)?;
+                            return self.expand_expr(&expansion, scope);
+                        }
+                        Denotation::Core(form) => return self.expand_core(&form, list, scope),
+                        Denotation::Variable(_) | Denotation::PatternVariable(..) => {}
+                    }
+                }
+
+                // Calls keep where they were read from, for errors to point at
+                match self.expand_all(list, scope)? {
+                    Expression::List(expanded) => {
+                        Ok(Expression::List(expanded.with_span(list.span())))
+                    }
+                    _ => unreachable!(),
+                }
+            }),
+            _ => Ok(expr.clone()),
+        }
+    }
+
+    fn expand_all(&mut self, exprs: &[Expression], scope: &Rc<Scope>) -> Result<Expression, Error> {
+        let expanded: Result<Vec<Expression>, Error> = exprs
+            .iter()
+            .map(|expr| self.expand_expr(expr, scope))
+            .collect();
+
+        Ok(Expression::List(expanded?.into()))
+    }
+
+    // Expand a core form, rewriting its keyword to the plain name the evaluator expects
+    fn expand_core(
+        &mut self,
+        form: &str,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        let keyword = Expression::Symbol(form.into());
+
+        let mut expanded = match form {
+            "define" | "define*" => self.expand_define(list, scope, form == "define*")?,
+            "lambda" | "lambda*" => {
+                if list.len() < 3 {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        format!("'{}' requires a parameter list and a body", form),
+                    ));
+                }
+                let lambda_scope = Scope::new(Some(scope.clone()));
+                let mut expanded =
+                    vec![self.expand_formals(&list[1], &lambda_scope, form == "lambda*")?];
+                expanded.extend(self.expand_body(&list[2..], &lambda_scope)?);
+                expanded
+            }
+            "case-lambda" => {
+                let mut clauses = Vec::new();
+                for clause in &list[1..] {
+                    match clause {
+                        Expression::List(clause) if clause.len() >= 2 => {
+                            let clause_scope = Scope::new(Some(scope.clone()));
+                            let mut expanded =
+                                vec![self.expand_formals(&clause[0], &clause_scope, false)?];
+                            expanded.extend(self.expand_body(&clause[1..], &clause_scope)?);
+                            clauses.push(Expression::List(expanded.into()));
+                        }
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::Syntax,
+                                "Invalid clause in case-lambda expression",
+                            ))
+                        }
+                    }
+                }
+                clauses
+            }
+            "cond" => {
+                let mut clauses = Vec::new();
+                for clause in &list[1..] {
+                    clauses.push(self.expand_clause(clause, scope)?);
+                }
+                clauses
+            }
+            "case" => {
+                if list.len() < 2 {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'case' requires a key expression",
+                    ));
+                }
+                let mut expanded = vec![self.expand_expr(&list[1], scope)?];
+                for clause in &list[2..] {
+                    expanded.push(self.expand_case_clause(clause, scope)?);
+                }
+                expanded
+            }
+            "do" => self.expand_do(list, scope)?,
+            "let" => self.expand_let(list, scope)?,
+            // Quoted data are not evaluated, so they lose their lexical context
+            "quote" => match list {
+                [_, datum] => vec![strip_syntax(datum)],
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'quote' requires exactly one argument",
+                    ))
+                }
+            },
+            "syntax" => match list {
+                [_, template] => self.expand_template(template, scope),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'syntax' requires exactly one template",
+                    ))
+                }
+            },
+            "syntax-case" => self.expand_syntax_case(list, scope)?,
+            "quasiquote" => match list {
+                [_, template] => vec![self.expand_quasiquote(template, 1, scope)?],
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'quasiquote' requires exactly one argument",
+                    ))
+                }
+            },
+            "unquote" | "unquote-splicing" => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    format!("'{}' is only allowed in a quasiquote", form),
+                ))
+            }
+            // `(reset body ...)` and `(shift k body ...)` delimit and capture
+            // continuations around their bodies, which become procedures
+            "reset" | "shift" => {
+                let formals = match (form, list.get(1)) {
+                    ("reset", _) if list.len() >= 2 => Expression::List(List::new()),
+                    ("shift", Some(k @ Expression::Symbol(_))) if list.len() >= 3 => {
+                        Expression::List(vec![k.clone()].into())
+                    }
+                    ("reset", _) => {
+                        return Err(Error::new(ErrorKind::Syntax, "'reset' requires a body"))
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Syntax,
+                            "'shift' requires a variable and a body",
+                        ))
+                    }
+                };
+                let body_start = if form == "reset" { 1 } else { 2 };
+                let mut lambda = vec![Expression::Symbol("lambda".into()), formals];
+                lambda.extend_from_slice(&list[body_start..]);
+                vec![self.expand_core("lambda", &lambda, scope)?]
+            }
+            // `(guard (var clause ...) body ...)`, where the clauses see the
+            // raised object as `var`
+            "guard" => match list {
+                [_, Expression::List(spec), _, ..]
+                    if matches!(spec.first(), Some(Expression::Symbol(_))) =>
+                {
+                    let clause_scope = Scope::new(Some(scope.clone()));
+                    let var = match &spec[0] {
+                        Expression::Symbol(var) => clause_scope.bind_variable(var),
+                        _ => unreachable!(),
+                    };
+                    let mut expanded_spec = vec![Expression::Symbol(var)];
+                    for clause in &spec[1..] {
+                        expanded_spec.push(self.expand_clause(clause, &clause_scope)?);
+                    }
+
+                    let body_scope = Scope::new(Some(scope.clone()));
+                    let mut expanded = vec![Expression::List(expanded_spec.into())];
+                    expanded.extend(self.expand_body(&list[2..], &body_scope)?);
+                    expanded
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "'guard' requires a variable, clauses and a body",
+                    ))
+                }
+            },
+            "with-syntax" => return self.expand_with_syntax(list, scope),
+            "let-syntax" | "letrec-syntax" => {
+                return self.expand_let_syntax(list, scope, form == "letrec-syntax")
+            }
+            "define-syntax" | "define-macro" => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    format!("'{}' is only allowed at the top level or in a body", form),
+                ))
+            }
+            "syntax-rules" => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'syntax-rules' is only allowed in a syntax definition",
+                ))
+            }
+            // The remaining forms only contain expressions
+            _ => match self.expand_all(&list[1..], scope)? {
+                Expression::List(expanded) => expanded.into_vec(),
+                _ => unreachable!(),
+            },
+        };
+
+        expanded.insert(0, keyword);
+        Ok(Expression::List(expanded.into()))
+    }
+
+    fn expand_define(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+        extended: bool,
+    ) -> Result<Vec<Expression>, Error> {
+        if list.len() < 3 {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "'define' requires at least two arguments",
+            ));
+        }
+
+        match &list[1] {
+            Expression::List(func) if !func.is_empty() => {
+                let name = self.expand_binder(&func[0], scope)?;
+                let lambda_scope = Scope::new(Some(scope.clone()));
+                let formals = match self.expand_formals(
+                    &Expression::List(func.tail(1)),
+                    &lambda_scope,
+                    extended,
+                )? {
+                    Expression::List(formals) => formals,
+                    _ => unreachable!(),
+                };
+
+                let mut signature = vec![name];
+                signature.extend(formals);
+
+                let mut expanded = vec![Expression::List(signature.into())];
+                expanded.extend(self.expand_body(&list[2..], &lambda_scope)?);
+                Ok(expanded)
+            }
+            Expression::Symbol(_) => {
+                let name = self.expand_binder(&list[1], scope)?;
+                let mut expanded = vec![name];
+                for expr in &list[2..] {
+                    expanded.push(self.expand_expr(expr, scope)?);
+                }
+                Ok(expanded)
+            }
+            _ => Err(Error::new(ErrorKind::Syntax, "Invalid define syntax")),
+        }
+    }
+
+    // The name bound by a definition. Top-level definitions introduced by a
+    // macro are visible under their plain name, and internal definitions
+    // under the name their body gave them.
+    fn expand_binder(&self, binder: &Expression, scope: &Rc<Scope>) -> Result<Expression, Error> {
+        match binder {
+            Expression::Symbol(s) if Rc::ptr_eq(scope, &self.root) => {
+                Ok(Expression::Symbol(unmarked(s)))
+            }
+            Expression::Symbol(s) => Ok(Expression::Symbol(scope.define_variable(s))),
+            _ => Err(Error::new(ErrorKind::Syntax, "Invalid define syntax")),
+        }
+    }
+
+    // Bind the parameters of a procedure in its scope and expand the default
+    // expressions of optional parameters
+    fn expand_formals(
+        &mut self,
+        formals: &Expression,
+        scope: &Rc<Scope>,
+        extended: bool,
+    ) -> Result<Expression, Error> {
+        let items = match formals {
+            Expression::Symbol(s) => return Ok(Expression::Symbol(scope.bind_variable(s))),
+            Expression::List(items) => items,
+            _ => return Err(Error::new(ErrorKind::Syntax, "Invalid parameter list")),
+        };
+
+        let mut expanded = Vec::new();
+
+        for item in items {
+            match item {
+                Expression::Symbol(s) if s == "." => expanded.push(item.clone()),
+                Expression::Symbol(s) => expanded.push(Expression::Symbol(scope.bind_variable(s))),
+                Expression::List(spec) if extended && (spec.len() == 2 || spec.len() == 3) => {
+                    // Default expressions can refer to the parameters before them
+                    let default = self.expand_expr(&spec[spec.len() - 1], scope)?;
+                    let mut spec_expanded = spec[..spec.len() - 1].to_vec();
+                    if let Some(Expression::Symbol(name)) = spec_expanded.pop() {
+                        spec_expanded.push(Expression::Symbol(scope.bind_variable(&name)));
+                    }
+                    spec_expanded.push(default);
+                    expanded.push(Expression::List(spec_expanded.into()));
+                }
+                _ => return Err(Error::new(ErrorKind::Syntax, "Invalid parameter name")),
+            }
+        }
+
+        Ok(Expression::List(expanded.into()))
+    }
+
+    // Expand a body. Macro uses are expanded far enough to find the internal
+    // definitions first, so that every definition is in scope for the whole body.
+    fn expand_body(
+        &mut self,
+        body: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<Vec<Expression>, Error> {
+        let mut pending: Vec<Expression> = body.iter().rev().cloned().collect();
+        let mut forms = Vec::new();
+
+        while let Some(form) = pending.pop() {
+            let form = self.expand_head(&form, scope)?;
+
+            let form_name = match &form {
+                Expression::List(list) => match list.first() {
+                    Some(Expression::Symbol(head)) => match self.resolve(head, scope) {
+                        Denotation::Core(name) => Some(name),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            let list = match &form {
+                Expression::List(list) => list,
+                _ => {
+                    forms.push(form);
+                    continue;
+                }
+            };
+
+            match form_name.as_deref() {
+                // Splice `begin` forms into the body
+                Some("begin") => pending.extend(list[1..].iter().rev().cloned()),
+                Some(form @ ("define-syntax" | "define-macro")) => {
+                    let (name, mac) = self.parse_syntax_definition(form, list, scope)?;
+                    scope.bind(&name, Binding::Macro(mac, Rc::downgrade(scope)));
+                }
+                Some("define") | Some("define*") => {
+                    match list.get(1) {
+                        Some(Expression::Symbol(name)) => {
+                            scope.define_variable(name);
+                        }
+                        Some(Expression::List(func)) => {
+                            if let Some(Expression::Symbol(name)) = func.first() {
+                                scope.define_variable(name);
+                            }
+                        }
+                        _ => {}
+                    }
+                    forms.push(form);
+                }
+                _ => forms.push(form),
+            }
+        }
+
+        forms
+            .iter()
+            .map(|form| self.expand_expr(form, scope))
+            .collect()
+    }
+
+    // Expand a clause of `cond`, or the `else` clause of `case`
+    fn expand_clause(
+        &mut self,
+        clause: &Expression,
+        scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        let clause = match clause {
+            Expression::List(clause) if !clause.is_empty() => clause,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Invalid clause in conditional expression",
+                ))
+            }
+        };
+
+        let mut expanded = Vec::new();
+
+        for (i, expr) in clause.iter().enumerate() {
+            if i == 0 && self.refers_to(expr, scope, "else") {
+                expanded.push(Expression::Symbol("else".into()));
+            } else if i == 1 && self.refers_to(expr, scope, "=>") {
+                expanded.push(Expression::Symbol("=>".into()));
+            } else {
+                expanded.push(self.expand_expr(expr, scope)?);
+            }
+        }
+
+        Ok(Expression::List(expanded.into()))
+    }
+
+    fn expand_case_clause(
+        &mut self,
+        clause: &Expression,
+        scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        match clause {
+            // The data of a case clause are not evaluated
+            Expression::List(clause) if matches!(clause.first(), Some(Expression::List(_))) => {
+                let mut expanded = vec![strip_syntax(&clause[0])];
+                for (i, expr) in clause.iter().enumerate().skip(1) {
+                    if i == 1 && self.refers_to(expr, scope, "=>") {
+                        expanded.push(Expression::Symbol("=>".into()));
+                    } else {
+                        expanded.push(self.expand_expr(expr, scope)?);
+                    }
+                }
+                Ok(Expression::List(expanded.into()))
+            }
+            _ => self.expand_clause(clause, scope),
+        }
+    }
+
+    fn expand_do(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<Vec<Expression>, Error> {
+        let (bindings, exit_clause) = match (list.get(1), list.get(2)) {
+            (Some(Expression::List(bindings)), Some(exit_clause)) => (bindings, exit_clause),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'do' requires a list of bindings and a termination clause",
+                ))
+            }
+        };
+
+        let loop_scope = Scope::new(Some(scope.clone()));
+
+        // Initial values are evaluated outside the loop, steps inside it
+        let mut inits = Vec::new();
+        for binding in bindings {
+            match binding {
+                Expression::List(binding) if binding.len() == 2 || binding.len() == 3 => {
+                    let name = match &binding[0] {
+                        Expression::Symbol(name) => {
+                            Expression::Symbol(loop_scope.bind_variable(name))
+                        }
+                        name => name.clone(),
+                    };
+                    inits.push((name, self.expand_expr(&binding[1], scope)?));
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid binding in do expression",
+                    ))
+                }
+            }
+        }
+
+        let mut expanded_bindings = Vec::new();
+        for (binding, (name, init)) in bindings.iter().zip(inits) {
+            if let Expression::List(binding) = binding {
+                let mut expanded = vec![name, init];
+                if let Some(step) = binding.get(2) {
+                    expanded.push(self.expand_expr(step, &loop_scope)?);
+                }
+                expanded_bindings.push(Expression::List(expanded.into()));
+            }
+        }
+
+        let mut expanded = vec![Expression::List(expanded_bindings.into())];
+
+        match exit_clause {
+            Expression::List(exit_clause) => {
+                expanded.push(self.expand_all(exit_clause, &loop_scope)?)
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Invalid termination clause in do expression",
+                ))
+            }
+        }
+
+        for command in &list[3..] {
+            expanded.push(self.expand_expr(command, &loop_scope)?);
+        }
+
+        Ok(expanded)
+    }
+
+    fn expand_let(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<Vec<Expression>, Error> {
+        let let_scope = Scope::new(Some(scope.clone()));
+        let mut expanded = Vec::new();
+
+        // A named let also binds the loop procedure in its body
+        let start = match list.get(1) {
+            Some(Expression::Symbol(name)) => {
+                expanded.push(Expression::Symbol(let_scope.bind_variable(name)));
+                2
+            }
+            _ => 1,
+        };
+
+        let bindings = match list.get(start) {
+            Some(Expression::List(bindings)) if list.len() > start + 1 => bindings,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'let' requires bindings and a body",
+                ))
+            }
+        };
+
+        let mut expanded_bindings = Vec::new();
+        for binding in bindings {
+            match binding {
+                Expression::List(binding) if binding.len() == 2 => {
+                    let name = match &binding[0] {
+                        Expression::Symbol(name) => {
+                            Expression::Symbol(let_scope.bind_variable(name))
+                        }
+                        name => name.clone(),
+                    };
+                    expanded_bindings.push(Expression::List(
+                        vec![name, self.expand_expr(&binding[1], scope)?].into(),
+                    ));
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid binding in let expression",
+                    ))
+                }
+            }
+        }
+
+        expanded.push(Expression::List(expanded_bindings.into()));
+        expanded.extend(self.expand_body(&list[start + 1..], &let_scope)?);
+        Ok(expanded)
+    }
+
+    fn expand_let_syntax(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+        recursive: bool,
+    ) -> Result<Expression, Error> {
+        let bindings = match list.get(1) {
+            Some(Expression::List(bindings)) if list.len() > 2 => bindings,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Syntax bindings require a list of bindings and a body",
+                ))
+            }
+        };
+
+        let syntax_scope = Scope::new(Some(scope.clone()));
+
+        // The transformers of `letrec-syntax` can refer to each other
+        let def_scope = if recursive { &syntax_scope } else { scope };
+
+        for binding in bindings {
+            match binding {
+                Expression::List(binding) if binding.len() == 2 => {
+                    let name = match &binding[0] {
+                        Expression::Symbol(name) => name,
+                        _ => return Err(Error::new(ErrorKind::Syntax, "Invalid syntax binding")),
+                    };
+                    let mac = self.parse_transformer(&binding[1], def_scope)?;
+                    syntax_scope.bind(name, Binding::Macro(mac, Rc::downgrade(def_scope)));
+                }
+                _ => return Err(Error::new(ErrorKind::Syntax, "Invalid syntax binding")),
+            }
+        }
+
+        let mut expanded = vec![
+            Expression::Symbol("let".into()),
+            Expression::List(List::new()),
+        ];
+        expanded.extend(self.expand_body(&list[2..], &syntax_scope)?);
+        Ok(Expression::List(expanded.into()))
+    }
+
+    fn parse_syntax_definition(
+        &mut self,
+        form: &str,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<(String, Macro), Error> {
+        if form == "define-macro" {
+            return self.parse_macro_definition(list, scope);
+        }
+
+        match list {
+            [_, Expression::Symbol(name), spec] => {
+                Ok((name.to_string(), self.parse_transformer(spec, scope)?))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Syntax,
+                "'define-syntax' requires a name and a transformer",
+            )),
+        }
+    }
+
+    // `(define-macro (name . formals) body ...)` or `(define-macro name expr)`,
+    // where the procedure receives the unevaluated arguments of a macro use
+    fn parse_macro_definition(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<(String, Macro), Error> {
+        let (name, transformer) = match list {
+            [_, Expression::Symbol(name), expr] => (name, self.expand_expr(expr, scope)?),
+            [_, Expression::List(signature), _, ..] => match signature.split_first() {
+                Some((Expression::Symbol(name), formals)) => {
+                    let mut lambda = vec![
+                        Expression::Symbol("lambda".into()),
+                        Expression::List(formals.to_vec().into()),
+                    ];
+                    lambda.extend_from_slice(&list[2..]);
+                    (name, self.expand_core("lambda", &lambda, scope)?)
+                }
+                _ => return Err(Error::new(ErrorKind::Syntax, "Invalid define-macro syntax")),
+            },
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'define-macro' requires a name and a transformer",
+                ))
+            }
+        };
+
+        let transformer = eval_transformer(transformer, self.env)?;
+        Ok((name.to_string(), Macro::DefineMacro(Box::new(transformer))))
+    }
+
+    // A transformer is either a `syntax-rules` form or an expression that is
+    // evaluated right away to a procedure. Like any code run at expansion
+    // time, the procedure can only see top-level bindings.
+    fn parse_transformer(&mut self, spec: &Expression, scope: &Rc<Scope>) -> Result<Macro, Error> {
+        if let Expression::List(list) = spec {
+            if let Some(Expression::Symbol(head)) = list.first() {
+                if matches!(self.resolve(head, scope), Denotation::Core(form) if form == "syntax-rules")
+                {
+                    return Ok(Macro::SyntaxRules(self.parse_syntax_rules(spec, scope)?));
+                }
+            }
+        }
+
+        let expanded = self.expand_expr(spec, scope)?;
+        let transformer = eval_transformer(expanded, self.env)?;
+        Ok(Macro::Procedure(Box::new(transformer)))
+    }
+
+    // Parse `(syntax-rules (literal ...) (pattern template) ...)`, optionally
+    // with a custom ellipsis identifier before the literals
+    fn parse_syntax_rules(
+        &self,
+        spec: &Expression,
+        scope: &Rc<Scope>,
+    ) -> Result<SyntaxRules, Error> {
+        let spec = match spec {
+            Expression::List(spec) if !spec.is_empty() => spec,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Expected a syntax-rules transformer",
+                ))
+            }
+        };
+
+        match &spec[0] {
+            Expression::Symbol(s) => match self.resolve(s, scope) {
+                Denotation::Core(form) if form == "syntax-rules" => {}
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Expected a syntax-rules transformer",
+                    ))
+                }
+            },
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "Expected a syntax-rules transformer",
+                ))
+            }
+        }
+
+        let (ellipsis, rest) = match spec.get(1) {
+            Some(Expression::Symbol(ellipsis)) => (ellipsis.to_string(), &spec[2..]),
+            _ => ("...".to_string(), &spec[1..]),
+        };
+
+        let literals = match rest.first() {
+            Some(Expression::List(literals)) => literals
+                .iter()
+                .map(|literal| match literal {
+                    Expression::Symbol(s) => Ok(s.to_string()),
+                    _ => Err(Error::new(
+                        ErrorKind::Parse,
+                        "Invalid literal in syntax-rules",
+                    )),
+                })
+                .collect::<Result<Vec<String>, Error>>()?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "syntax-rules requires a list of literals",
+                ))
+            }
+        };
+
+        let mut rules = Vec::new();
+        for rule in &rest[1..] {
+            match rule {
+                Expression::List(rule)
+                    if rule.len() == 2 && matches!(rule[0], Expression::List(_)) =>
+                {
+                    rules.push((rule[0].clone(), rule[1].clone()));
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid rule in syntax-rules",
+                    ))
+                }
+            }
+        }
+
+        Ok(SyntaxRules {
+            ellipsis,
+            literals,
+            rules,
+        })
+    }
+
+    // Rewrite a syntax template, naming the pattern variables it uses and
+    // the number of ellipses they were matched under for the evaluator
+    fn expand_template(&self, template: &Expression, scope: &Rc<Scope>) -> Vec<Expression> {
+        let mut vars = Vec::new();
+        let template = self.rename_pattern_vars(template, scope, &mut vars);
+
+        let vars = vars
+            .into_iter()
+            .map(|(name, depth)| {
+                Expression::List(
+                    vec![
+                        Expression::Symbol(name.into()),
+                        Expression::Number(depth as f64),
+                    ]
+                    .into(),
+                )
+            })
+            .collect();
+
+        vec![template, Expression::List(vars)]
+    }
+
+    fn rename_pattern_vars(
+        &self,
+        template: &Expression,
+        scope: &Rc<Scope>,
+        vars: &mut Vec<(String, usize)>,
+    ) -> Expression {
+        match template {
+            Expression::Symbol(s) => match self.resolve(s, scope) {
+                Denotation::PatternVariable(name, depth) => {
+                    if !vars.iter().any(|(var, _)| *var == name) {
+                        vars.push((name.clone(), depth));
+                    }
+                    Expression::Symbol(name.into())
+                }
+                _ => template.clone(),
+            },
+            Expression::List(items) => Expression::List(
+                items
+                    .iter()
+                    .map(|item| self.rename_pattern_vars(item, scope, vars))
+                    .collect(),
+            ),
+            _ => template.clone(),
+        }
+    }
+
+    // Expand `(syntax-case expr (literal ...) (pattern [fender] output) ...)`.
+    // The pattern variables of each clause are in scope in its fender and output.
+    fn expand_syntax_case(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<Vec<Expression>, Error> {
+        let literals = match list.get(2) {
+            Some(Expression::List(literals)) => literals
+                .iter()
+                .map(|literal| match literal {
+                    Expression::Symbol(s) => Ok(s.to_string()),
+                    _ => Err(Error::new(
+                        ErrorKind::Parse,
+                        "Invalid literal in syntax-case",
+                    )),
+                })
+                .collect::<Result<Vec<String>, Error>>()?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'syntax-case' requires an expression and a list of literals",
+                ))
+            }
+        };
+
+        let mut expanded = vec![self.expand_expr(&list[1], scope)?, list[2].clone()];
+
+        for clause in &list[3..] {
+            let clause = match clause {
+                Expression::List(clause) if clause.len() == 2 || clause.len() == 3 => clause,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid clause in syntax-case expression",
+                    ))
+                }
+            };
+
+            let clause_scope = self.bind_pattern(&clause[0], &literals, scope);
+            let mut expanded_clause = vec![clause[0].clone()];
+            for expr in &clause[1..] {
+                expanded_clause.push(self.expand_expr(expr, &clause_scope)?);
+            }
+            expanded.push(Expression::List(expanded_clause.into()));
+        }
+
+        Ok(expanded)
+    }
+
+    fn bind_pattern(
+        &self,
+        pattern: &Expression,
+        literals: &[String],
+        scope: &Rc<Scope>,
+    ) -> Rc<Scope> {
+        let pattern_scope = Scope::new(Some(scope.clone()));
+
+        let mut vars = Vec::new();
+        pattern_var_depths(pattern, literals, 0, &mut vars);
+        for (name, depth) in vars {
+            pattern_scope.bind(&name, Binding::PatternVariable(depth));
+        }
+
+        pattern_scope
+    }
+
+    // `(with-syntax ((pattern expr) ...) body ...)` matches each expression
+    // against its pattern, and becomes nested `syntax-case` forms
+    fn expand_with_syntax(
+        &mut self,
+        list: &[Expression],
+        scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        let bindings = match list.get(1) {
+            Some(Expression::List(bindings)) if list.len() > 2 => bindings,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Syntax,
+                    "'with-syntax' requires a list of bindings and a body",
+                ))
+            }
+        };
+
+        let mut clauses = Vec::new();
+        let mut body_scope = scope.clone();
+
+        for binding in bindings {
+            match binding {
+                Expression::List(binding) if binding.len() == 2 => {
+                    let expr = self.expand_expr(&binding[1], scope)?;
+                    body_scope = self.bind_pattern(&binding[0], &[], &body_scope);
+                    clauses.push((binding[0].clone(), expr));
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Invalid binding in with-syntax expression",
+                    ))
+                }
+            }
+        }
+
+        let mut body = vec![
+            Expression::Symbol("let".into()),
+            Expression::List(List::new()),
+        ];
+        body.extend(self.expand_body(&list[2..], &body_scope)?);
+
+        Ok(clauses.into_iter().rev().fold(
+            Expression::List(body.into()),
+            |body, (pattern, expr)| {
+                Expression::List(
+                    vec![
+                        Expression::Symbol("syntax-case".into()),
+                        expr,
+                        Expression::List(List::new()),
+                        Expression::List(vec![pattern, body].into()),
+                    ]
+                    .into(),
+                )
+            },
+        ))
+    }
+
+    // Expand the unquoted parts of a quasiquote template at the given level of
+    // nesting. Everything else is data.
+    fn expand_quasiquote(
+        &mut self,
+        template: &Expression,
+        depth: usize,
+        scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        let items = match template {
+            Expression::List(items) => items,
+            _ => return Ok(strip_syntax(template)),
+        };
+
+        let form = match items.as_slice() {
+            [Expression::Symbol(head), _] => match self.resolve(head, scope) {
+                Denotation::Core(form) => Some(form),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match form.as_deref() {
+            Some(unquote @ ("unquote" | "unquote-splicing")) if depth == 1 => Ok(Expression::List(
+                vec![
+                    Expression::Symbol(unquote.into()),
+                    self.expand_expr(&items[1], scope)?,
+                ]
+                .into(),
+            )),
+            Some(nested @ ("unquote" | "unquote-splicing" | "quasiquote")) => {
+                let depth = if nested == "quasiquote" {
+                    depth + 1
+                } else {
+                    depth - 1
+                };
+                Ok(Expression::List(
+                    vec![
+                        Expression::Symbol(nested.into()),
+                        self.expand_quasiquote(&items[1], depth, scope)?,
+                    ]
+                    .into(),
+                ))
+            }
+            _ => {
+                let expanded: Result<Vec<Expression>, Error> = items
+                    .iter()
+                    .map(|item| grow_stack(|| self.expand_quasiquote(item, depth, scope)))
+                    .collect();
+                Ok(Expression::List(expanded?.into()))
+            }
+        }
+    }
+
+    // A fresh mark for the identifiers introduced by one macro expansion
+    fn new_mark(&mut self, def_scope: &Rc<Scope>) -> usize {
+        let mark = NEXT_MARK.fetch_add(1, Ordering::Relaxed);
+        if !Rc::ptr_eq(def_scope, &self.root) {
+            self.marks.borrow_mut().insert(mark, def_scope.clone());
+        }
+        mark
+    }
+
+    fn apply_macro(
+        &mut self,
+        mac: &Macro,
+        form: &[Expression],
+        def_scope: &Rc<Scope>,
+        use_scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        // Macros can loop too, so each use counts against the budget, and so
+        // does the code it makes
+        budget::step(|| 0)?;
+
+        let expansion = match mac {
+            Macro::SyntaxRules(rules) => self.transcribe(rules, form, def_scope, use_scope),
+            Macro::Procedure(transformer) => {
+                let mark = self.new_mark(def_scope);
+                let input = toggle_mark(&Expression::List(form.to_vec().into()), mark);
+                let context = Transforming {
+                    env: self.env.clone(),
+                    root: self.root.clone(),
+                    marks: self.marks.clone(),
+                    mark,
+                    use_scope: use_scope.clone(),
+                };
+                TRANSFORMING.with(|transforming| transforming.borrow_mut().push(context));
+                let output = apply_transformer(transformer, vec![input]);
+                TRANSFORMING.with(|transforming| transforming.borrow_mut().pop());
+                Ok(toggle_mark(&output?, mark))
+            }
+            // No renaming happens here, so the expansion can capture names
+            Macro::DefineMacro(transformer) => apply_transformer(transformer, form[1..].to_vec()),
+        }?;
+
+        budget::allocate(size(&expansion));
+        Ok(expansion)
+    }
+
+    fn transcribe(
+        &mut self,
+        rules: &SyntaxRules,
+        form: &[Expression],
+        def_scope: &Rc<Scope>,
+        use_scope: &Rc<Scope>,
+    ) -> Result<Expression, Error> {
+        for (pattern, template) in &rules.rules {
+            let pattern = match pattern {
+                Expression::List(pattern) if !pattern.is_empty() => pattern,
+                _ => continue,
+            };
+
+            // The keyword position of the pattern is ignored
+            let mut bindings = Bindings::new();
+            let same_binding = |f: &str, p: &str| self.same_binding(f, use_scope, p, def_scope);
+            let matcher = Matcher {
+                ellipsis: &rules.ellipsis,
+                literals: &rules.literals,
+                same_binding: &same_binding,
+            };
+
+            if matcher.match_list(&pattern[1..], &form[1..], &mut bindings) {
+                let transcriber = Transcriber {
+                    ellipsis: &rules.ellipsis,
+                    mark: Some(self.new_mark(def_scope)),
+                };
+                return transcriber.instantiate(template, &bindings, true);
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::Syntax,
+            format!(
+                "No matching syntax rule for {}",
+                strip_syntax(&Expression::List(form.to_vec().into()))
+            ),
+        ))
+    }
+}
+
+// Matches forms against the patterns of `syntax-rules` and `syntax-case`.
+// Literals are compared with `same_binding`, which is given an identifier of
+// the form and the literal of the pattern.
+struct Matcher<'a> {
+    ellipsis: &'a str,
+    literals: &'a [String],
+    same_binding: &'a dyn Fn(&str, &str) -> bool,
+}
+
+impl Matcher<'_> {
+    fn is_ellipsis(&self, expr: &Expression) -> bool {
+        is_symbol(expr, self.ellipsis)
+    }
+
+    fn match_pattern(
+        &self,
+        pattern: &Expression,
+        form: &Expression,
+        bindings: &mut Bindings,
+    ) -> bool {
+        match pattern {
+            Expression::Symbol(p) if p == "_" => true,
+            Expression::Symbol(p) if self.literals.iter().any(|literal| **p == **literal) => {
+                match form {
+                    Expression::Symbol(f) => (self.same_binding)(f, p),
+                    _ => false,
+                }
+            }
+            Expression::Symbol(p) => {
+                bindings.insert(p.to_string(), MatchTree::One(form.clone()));
+                true
+            }
+            Expression::List(patterns) => match form {
+                Expression::List(forms) => self.match_list(patterns, forms, bindings),
+                _ => false,
+            },
+            _ => pattern == form,
+        }
+    }
+
+    fn match_list(
+        &self,
+        patterns: &[Expression],
+        forms: &[Expression],
+        bindings: &mut Bindings,
+    ) -> bool {
+        // A dotted pattern `(p ... . tail)` matches the rest of the list with its tail
+        let (patterns, tail) = match patterns.iter().position(|p| is_symbol(p, ".")) {
+            Some(i) if i + 2 == patterns.len() => (&patterns[..i], Some(&patterns[i + 1])),
+            Some(_) => return false,
+            None => (patterns, None),
+        };
+
+        let (fixed, rest) = match patterns.iter().position(|p| self.is_ellipsis(p)) {
+            Some(0) => return false,
+            Some(i) => {
+                let before = &patterns[..i - 1];
+                let repeated = &patterns[i - 1];
+                let after = &patterns[i + 1..];
+
+                if forms.len() < before.len() + after.len() {
+                    return false;
+                }
+
+                let count = forms.len() - before.len() - after.len();
+                let middle = &forms[before.len()..before.len() + count];
+
+                let mut matches = Vec::new();
+                for form in middle {
+                    let mut item_bindings = Bindings::new();
+                    if !self.match_pattern(repeated, form, &mut item_bindings) {
+                        return false;
+                    }
+                    matches.push(item_bindings);
+                }
+
+                for var in self.pattern_vars(repeated) {
+                    let items = matches.iter_mut().filter_map(|m| m.remove(&var)).collect();
+                    bindings.insert(var, MatchTree::Many(items));
+                }
+
+                if !self.match_each(after, &forms[before.len() + count..], bindings) {
+                    return false;
+                }
+
+                (before, &forms[forms.len()..])
+            }
+            None => {
+                if forms.len() < patterns.len() || (tail.is_none() && forms.len() != patterns.len())
+                {
+                    return false;
+                }
+                (patterns, &forms[patterns.len()..])
+            }
+        };
+
+        if !self.match_each(fixed, forms, bindings) {
+            return false;
+        }
+
+        match tail {
+            Some(tail) => {
+                self.match_pattern(tail, &Expression::List(rest.to_vec().into()), bindings)
+            }
+            None => true,
+        }
+    }
+
+    fn match_each(
+        &self,
+        patterns: &[Expression],
+        forms: &[Expression],
+        bindings: &mut Bindings,
+    ) -> bool {
+        patterns
+            .iter()
+            .zip(forms)
+            .all(|(pattern, form)| self.match_pattern(pattern, form, bindings))
+    }
+
+    fn pattern_vars(&self, pattern: &Expression) -> Vec<String> {
+        match pattern {
+            Expression::Symbol(s)
+                if s != "_"
+                    && s != "."
+                    && !self.is_ellipsis(pattern)
+                    && !self.literals.iter().any(|literal| **s == **literal) =>
+            {
+                vec![s.to_string()]
+            }
+            Expression::List(items) => items
+                .iter()
+                .flat_map(|item| self.pattern_vars(item))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+// Instantiates templates. Identifiers that are not pattern variables get the
+// mark of the expansion, if there is one.
+struct Transcriber<'a> {
+    ellipsis: &'a str,
+    mark: Option<usize>,
+}
+
+impl Transcriber<'_> {
+    fn instantiate(
+        &self,
+        template: &Expression,
+        bindings: &Bindings,
+        ellipsis_enabled: bool,
+    ) -> Result<Expression, Error> {
+        match template {
+            Expression::Symbol(s) => match bindings.get(s.as_str()) {
+                Some(MatchTree::One(expr)) => Ok(expr.clone()),
+                Some(MatchTree::Many(_)) => Err(Error::new(
+                    ErrorKind::Syntax,
+                    format!("Pattern variable used without an ellipsis: {}", s),
+                )),
+                None if s == "." || is_keyword(s) => Ok(template.clone()),
+                None => match self.mark {
+                    Some(mark) => Ok(Expression::Symbol(
+                        format!("{}{}{}", s, MARK_SEPARATOR, mark).into(),
+                    )),
+                    None => Ok(template.clone()),
+                },
+            },
+            Expression::List(items) => {
+                // `(... template)` escapes the ellipsis inside the template
+                if ellipsis_enabled && items.len() == 2 && is_symbol(&items[0], self.ellipsis) {
+                    return self.instantiate(&items[1], bindings, false);
+                }
+
+                let mut expanded = Vec::new();
+                let mut i = 0;
+
+                while i < items.len() {
+                    let item = &items[i];
+
+                    let mut depth = 0;
+                    while ellipsis_enabled
+                        && items
+                            .get(i + 1 + depth)
+                            .is_some_and(|next| is_symbol(next, self.ellipsis))
+                    {
+                        depth += 1;
+                    }
+
+                    if depth > 0 {
+                        expanded.extend(self.instantiate_repeated(item, bindings, depth)?);
+                    } else if is_symbol(item, ".") && i + 2 == items.len() {
+                        // Splice a dotted tail that turned out to be a list
+                        match self.instantiate(&items[i + 1], bindings, ellipsis_enabled)? {
+                            Expression::List(tail) => expanded.extend(tail),
+                            tail => {
+                                expanded.push(item.clone());
+                                expanded.push(tail);
+                            }
+                        }
+                        break;
+                    } else {
+                        expanded.push(self.instantiate(item, bindings, ellipsis_enabled)?);
+                    }
+
+                    i += 1 + depth;
+                }
+
+                Ok(Expression::List(expanded.into()))
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    // Instantiate a template followed by `depth` ellipses once for each match
+    // of the pattern variables it repeats over
+    fn instantiate_repeated(
+        &self,
+        template: &Expression,
+        bindings: &Bindings,
+        depth: usize,
+    ) -> Result<Vec<Expression>, Error> {
+        let mut template_depths = HashMap::new();
+        self.template_depths(template, 0, &mut template_depths);
+
+        // A variable is iterated here when it was matched under more ellipses
+        // than it is used with inside this template
+        let vars: Vec<&String> = template_depths
+            .iter()
+            .filter(|(var, template_depth)| {
+                bindings
+                    .get(*var)
+                    .is_some_and(|tree| tree.depth() > **template_depth)
+            })
+            .map(|(var, _)| var)
+            .collect();
+
+        if vars.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                "No pattern variable to repeat before ellipsis in template",
+            ));
+        }
+
+        let mut count = None;
+        for var in &vars {
+            if let Some(MatchTree::Many(items)) = bindings.get(*var) {
+                if count.is_some_and(|count| count != items.len()) {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "Pattern variables under the same ellipsis matched different lengths",
+                    ));
+                }
+                count = Some(items.len());
+            }
+        }
+
+        let mut results = Vec::new();
+
+        for i in 0..count.unwrap_or(0) {
+            let mut item_bindings = bindings.clone();
+            for var in &vars {
+                if let Some(MatchTree::Many(items)) = bindings.get(*var) {
+                    item_bindings.insert((*var).clone(), items[i].clone());
+                }
+            }
+
+            if depth > 1 {
+                results.extend(self.instantiate_repeated(template, &item_bindings, depth - 1)?);
+            } else {
+                results.push(self.instantiate(template, &item_bindings, true)?);
+            }
+        }
+
+        Ok(results)
+    }
+
+    // Record how many ellipses each symbol of a template is nested under
+    fn template_depths(
+        &self,
+        template: &Expression,
+        depth: usize,
+        depths: &mut HashMap<String, usize>,
+    ) {
+        match template {
+            Expression::Symbol(s) => {
+                depths.entry(s.to_string()).or_insert(depth);
+            }
+            Expression::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let mut ellipses = 0;
+                    while items
+                        .get(i + 1 + ellipses)
+                        .is_some_and(|next| is_symbol(next, self.ellipsis))
+                    {
+                        ellipses += 1;
+                    }
+                    self.template_depths(item, depth + ellipses, depths);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Expand all macro uses in a top-level form, leaving only core forms for the
+// evaluator. Syntax definitions at the top level are stored in `env`.
+pub fn expand(expr: &Expression, env: &mut Environment) -> Result<Expression, Error> {
+    let mut expander = Expander {
+        env,
+        root: Scope::new(None),
+        marks: Rc::default(),
+    };
+
+    expander.expand_toplevel(expr)
+}
+
+// Match a syntax object against a `syntax-case` pattern, returning the values
+// of the pattern variables. Variables under ellipses are bound to lists.
+pub(crate) fn match_syntax(
+    pattern: &Expression,
+    form: &Expression,
+    literals: &[String],
+) -> Option<Vec<(String, Expression)>> {
+    let matcher = Matcher {
+        ellipsis: "...",
+        literals,
+        same_binding: &free_identifier_eq,
+    };
+
+    let mut bindings = Bindings::new();
+    if !matcher.match_pattern(pattern, form, &mut bindings) {
+        return None;
+    }
+
+    Some(
+        bindings
+            .into_iter()
+            .map(|(name, tree)| (name, tree.into_value()))
+            .collect(),
+    )
+}
+
+// Instantiate a `syntax` template with the values of its pattern variables,
+// each given with the number of ellipses it was matched under
+pub(crate) fn instantiate_syntax(
+    template: &Expression,
+    vars: Vec<(String, usize, Expression)>,
+) -> Result<Expression, Error> {
+    let mut bindings = Bindings::new();
+    for (name, depth, value) in vars {
+        let tree = MatchTree::from_value(value, depth).ok_or_else(|| {
+            Error::new(
+                ErrorKind::Syntax,
+                format!("Pattern variable does not hold a list: {}", name),
+            )
+        })?;
+        bindings.insert(name, tree);
+    }
+
+    let transcriber = Transcriber {
+        ellipsis: "...",
+        mark: None,
+    };
+    transcriber.instantiate(template, &bindings, true)
+}
+
+// Expand a macro use at the head of a form for inspection, either once or
+// until the head is no longer a macro. Subforms are left alone.
+pub(crate) fn macroexpand(
+    expr: &Expression,
+    env: &mut Environment,
+    once: bool,
+) -> Result<Expression, Error> {
+    let mut expander = Expander {
+        env,
+        root: Scope::new(None),
+        marks: Rc::default(),
+    };
+    let root = expander.root.clone();
+
+    let expanded = if once {
+        match expr {
+            Expression::List(list) => match list.first() {
+                Some(Expression::Symbol(head)) => match expander.resolve(head, &root) {
+                    Denotation::Macro(mac, def_scope) => {
+                        expander.apply_macro(&mac, list, &def_scope, &root)?
+                    }
+                    _ => expr.clone(),
+                },
+                _ => expr.clone(),
+            },
+            _ => expr.clone(),
+        }
+    } else {
+        expander.expand_head(expr, &root)?
+    };
+
+    Ok(strip_syntax(&expanded))
+}
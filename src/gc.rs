@@ -0,0 +1,266 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::budget;
+use crate::eval::grow_stack;
+use crate::parser::{Expression, Macro};
+
+// Scheme values are reference counted, which frees them as soon as the last
+// reference goes away, except when they refer to each other in a cycle. A
+// procedure bound in the scope it closes over is the usual example. The
+// collector finds such cycles by counting how many of the references to each
+// object come from other objects. Objects with more references than that are
+// reachable from outside, from Rust code or the evaluator, and so is
+// everything they refer to. The rest is only reachable from itself, and
+// emptying the scopes among it breaks its cycles so that reference counting
+// frees it.
+//
+// References that are not traced only make the collector keep more, so
+// immutable data such as the bodies of procedures is not looked into.
+
+// The number of objects made between two collections at first. After a
+// collection, the next one comes when the number of objects has doubled.
+const FIRST_COLLECTION: usize = 10_000;
+
+// A heap object that can hold references to others
+pub(crate) trait Traced {
+    // Pass every object this one refers to to the tracer
+    fn trace(&self, tracer: &mut Tracer);
+
+    // Take out the contents of a mutable object to break the cycles through
+    // it. The contents are dropped once the collection is over.
+    fn clear(&self) -> Option<Box<dyn Any>> {
+        None
+    }
+
+    // The number of bytes the object takes up
+    fn size(&self) -> usize;
+}
+
+struct Heap {
+    // Every scope that has been made, including dead ones until the next
+    // collection removes them
+    objects: Vec<Weak<dyn Traced>>,
+    next_collection: usize,
+    collections: usize,
+    collected: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        objects: Vec::new(),
+        next_collection: FIRST_COLLECTION,
+        collections: 0,
+        collected: 0,
+    });
+}
+
+// Keep track of a new scope, collecting garbage if enough have been made
+// since the last collection
+pub(crate) fn register<T: Traced + 'static>(object: &Rc<T>) {
+    budget::allocate(1);
+    let due = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(Rc::downgrade(object) as Weak<dyn Traced>);
+        heap.objects.len() >= heap.next_collection
+    });
+
+    if due {
+        collect();
+    }
+}
+
+// What the collector knows about one object
+struct Node {
+    // The references to the object, apart from any the collector holds
+    references: usize,
+    // The references to it from traced objects
+    internal: usize,
+    children: Vec<usize>,
+    object: Weak<dyn Traced>,
+}
+
+// Builds the graph of the objects reachable from the registered ones
+pub(crate) struct Tracer {
+    nodes: HashMap<usize, Node>,
+    // The references of the object being traced
+    children: Vec<usize>,
+}
+
+fn address<T: ?Sized>(object: *const T) -> usize {
+    object as *const () as usize
+}
+
+impl Tracer {
+    // A reference to another object
+    pub(crate) fn edge<T: Traced + 'static>(&mut self, object: &Rc<T>) {
+        let id = address(Rc::as_ptr(object));
+        self.children.push(id);
+
+        if !self.nodes.contains_key(&id) {
+            let handle = Rc::downgrade(object) as Weak<dyn Traced>;
+            self.visit(id, &**object, handle, Rc::strong_count(object));
+        }
+    }
+
+    fn visit(
+        &mut self,
+        id: usize,
+        object: &dyn Traced,
+        handle: Weak<dyn Traced>,
+        references: usize,
+    ) {
+        // Record the object before tracing it, so cycles end here
+        self.nodes.insert(
+            id,
+            Node {
+                references,
+                internal: 0,
+                children: Vec::new(),
+                object: handle,
+            },
+        );
+
+        let outer = mem::take(&mut self.children);
+        grow_stack(|| object.trace(self));
+        let children = mem::replace(&mut self.children, outer);
+
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.children = children;
+        }
+    }
+
+    pub(crate) fn expr(&mut self, expr: &Expression) {
+        match expr {
+            Expression::List(list) => list.trace(self),
+            Expression::Function(proc) => proc.env.trace(self),
+            Expression::CaseLambda(procs) => procs.iter().for_each(|proc| proc.env.trace(self)),
+            Expression::Closure(closure) => closure.trace(self),
+            Expression::Continuation(continuation) => continuation.trace(self),
+            Expression::Macro(Macro::Procedure(transformer))
+            | Expression::Macro(Macro::DefineMacro(transformer)) => self.expr(transformer),
+            Expression::ErrorObject(error) => error
+                .irritants
+                .iter()
+                .for_each(|irritant| self.expr(irritant)),
+            _ => {}
+        }
+    }
+}
+
+// Whether a value can refer to a scope, so that the collector has to look
+// into it
+pub(crate) fn is_traced(value: &Expression) -> bool {
+    match value {
+        Expression::List(list) => list.is_traced(),
+        Expression::Function(_)
+        | Expression::CaseLambda(_)
+        | Expression::Closure(_)
+        | Expression::Continuation(_)
+        | Expression::Macro(Macro::Procedure(_))
+        | Expression::Macro(Macro::DefineMacro(_)) => true,
+        Expression::ErrorObject(error) => !error.irritants.is_empty(),
+        _ => false,
+    }
+}
+
+// Free the objects that are only reachable from cycles. Returns how many
+// scopes were emptied.
+pub fn collect() -> usize {
+    let objects = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects.clone()
+    });
+
+    let mut tracer = Tracer {
+        nodes: HashMap::new(),
+        children: Vec::new(),
+    };
+    for object in &objects {
+        let id = address(object.as_ptr());
+        if tracer.nodes.contains_key(&id) {
+            continue;
+        }
+        if let Some(object) = object.upgrade() {
+            // Not counting the reference that was just made
+            let references = Rc::strong_count(&object) - 1;
+            tracer.visit(id, &*object, Rc::downgrade(&object), references);
+        }
+    }
+
+    let mut nodes = tracer.nodes;
+    let edges: Vec<usize> = nodes
+        .values()
+        .flat_map(|node| node.children.iter().copied())
+        .collect();
+    for child in edges {
+        if let Some(node) = nodes.get_mut(&child) {
+            node.internal += 1;
+        }
+    }
+
+    // Everything referred to from outside the traced objects is alive, and
+    // so is everything it refers to
+    let mut alive: Vec<usize> = nodes
+        .iter()
+        .filter(|(_, node)| node.references > node.internal)
+        .map(|(id, _)| *id)
+        .collect();
+    let mut reached: HashSet<usize> = alive.iter().copied().collect();
+    while let Some(id) = alive.pop() {
+        for child in &nodes[&id].children {
+            if reached.insert(*child) {
+                alive.push(*child);
+            }
+        }
+    }
+
+    let mut garbage = Vec::new();
+    for (id, node) in nodes.drain() {
+        if !reached.contains(&id) {
+            if let Some(contents) = node.object.upgrade().and_then(|object| object.clear()) {
+                garbage.push(contents);
+            }
+        }
+    }
+    let collected = garbage.len();
+    drop(garbage);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.next_collection = (heap.objects.len() * 2).max(FIRST_COLLECTION);
+        heap.collections += 1;
+        heap.collected += collected;
+    });
+
+    collected
+}
+
+// The state of the heap, as reported by `gc-stats`
+pub struct Stats {
+    // The scopes that are alive
+    pub objects: usize,
+    pub bytes: usize,
+    pub collections: usize,
+    // The scopes emptied by all collections so far
+    pub collected: usize,
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let live: Vec<Rc<dyn Traced>> = heap.objects.iter().filter_map(Weak::upgrade).collect();
+
+        Stats {
+            objects: live.len(),
+            bytes: live.iter().map(|object| object.size()).sum(),
+            collections: heap.collections,
+            collected: heap.collected,
+        }
+    })
+}
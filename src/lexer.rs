@@ -1,11 +1,20 @@
 use std::fmt;
 
+use crate::error::{new_source, Error, ErrorKind, Span};
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     OpenParen,
     CloseParen,
+    Bool(bool),
     Number(f64),
     Symbol(String),
+    String(String),
+    Quote,
+    Syntax,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 }
 
 impl fmt::Display for Token {
@@ -13,37 +22,166 @@ impl fmt::Display for Token {
         match self {
             Token::OpenParen => write!(f, "("),
             Token::CloseParen => write!(f, ")"),
+            Token::Bool(b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
             Token::Number(n) => write!(f, "{}", n),
             Token::Symbol(s) => write!(f, "{}", s),
+            Token::String(s) => write!(f, "{:?}", s),
+            Token::Quote => write!(f, "'"),
+            Token::Syntax => write!(f, "#'"),
+            Token::Quasiquote => write!(f, "`"),
+            Token::Unquote => write!(f, ","),
+            Token::UnquoteSplicing => write!(f, ",@"),
         }
     }
 }
 
-pub fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
-    let expr = expr.replace("(", " ( ").replace(")", " ) ");
+// The tokens alone, without their spans
+#[cfg(test)]
+fn tokenize(expr: &str) -> Result<Vec<Token>, Error> {
+    Ok(tokenize_with_spans(expr)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect())
+}
+
+// Split the source into tokens, each with the region of the source it came from
+pub fn tokenize_with_spans(expr: &str) -> Result<Vec<(Token, Span)>, Error> {
+    let mut tokens: Vec<(Token, Span)> = Vec::new();
+    let source = new_source();
+
+    let mut chars = expr.char_indices().peekable();
+    let mut line = 1;
+    let mut line_start = 0;
+    // Where the last token started and its column, so that the column of the
+    // next one only needs the characters in between counted
+    let mut last = (0, 1);
+
+    while let Some(&(start, c)) = chars.peek() {
+        let mut span = |start: usize, end: usize| {
+            if last.0 < line_start {
+                last = (line_start, 1);
+            }
+            let column = last.1 + expr[last.0..start].chars().count();
+            last = (start, column);
+            Span {
+                source,
+                start,
+                end,
+                line,
+                column,
+            }
+        };
+
+        if c.is_whitespace() {
+            chars.next();
+            if c == '\n' {
+                line += 1;
+                line_start = start + 1;
+            }
+            continue;
+        }
+
+        if c == '(' || c == ')' {
+            chars.next();
+            let token = if c == '(' {
+                Token::OpenParen
+            } else {
+                Token::CloseParen
+            };
+            tokens.push((token, span(start, start + 1)));
+            continue;
+        }
 
-    let words = expr.split_whitespace();
+        if c == '"' {
+            let token = read_string(&mut chars).ok_or_else(|| {
+                Error::new(ErrorKind::Parse, "Unterminated string")
+                    .with_span(span(start, expr.len()))
+            })?;
+            let end = chars.peek().map_or(expr.len(), |&(i, _)| i);
+            tokens.push((token, span(start, end)));
 
-    let mut tokens: Vec<Token> = Vec::new();
+            // Strings may span lines
+            if let Some(newline) = expr[start..end].rfind('\n') {
+                line += expr[start..end].matches('\n').count();
+                line_start = start + newline + 1;
+            }
+            continue;
+        }
 
-    for word in words {
-        match word {
-            "(" => tokens.push(Token::OpenParen),
-            ")" => tokens.push(Token::CloseParen),
+        let mut end = start;
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                break;
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+
+        // `'datum` and `#'template` abbreviate (quote datum) and (syntax template),
+        // and `` ` ``, `,` and `,@` the parts of a quasiquote
+        let mut word = &expr[start..end];
+        let mut word_start = start;
+        loop {
+            let (token, rest) = if let Some(rest) = word.strip_prefix("#'") {
+                (Token::Syntax, rest)
+            } else if let Some(rest) = word.strip_prefix('\'') {
+                (Token::Quote, rest)
+            } else if let Some(rest) = word.strip_prefix('`') {
+                (Token::Quasiquote, rest)
+            } else if let Some(rest) = word.strip_prefix(",@") {
+                (Token::UnquoteSplicing, rest)
+            } else if let Some(rest) = word.strip_prefix(',') {
+                (Token::Unquote, rest)
+            } else {
+                break;
+            };
+            let prefix_end = end - rest.len();
+            tokens.push((token, span(word_start, prefix_end)));
+            word = rest;
+            word_start = prefix_end;
+        }
+
+        let token = match word {
+            "" => continue,
+            "#t" | "#true" => Token::Bool(true),
+            "#f" | "#false" => Token::Bool(false),
             _ => {
-                let i = word.parse::<f64>();
-                if i.is_ok() {
-                    tokens.push(Token::Number(i.unwrap()));
+                if let Ok(n) = word.parse::<f64>() {
+                    Token::Number(n)
                 } else {
-                    tokens.push(Token::Symbol(word.to_string()));
+                    Token::Symbol(word.to_string())
                 }
             }
-        }
+        };
+        tokens.push((token, span(word_start, end)));
     }
 
     Ok(tokens)
 }
 
+// Read a string literal, from its opening quote to its closing one. Returns
+// None if the input ends first.
+fn read_string(chars: &mut std::iter::Peekable<std::str::CharIndices>) -> Option<Token> {
+    let mut string = String::new();
+    chars.next();
+
+    loop {
+        let (_, c) = chars.next()?;
+        match c {
+            '"' => return Some(Token::String(string)),
+            '\\' => {
+                let (_, escaped) = chars.next()?;
+                string.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    c => c,
+                });
+            }
+            c => string.push(c),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,4 +237,102 @@ mod tests {
 
         assert_eq!(actual_tokens, expected_tokens);
     }
+
+    #[test]
+    fn test_tokenize_quotes() {
+        let input = "('a #'(b c))";
+
+        let actual_tokens = tokenize(input).unwrap_or(vec![]);
+
+        let expected_tokens = vec![
+            Token::OpenParen,
+            Token::Quote,
+            Token::Symbol("a".to_string()),
+            Token::Syntax,
+            Token::OpenParen,
+            Token::Symbol("b".to_string()),
+            Token::Symbol("c".to_string()),
+            Token::CloseParen,
+            Token::CloseParen,
+        ];
+
+        assert_eq!(actual_tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_quasiquote() {
+        let input = "`(a ,b ,@c)";
+
+        let actual_tokens = tokenize(input).unwrap_or(vec![]);
+
+        let expected_tokens = vec![
+            Token::Quasiquote,
+            Token::OpenParen,
+            Token::Symbol("a".to_string()),
+            Token::Unquote,
+            Token::Symbol("b".to_string()),
+            Token::UnquoteSplicing,
+            Token::Symbol("c".to_string()),
+            Token::CloseParen,
+        ];
+
+        assert_eq!(actual_tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_booleans() {
+        let input = "(if #t #false #f)";
+
+        let actual_tokens = tokenize(input).unwrap_or(vec![]);
+
+        let expected_tokens = vec![
+            Token::OpenParen,
+            Token::Symbol("if".to_string()),
+            Token::Bool(true),
+            Token::Bool(false),
+            Token::Bool(false),
+            Token::CloseParen,
+        ];
+
+        assert_eq!(actual_tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_strings() {
+        let input = "(display \"a \\\"b\\\"\\n\")";
+
+        let actual_tokens = tokenize(input).unwrap_or(vec![]);
+
+        let expected_tokens = vec![
+            Token::OpenParen,
+            Token::Symbol("display".to_string()),
+            Token::String("a \"b\"\n".to_string()),
+            Token::CloseParen,
+        ];
+
+        assert_eq!(actual_tokens, expected_tokens);
+
+        assert!(tokenize("(display \"oops)").is_err());
+    }
+
+    #[test]
+    fn test_tokenize_spans() {
+        let input = "(car\n  'xs)";
+
+        let actual_spans: Vec<(usize, usize, usize, usize)> = tokenize_with_spans(input)
+            .unwrap_or(vec![])
+            .into_iter()
+            .map(|(_, span)| (span.start, span.end, span.line, span.column))
+            .collect();
+
+        let expected_spans = vec![
+            (0, 1, 1, 1),
+            (1, 4, 1, 2),
+            (7, 8, 2, 3),
+            (8, 10, 2, 4),
+            (10, 11, 2, 6),
+        ];
+
+        assert_eq!(actual_spans, expected_spans);
+    }
 }
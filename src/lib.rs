@@ -1,37 +1,111 @@
 use std::io;
+use std::num::NonZeroUsize;
+use std::sync::atomic::Ordering;
 
+pub mod budget;
+mod compiler;
 pub mod env;
+pub mod error;
 pub mod eval;
+mod expander;
+pub mod gc;
 mod lexer;
 mod operator_utils;
 pub mod parser;
+mod resolver;
+pub mod symbol;
+pub mod vm;
 
+use crate::budget::interrupt_flag;
 use crate::env::standard_env;
-use crate::eval::eval;
+use crate::error::{next_source, Error};
+use crate::eval::{eval_with, Engine};
+use crate::parser::Expression;
 
 use anyhow::{Context, Result};
 
-pub fn repl() {
+// How many procedures of the call stack are shown with an error, unless the
+// full trace is asked for
+const SHORT_TRACE: usize = 5;
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplOptions {
+    // Show every procedure of the call stack with an error
+    pub full_trace: bool,
+    pub engine: Engine,
+}
+
+pub fn repl(options: ReplOptions) {
     let mut global_env = standard_env();
 
+    // Ctrl-C stops the evaluation that is running and goes back to the
+    // prompt, keeping the definitions made so far
+    let interrupt = interrupt_flag();
+    if let Err(error) = ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed)) {
+        eprintln!(
+            "==> Warning: Ctrl-C will not interrupt evaluation: {}",
+            error
+        );
+    }
+
     loop {
         println!("schemer>");
 
-        let expr = read_input().unwrap();
+        // Stop at the end of the input
+        let expr = match read_input().unwrap() {
+            Some(expr) => expr,
+            None => break,
+        };
+
+        if expr.trim().is_empty() {
+            continue;
+        }
 
-        match eval(expr.as_ref(), &mut global_env) {
+        let source = next_source();
+        match eval_with(expr.as_ref(), &mut global_env, options.engine) {
+            Ok(Expression::Void) => {}
             Ok(val) => println!(" ==> {}", val),
-            Err(error) => eprintln!("==> Error: {}", error),
+            Err(error) => report_error(&error, source, &options),
         };
     }
 }
 
-fn read_input() -> Result<String> {
+// Report an error of the input numbered `source`. An error in code read from
+// another input, such as the body of a procedure defined earlier, has no
+// position in this one and is shown by its call stack alone.
+fn report_error(error: &Error, source: NonZeroUsize, options: &ReplOptions) {
+    match error.span {
+        Some(span) if span.source == source => eprintln!(
+            "==> Error at line {}, column {}: {}",
+            span.line, span.column, error
+        ),
+        _ => eprintln!("==> Error: {}", error),
+    }
+
+    let shown = if options.full_trace {
+        error.stack.len()
+    } else {
+        error.stack.len().min(SHORT_TRACE)
+    };
+
+    for name in &error.stack[..shown] {
+        eprintln!("    in {}", name);
+    }
+
+    if shown < error.stack.len() {
+        eprintln!(
+            "    ... {} more (run with --trace to see them all)",
+            error.stack.len() - shown
+        );
+    }
+}
+
+fn read_input() -> Result<Option<String>> {
     let mut raw_input = String::new();
 
-    io::stdin()
+    let read = io::stdin()
         .read_line(&mut raw_input)
         .context("Failed to read line")?;
 
-    Ok(raw_input)
+    Ok((read > 0).then_some(raw_input))
 }
@@ -1,3 +1,19 @@
+use rustyscm::eval::Engine;
+use rustyscm::ReplOptions;
+
 fn main() {
-    rustyscm::repl();
+    let engine = if std::env::args().any(|arg| arg == "--bytecode-only") {
+        Engine::BytecodeOnly
+    } else if std::env::args().any(|arg| arg == "--bytecode") {
+        Engine::Bytecode
+    } else {
+        Engine::TreeWalker
+    };
+
+    let options = ReplOptions {
+        full_trace: std::env::args().any(|arg| arg == "--trace"),
+        engine,
+    };
+
+    rustyscm::repl(options);
 }
@@ -1,22 +1,31 @@
+use crate::error::{Error, ErrorKind};
 use crate::parser::Expression;
 
-use anyhow::{anyhow, Result};
+// A type error naming the argument that was wrong, or the missing one
+fn expected(what: &str, arg: Option<&Expression>) -> Error {
+    match arg {
+        Some(arg) => Error::new(ErrorKind::Type, format!("Expected {}:", what))
+            .with_irritants(vec![arg.clone()]),
+        None => Error::new(ErrorKind::Type, format!("Expected {}", what)),
+    }
+}
 
-pub fn power(args: &[Expression]) -> Result<Expression> {
+pub fn power(args: &[Expression]) -> Result<Expression, Error> {
     if args.len() != 2 {
-        return Err(anyhow!(
-            "Exponent operator requires two arguments: base and exponent factor"
+        return Err(Error::new(
+            ErrorKind::Arity,
+            "Exponent operator requires two arguments: base and exponent factor",
         ));
     }
 
-    let base = match args.get(0) {
+    let base = match args.first() {
         Some(Expression::Number(num)) => Ok(num),
-        _ => Err(anyhow!("Expected a number")),
+        arg => Err(expected("a number", arg)),
     }?;
 
     let n = match args.get(1) {
         Some(Expression::Number(num)) => Ok(num),
-        _ => Err(anyhow!("Expected a number")),
+        arg => Err(expected("a number", arg)),
     }?;
 
     let result = base.powf(*n);
@@ -24,76 +33,77 @@ pub fn power(args: &[Expression]) -> Result<Expression> {
     Ok(Expression::Number(result))
 }
 
-pub fn add(args: &[Expression]) -> Result<Expression> {
+pub fn add(args: &[Expression]) -> Result<Expression, Error> {
     let sum = args.iter().try_fold(0.0, |acc, arg| {
         if let Expression::Number(num) = arg {
             Ok(acc + num)
         } else {
-            Err(anyhow!("Expected a number"))
+            Err(expected("a number", Some(arg)))
         }
     })?;
 
     Ok(Expression::Number(sum))
 }
 
-pub fn subtract(args: &[Expression]) -> Result<Expression> {
-    let first = get_number(args);
+pub fn subtract(args: &[Expression]) -> Result<Expression, Error> {
+    let first = get_number(args)?;
 
     let result = args.iter().skip(1).try_fold(first, |acc, arg| {
         if let Expression::Number(num) = arg {
             Ok(acc - num)
         } else {
-            Err(anyhow!("Expected a number"))
+            Err(expected("a number", Some(arg)))
         }
     })?;
 
     Ok(Expression::Number(result))
 }
 
-pub fn multiply(args: &[Expression]) -> Result<Expression> {
+pub fn multiply(args: &[Expression]) -> Result<Expression, Error> {
     let product = args.iter().try_fold(1.0, |acc, arg| {
         if let Expression::Number(num) = arg {
             Ok(acc * num)
         } else {
-            Err(anyhow!("Expected a number"))
+            Err(expected("a number", Some(arg)))
         }
     })?;
 
     Ok(Expression::Number(product))
 }
 
-pub fn divide(args: &[Expression]) -> Result<Expression> {
-    let first = get_number(args);
+pub fn divide(args: &[Expression]) -> Result<Expression, Error> {
+    let first = get_number(args)?;
 
     let quotient = args.iter().skip(1).try_fold(first, |acc, arg| {
         if let Expression::Number(num) = arg {
             if *num == 0.0 {
-                panic!("Cannot divide by zero")
+                return Err(Error::new(ErrorKind::DivideByZero, "Cannot divide by zero"));
             }
             Ok(acc / num)
         } else {
-            Err(anyhow!("Expected a number"))
+            Err(expected("a number", Some(arg)))
         }
     })?;
 
     Ok(Expression::Number(quotient))
 }
 
-pub fn compare(args: &[Expression], op: &str) -> Result<Expression> {
+pub fn compare(args: &[Expression], op: &str) -> Result<Expression, Error> {
     if args.len() != 2 {
-        return Err(anyhow!(
-            "Comparison operators require exactly two arguments"
+        return Err(Error::new(
+            ErrorKind::Arity,
+            "Comparison operators require exactly two arguments",
         ));
     }
 
-    let a = match args.get(0) {
+    let a = match args.first() {
         Some(Expression::Number(num)) => Ok(num),
-        _ => Err(anyhow!("Expected a number")),
+        arg => Err(expected("a number", arg)),
     }?;
 
     let b = match args.get(1) {
         Some(Expression::Number(num)) => Ok(num),
-        _ => Err(anyhow!("Expected a number")),
+        arg => Err(expected("a number", arg)),
     }?;
 
     let result = match op {
@@ -102,21 +112,61 @@ pub fn compare(args: &[Expression], op: &str) -> Result<Expression> {
         "<" => a < b,
         ">=" => a >= b,
         "<=" => a <= b,
-        _ => panic!("Unknown operator"),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                format!("Unknown operator: {}", op),
+            ))
+        }
     };
 
     Ok(Expression::Bool(result))
 }
 
-fn get_number(args: &[Expression]) -> f64 {
-    args.iter()
-        .next()
-        .and_then(|arg| {
-            if let Expression::Number(num) = arg {
-                Some(*num)
-            } else {
-                None
-            }
-        })
-        .expect("Expected a number")
+pub fn cons(args: &[Expression]) -> Result<Expression, Error> {
+    match (args.first(), args.get(1)) {
+        (Some(first), Some(Expression::List(rest))) => {
+            Ok(Expression::List(rest.cons(first.clone())))
+        }
+        (_, arg) => Err(expected("a list as the second argument", arg)),
+    }
+}
+
+pub fn car(args: &[Expression]) -> Result<Expression, Error> {
+    match args.first() {
+        Some(Expression::List(list)) if !list.is_empty() => Ok(list[0].clone()),
+        arg => Err(expected("a non-empty list", arg)),
+    }
+}
+
+pub fn cdr(args: &[Expression]) -> Result<Expression, Error> {
+    match args.first() {
+        Some(Expression::List(list)) if !list.is_empty() => Ok(Expression::List(list.tail(1))),
+        arg => Err(expected("a non-empty list", arg)),
+    }
+}
+
+fn get_number(args: &[Expression]) -> Result<f64, Error> {
+    match args.first() {
+        Some(Expression::Number(num)) => Ok(*num),
+        arg => Err(expected("a number", arg)),
+    }
+}
+
+// Whether two values are the same object. Symbols are the same when they come
+// from the same table entry, which is a pointer comparison.
+pub fn eq(args: &[Expression]) -> Result<Expression, Error> {
+    let result = match (&args[0], &args[1]) {
+        (Expression::Symbol(a), Expression::Symbol(b)) => a == b,
+        (Expression::Number(a), Expression::Number(b)) => a == b,
+        (Expression::Bool(a), Expression::Bool(b)) => a == b,
+        (Expression::List(a), Expression::List(b)) => a.ptr_eq(b),
+        (Expression::Void, Expression::Void) => true,
+        // Strings are copied around, so they have no identity to compare.
+        // Procedures and error objects are compared as values.
+        (Expression::String(_), _) | (Expression::List(_), _) => false,
+        (a, b) => a == b,
+    };
+
+    Ok(Expression::Bool(result))
 }
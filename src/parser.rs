@@ -1,85 +1,607 @@
+use std::cell::{Cell, UnsafeCell};
 use std::fmt;
+use std::iter;
+use std::mem::{self, MaybeUninit};
+use std::ops::Deref;
+use std::rc::Rc;
+use std::slice;
 
 use crate::env::Environment;
-use crate::lexer::{tokenize, Token};
-
+use crate::error::{Error, ErrorKind, Span};
+use crate::eval::{grow_stack, Continuation};
+use crate::gc::{is_traced, Traced, Tracer};
+use crate::lexer::{tokenize_with_spans, Token};
+use crate::resolver::Reference;
+use crate::symbol::Symbol;
+use crate::vm::Closure;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Bool(bool),
     Number(f64),
-    Symbol(String),
-    List(Vec<Expression>),
-    Func(fn(&[Expression]) -> Expression),
+    Symbol(Symbol),
+    // A variable whose binding the evaluator knows the place of
+    Reference(Reference),
+    String(String),
+    List(List),
+    Func(Builtin),
     Function(Procedure),
+    CaseLambda(Vec<Procedure>),
+    // A procedure compiled by the bytecode engine
+    Closure(Closure),
+    Macro(Macro),
+    Control(Control),
+    Continuation(Continuation),
+    ErrorObject(Box<Error>),
+    Void,
+}
+
+// A procedure that works on the evaluator's own state rather than on its
+// arguments alone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    CallCC,
+    DynamicWind,
+    CallWithPrompt,
+    Abort,
+    Shift,
+    WithHandler,
+    Raise,
+    RaiseContinuable,
+    Error,
+}
+
+impl Control {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Control::CallCC => "call/cc",
+            Control::DynamicWind => "dynamic-wind",
+            Control::CallWithPrompt => "call-with-continuation-prompt",
+            Control::Abort => "abort-current-continuation",
+            Control::Shift => "shift",
+            Control::WithHandler => "with-exception-handler",
+            Control::Raise => "raise",
+            Control::RaiseContinuable => "raise-continuable",
+            Control::Error => "error",
+        }
+    }
+
+    pub fn arity(&self) -> Arity {
+        match self {
+            Control::CallCC => Arity::Exactly(1),
+            Control::DynamicWind => Arity::Exactly(3),
+            Control::CallWithPrompt => Arity::Between(1, 3),
+            Control::Abort => Arity::AtLeast(1),
+            Control::Shift => Arity::Exactly(1),
+            Control::WithHandler => Arity::Exactly(2),
+            Control::Raise | Control::RaiseContinuable => Arity::Exactly(1),
+            Control::Error => Arity::AtLeast(1),
+        }
+    }
+}
+
+// A macro transformer: either a set of `syntax-rules`, or a procedure that is
+// called at expansion time with the macro use and returns its expansion
+#[derive(Debug, Clone, PartialEq)]
+pub enum Macro {
+    SyntaxRules(SyntaxRules),
+    Procedure(Box<Expression>),
+    // A non-hygienic `define-macro` procedure, called with the arguments of
+    // the macro use
+    DefineMacro(Box<Expression>),
 }
 
+// A macro transformer defined with `syntax-rules`. Each rule pairs a pattern
+// with the template that replaces a matching form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxRules {
+    pub ellipsis: String,
+    pub literals: Vec<String>,
+    pub rules: Vec<(Expression, Expression)>,
+}
+
+// A procedure implemented in Rust, such as the ones in the standard environment
+#[derive(Debug, Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub func: fn(&[Expression]) -> Result<Expression, Error>,
+}
+
+// Builtins are registered once under a unique name, so the name identifies them
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+// The number of arguments a procedure accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => count == n,
+            Arity::AtLeast(min) => count >= min,
+            Arity::Between(min, max) => count >= min && count <= max,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(min) => write!(f, "at least {}", min),
+            Arity::Between(min, max) => write!(f, "between {} and {}", min, max),
+        }
+    }
+}
+
+// Procedures share their parameters and body with their copies, so looking
+// one up or calling it doesn't copy its code
 #[derive(Debug, Clone, PartialEq)]
 pub struct Procedure {
-    pub params: Vec<Expression>,
-    pub body: Vec<Expression>,
+    pub name: Option<String>,
+    pub params: Rc<Parameters>,
+    pub body: Rc<[Expression]>,
     pub env: Environment,
 }
 
+// The formal parameters of a procedure: required names, SRFI 89 style optional
+// positional and named (keyword) parameters with their default expressions,
+// and an optional rest parameter that collects any remaining arguments.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Parameters {
+    pub required: Vec<Symbol>,
+    pub optional: Vec<(Symbol, Expression)>,
+    pub named: Vec<(Symbol, Symbol, Expression)>,
+    pub rest: Option<Symbol>,
+}
+
+impl Parameters {
+    pub fn arity(&self) -> Arity {
+        let min = self.required.len();
+
+        if self.rest.is_some() || !self.named.is_empty() {
+            Arity::AtLeast(min)
+        } else if self.optional.is_empty() {
+            Arity::Exactly(min)
+        } else {
+            Arity::Between(min, min + self.optional.len())
+        }
+    }
+}
+
+// A list of values. Lists can't be changed once made, so copies share their
+// elements, and copying one costs a reference count however long or deep it
+// is. The tail of a list shares its elements too, and so does a list made
+// with `cons` from the one it extends.
+#[derive(Clone, Default)]
+pub struct List {
+    items: Option<Rc<Items>>,
+    // Where the list starts in `items`
+    start: usize,
+}
+
+// The elements of a list and of the lists that share them. They fill the
+// buffer from the back, and `cons` writes new ones in front of the first,
+// where no existing list can see them. Elements don't change once written.
+struct Items {
+    buffer: Vec<UnsafeCell<MaybeUninit<Expression>>>,
+    // Where the first element that has been written is
+    front: Cell<usize>,
+    // Whether any of the values can refer to a scope. Lists of plain data
+    // are left alone by the collector.
+    traced: Cell<bool>,
+    // Where the list was read from, to point errors at the calls they
+    // happen in
+    span: Option<Span>,
+}
+
+impl Items {
+    // The values, with room for `spare` more in front of them
+    fn new(values: Vec<Expression>, spare: usize) -> Items {
+        let traced = values.iter().any(is_traced);
+        // Made in the allocation of `values`
+        let mut buffer: Vec<_> = values
+            .into_iter()
+            .map(|value| UnsafeCell::new(MaybeUninit::new(value)))
+            .collect();
+        if spare > 0 {
+            let room = iter::repeat_with(|| UnsafeCell::new(MaybeUninit::uninit()));
+            buffer.splice(..0, room.take(spare));
+        }
+
+        Items {
+            buffer,
+            front: Cell::new(spare),
+            traced: Cell::new(traced),
+            span: None,
+        }
+    }
+
+    // The elements from `start` on, which have all been written
+    fn values(&self, start: usize) -> &[Expression] {
+        assert!(start >= self.front.get());
+        let cells = &self.buffer[start..];
+        // SAFETY: `UnsafeCell` and `MaybeUninit` are laid out like the value
+        // they hold. The elements from the front on are initialized, and
+        // only the slots before the front are ever written.
+        unsafe { slice::from_raw_parts(cells.as_ptr().cast::<Expression>(), cells.len()) }
+    }
+
+    // Write `value` in front of the list that starts at `start`, if that
+    // list starts at the front and there is room before it
+    fn push_front(&self, start: usize, value: Expression) -> Result<(), Expression> {
+        let front = self.front.get();
+        if start != front || front == 0 {
+            return Err(value);
+        }
+
+        self.traced.set(self.traced.get() || is_traced(&value));
+        // SAFETY: no list starts before the front, so nothing refers to the
+        // slot in front of it
+        unsafe { (*self.buffer[front - 1].get()).write(value) };
+        self.front.set(front - 1);
+        Ok(())
+    }
+
+    // Move the elements out, leaving the buffer empty
+    fn into_values(mut self) -> Vec<Expression> {
+        let front = self.front.replace(0);
+        mem::take(&mut self.buffer)
+            .into_iter()
+            .skip(front)
+            // SAFETY: the elements from the front on are initialized
+            .map(|cell| unsafe { cell.into_inner().assume_init() })
+            .collect()
+    }
+}
+
+// A copy keeps the elements where they were, so lists can start at the same
+// place in it
+impl Clone for Items {
+    fn clone(&self) -> Self {
+        let front = self.front.get();
+        let mut items = Items::new(self.values(front).to_vec(), front);
+        items.span = self.span;
+        items
+    }
+}
+
+impl Drop for Items {
+    fn drop(&mut self) {
+        let front = self.front.get();
+        for cell in &mut self.buffer[front..] {
+            // SAFETY: the elements from the front on are initialized, and
+            // the buffer is going away
+            unsafe { cell.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl List {
+    pub fn new() -> Self {
+        List::default()
+    }
+
+    pub fn as_slice(&self) -> &[Expression] {
+        match &self.items {
+            Some(items) => items.values(self.start),
+            None => &[],
+        }
+    }
+
+    // This list with `value` in front of it. The new list shares the
+    // elements of this one, and when nothing has been consed onto this one
+    // yet it is made by writing `value` alone, so building a list one
+    // element at a time takes time in proportion to its length.
+    pub fn cons(&self, value: Expression) -> List {
+        let value = match &self.items {
+            Some(items) => match items.push_front(self.start, value) {
+                Ok(()) => {
+                    return List {
+                        items: self.items.clone(),
+                        start: self.start - 1,
+                    }
+                }
+                Err(value) => value,
+            },
+            None => value,
+        };
+
+        // Otherwise the elements move to a new buffer, with as much room
+        // in front of them as they take up
+        let spare = self.len() + 1;
+        List {
+            items: Some(Rc::new(Items::new(
+                iter::once(value).chain(self.iter().cloned()).collect(),
+                spare,
+            ))),
+            start: spare,
+        }
+    }
+
+    // The list without its first `count` elements
+    pub fn tail(&self, count: usize) -> List {
+        if count >= self.len() {
+            return List::new();
+        }
+
+        List {
+            items: self.items.clone(),
+            start: self.start + count,
+        }
+    }
+
+    // Whether two lists share their elements, which is how `eq?` tells
+    // lists apart. Empty lists are all the same.
+    pub fn ptr_eq(&self, other: &List) -> bool {
+        match (&self.items, &other.items) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b) && self.start == other.start,
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    // Where the list was read from. The tail of a list has no span of its own.
+    pub fn span(&self) -> Option<Span> {
+        match &self.items {
+            Some(items) if self.start == 0 => items.span,
+            _ => None,
+        }
+    }
+
+    pub(crate) fn with_span(mut self, span: Option<Span>) -> List {
+        if let Some(items) = &mut self.items {
+            if self.start == 0 {
+                Rc::make_mut(items).span = span;
+            }
+        }
+        self
+    }
+
+    pub(crate) fn is_traced(&self) -> bool {
+        self.items.as_ref().is_some_and(|items| items.traced.get())
+    }
+
+    // Lists are shared, so the collector looks into each one once
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        if let Some(items) = &self.items {
+            if items.traced.get() {
+                tracer.edge(items);
+            }
+        }
+    }
+
+    // The elements, copied only if the list shares them
+    pub fn into_vec(mut self) -> Vec<Expression> {
+        match self.items.take() {
+            Some(items) => match Rc::try_unwrap(items) {
+                Ok(items) => {
+                    let skip = self.start - items.front.get();
+                    let mut values = items.into_values();
+                    values.drain(..skip);
+                    values
+                }
+                Err(items) => items.values(self.start).to_vec(),
+            },
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Traced for Items {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.values(self.front.get())
+            .iter()
+            .for_each(|value| tracer.expr(value));
+    }
+
+    fn size(&self) -> usize {
+        self.buffer.capacity() * mem::size_of::<Expression>()
+    }
+}
+
+impl Deref for List {
+    type Target = [Expression];
+
+    fn deref(&self) -> &[Expression] {
+        self.as_slice()
+    }
+}
+
+impl From<Vec<Expression>> for List {
+    fn from(items: Vec<Expression>) -> Self {
+        if items.is_empty() {
+            return List::new();
+        }
+
+        List {
+            items: Some(Rc::new(Items::new(items, 0))),
+            start: 0,
+        }
+    }
+}
+
+impl FromIterator<Expression> for List {
+    fn from_iter<I: IntoIterator<Item = Expression>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<_>>().into()
+    }
+}
+
+impl IntoIterator for List {
+    type Item = Expression;
+    type IntoIter = std::vec::IntoIter<Expression>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a List {
+    type Item = &'a Expression;
+    type IntoIter = std::slice::Iter<'a, Expression>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Dropping a list that is nested deeply would recurse as deep as the nesting,
+// so the lists inside it that nothing else refers to are dropped one by one
+impl Drop for List {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        let mut next = self.items.take();
+
+        while let Some(items) = next {
+            if let Ok(items) = Rc::try_unwrap(items) {
+                for value in items.into_values() {
+                    if let Expression::List(mut list) = value {
+                        pending.extend(list.items.take());
+                    }
+                }
+            }
+            next = pending.pop();
+        }
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || grow_stack(|| self.as_slice() == other.as_slice())
+    }
+}
+
+impl fmt::Debug for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        grow_stack(|| f.debug_list().entries(self.iter()).finish())
+    }
+}
+
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Expression::Bool(a) => write!(f, "{}", a),
             Expression::Number(n) => write!(f, "{}", n),
             Expression::Symbol(s) => write!(f, "{}", s),
+            Expression::Reference(reference) => write!(f, "{}", reference.name),
+            Expression::String(s) => write!(f, "{:?}", s),
             Expression::List(list) => {
-                let formatted_list: Vec<String> =
-                    list.iter().map(|exp| format!("{}", exp)).collect();
+                let formatted_list: Vec<String> = list
+                    .iter()
+                    .map(|exp| grow_stack(|| format!("{}", exp)))
+                    .collect();
                 write!(f, "({})", formatted_list.join(" "))
             }
             Expression::Func(_) => write!(f, "<function>"),
             Expression::Function(_) => write!(f, "<function>"),
+            Expression::CaseLambda(_) => write!(f, "<function>"),
+            Expression::Closure(_) => write!(f, "<function>"),
+            Expression::Macro(_) => write!(f, "<macro>"),
+            Expression::Control(_) => write!(f, "<function>"),
+            Expression::Continuation(_) => write!(f, "<continuation>"),
+            Expression::ErrorObject(error) => write!(f, "<error: {}>", error),
+            Expression::Void => Ok(()),
         }
     }
 }
 
-fn parse_token_list(tokens: &mut Vec<Token>) -> Result<Expression, String> {
-    let token = tokens.pop();
-
-    if token != Some(Token::OpenParen) {
-        return Err(format!("Error: Expected OpenParen, found {:?}", token));
-    }
+pub fn parse(input: &str) -> Result<Expression, Error> {
+    let mut tokens: Vec<(Token, Span)> = tokenize_with_spans(input)?.into_iter().rev().collect();
 
-    let mut list: Vec<Expression> = Vec::new();
+    let expr = match tokens.pop() {
+        Some(token) => parse_datum(token, &mut tokens)?,
+        None => {
+            return Err(Error::new(
+                ErrorKind::Parse,
+                "Expected an expression, found the end of the input",
+            ))
+        }
+    };
 
-    while !tokens.is_empty() {
-        let token = tokens.pop();
+    match tokens.pop() {
+        Some((token, span)) => Err(Error::new(
+            ErrorKind::Parse,
+            format!("Unexpected '{}' after the end of the expression", token),
+        )
+        .with_span(span)),
+        None => Ok(expr),
+    }
+}
 
-        if token == None {
-            return Err("Error: Did not find enough tokens".to_string());
-        }
+fn parse_datum(
+    (token, span): (Token, Span),
+    tokens: &mut Vec<(Token, Span)>,
+) -> Result<Expression, Error> {
+    match token {
+        Token::Bool(b) => Ok(Expression::Bool(b)),
+        Token::Number(n) => Ok(Expression::Number(n)),
+        Token::Symbol(s) => Ok(Expression::Symbol(s.into())),
+        Token::String(s) => Ok(Expression::String(s)),
+        Token::OpenParen => parse_list(span, tokens),
+        Token::CloseParen => Err(Error::new(ErrorKind::Parse, "Unexpected ')'").with_span(span)),
+        Token::Quote => parse_quoted(span, tokens, "quote"),
+        Token::Syntax => parse_quoted(span, tokens, "syntax"),
+        Token::Quasiquote => parse_quoted(span, tokens, "quasiquote"),
+        Token::Unquote => parse_quoted(span, tokens, "unquote"),
+        Token::UnquoteSplicing => parse_quoted(span, tokens, "unquote-splicing"),
+    }
+}
 
-        let tok = token.unwrap();
+// The elements of a list up to its closing parenthesis. `open` is where the
+// list starts, to point at when the parenthesis is never closed.
+fn parse_list(open: Span, tokens: &mut Vec<(Token, Span)>) -> Result<Expression, Error> {
+    let mut list: Vec<Expression> = Vec::new();
 
-        match tok {
-            Token::Number(n) => list.push(Expression::Number(n)),
-            Token::Symbol(s) => list.push(Expression::Symbol(s)),
-            Token::OpenParen => {
-                tokens.push(Token::OpenParen);
-                let sub_list = parse_token_list(tokens)?;
-                list.push(sub_list);
+    loop {
+        match tokens.pop() {
+            Some((Token::CloseParen, close)) => {
+                let span = Span {
+                    end: close.end,
+                    ..open
+                };
+                return Ok(Expression::List(List::from(list).with_span(Some(span))));
             }
-            Token::CloseParen => {
-                return Ok(Expression::List(list));
+            Some(token) => list.push(grow_stack(|| parse_datum(token, tokens))?),
+            None => {
+                return Err(Error::new(ErrorKind::Parse, "Unclosed parenthesis").with_span(open))
             }
         }
     }
-
-    Ok(Expression::List(list))
 }
 
-pub fn parse(input: &str) -> Result<Expression, String> {
-    let token_result = match tokenize(input) {
-        Ok(val) => val,
-        Err(err) => return Err(format!("{}", err)),
+// Parse the datum following a prefix such as `'` or `#'` into a form such as
+// `(quote datum)` or `(syntax datum)`
+fn parse_quoted(
+    prefix: Span,
+    tokens: &mut Vec<(Token, Span)>,
+    form: &str,
+) -> Result<Expression, Error> {
+    let datum = match tokens.pop() {
+        Some((Token::CloseParen, span)) => {
+            return Err(
+                Error::new(ErrorKind::Parse, format!("Expected a datum after {}", form))
+                    .with_span(span),
+            )
+        }
+        Some(token) => parse_datum(token, tokens)?,
+        None => {
+            return Err(
+                Error::new(ErrorKind::Parse, format!("Expected a datum after {}", form))
+                    .with_span(prefix),
+            )
+        }
     };
 
-    let mut tokens = token_result.into_iter().rev().collect();
-
-    parse_token_list(&mut tokens)
+    Ok(Expression::List(
+        vec![Expression::Symbol(form.into()), datum].into(),
+    ))
 }
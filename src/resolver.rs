@@ -0,0 +1,389 @@
+use crate::eval::{
+    form_name, grow_stack, is_form, is_keyword, let_bindings, parse_params, rewrite_do,
+};
+use crate::expander::CORE_FORMS;
+use crate::parser::{Expression, List};
+use crate::symbol::Symbol;
+
+// A variable reference whose binding was found before evaluation. The
+// evaluator goes straight to the scope and slot rather than searching every
+// scope by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name: Symbol,
+    // How many scopes out from the one the reference is evaluated in
+    pub depth: usize,
+    // Where the binding is in that scope. A name no enclosing scope binds has
+    // no slot, and is looked up by name from the scope `depth` out.
+    pub slot: Option<usize>,
+}
+
+// Resolve the references to local variables in an expanded program. Each
+// scope is modeled as the names bound in it, in the order the evaluator binds
+// them: parameters, then internal definitions. Where the model is wrong, such
+// as for a definition that hasn't run yet, the evaluator finds the slot holds
+// another name and looks the variable up by name instead.
+pub(crate) fn resolve(expr: &Expression) -> Expression {
+    Resolver { scopes: Vec::new() }.expr(expr)
+}
+
+struct Resolver {
+    scopes: Vec<Vec<Symbol>>,
+}
+
+impl Resolver {
+    fn expr(&mut self, expr: &Expression) -> Expression {
+        match expr {
+            Expression::Symbol(name) => self.reference(name),
+            Expression::List(list) => grow_stack(|| self.list(list)),
+            _ => expr.clone(),
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expression]) -> Vec<Expression> {
+        exprs.iter().map(|expr| self.expr(expr)).collect()
+    }
+
+    fn reference(&self, name: &Symbol) -> Expression {
+        // At the top level there are no scopes to skip. Keywords evaluate to
+        // themselves when unbound, so they are left to the evaluator.
+        if self.scopes.is_empty() || is_keyword(name) {
+            return Expression::Symbol(name.clone());
+        }
+
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                scope
+                    .iter()
+                    .position(|s| s == name)
+                    .map(|slot| (depth, Some(slot)))
+            });
+        let (depth, slot) = found.unwrap_or((self.scopes.len(), None));
+
+        Expression::Reference(Reference {
+            name: name.clone(),
+            depth,
+            slot,
+        })
+    }
+
+    // Forms that don't parse are left as they are, for the evaluator to
+    // report
+    fn list(&mut self, list: &List) -> Expression {
+        let items = list.as_slice();
+
+        let resolved = match items.first().and_then(form_name) {
+            Some("quote" | "syntax" | "syntax-case") => None,
+            Some("define" | "define*") => self.define(items),
+            Some("set!") => match items {
+                [head, name, value] => Some(vec![head.clone(), name.clone(), self.expr(value)]),
+                _ => None,
+            },
+            Some(form @ ("lambda" | "lambda*")) => self.lambda(items, form == "lambda*"),
+            Some("case-lambda") => self.case_lambda(items),
+            Some("cond") => Some(self.cond(items)),
+            Some("case") => self.case(items),
+            Some("let") => self.let_form(items),
+            Some("do") => {
+                return rewrite_do(items)
+                    .map_or(Expression::List(list.clone()), |expr| self.expr(&expr))
+            }
+            Some("guard") => self.guard(items),
+            Some("quasiquote") => match items {
+                [head, template] => Some(vec![head.clone(), self.template(template, 1)]),
+                _ => None,
+            },
+            Some(form) if CORE_FORMS.contains(&form) => {
+                let mut resolved = vec![items[0].clone()];
+                resolved.extend(self.exprs(&items[1..]));
+                Some(resolved)
+            }
+            _ => Some(self.exprs(items)),
+        };
+
+        match resolved {
+            Some(items) => Expression::List(List::from(items).with_span(list.span())),
+            None => Expression::List(list.clone()),
+        }
+    }
+
+    fn lambda(&mut self, items: &[Expression], extended: bool) -> Option<Vec<Expression>> {
+        let (formals, body) = match items {
+            [_, formals, body @ ..] if !body.is_empty() => {
+                self.procedure(formals, body, extended)?
+            }
+            _ => return None,
+        };
+
+        let mut resolved = vec![items[0].clone(), formals];
+        resolved.extend(body);
+        Some(resolved)
+    }
+
+    fn define(&mut self, items: &[Expression]) -> Option<Vec<Expression>> {
+        match items {
+            [head, name @ Expression::Symbol(_), value] => {
+                Some(vec![head.clone(), name.clone(), self.expr(value)])
+            }
+            [head, Expression::List(signature), body @ ..] if !body.is_empty() => {
+                let name = signature.first()?;
+                let formals = Expression::List(signature.tail(1));
+                let (formals, body) = self.procedure(&formals, body, is_form(items, "define*"))?;
+
+                let mut signature = vec![name.clone()];
+                if let Expression::List(formals) = formals {
+                    signature.extend(formals);
+                }
+                let mut resolved = vec![head.clone(), Expression::List(signature.into())];
+                resolved.extend(body);
+                Some(resolved)
+            }
+            _ => None,
+        }
+    }
+
+    // The parameters of a procedure are bound in a new scope in the order
+    // they are listed. Default values are evaluated in that scope too.
+    fn procedure(
+        &mut self,
+        formals: &Expression,
+        body: &[Expression],
+        extended: bool,
+    ) -> Option<(Expression, Vec<Expression>)> {
+        let params = parse_params(formals, extended).ok()?;
+
+        let mut scope = params.required.clone();
+        scope.extend(params.optional.iter().map(|(name, _)| name.clone()));
+        scope.extend(params.named.iter().map(|(_, name, _)| name.clone()));
+        scope.extend(params.rest.clone());
+        definitions(body, &mut scope);
+
+        self.scopes.push(scope);
+        let formals = match formals {
+            Expression::List(formals) if extended => Expression::List(
+                formals
+                    .iter()
+                    .map(|formal| match formal {
+                        Expression::List(spec) => {
+                            let mut spec = spec.to_vec();
+                            if let Some(default) = spec.last_mut() {
+                                *default = self.expr(default);
+                            }
+                            Expression::List(spec.into())
+                        }
+                        formal => formal.clone(),
+                    })
+                    .collect(),
+            ),
+            formals => formals.clone(),
+        };
+        let body = self.exprs(body);
+        self.scopes.pop();
+
+        Some((formals, body))
+    }
+
+    fn case_lambda(&mut self, items: &[Expression]) -> Option<Vec<Expression>> {
+        let mut resolved = vec![items[0].clone()];
+
+        for clause in &items[1..] {
+            match clause {
+                Expression::List(clause) if clause.len() >= 2 => {
+                    let (formals, body) = self.procedure(&clause[0], &clause[1..], false)?;
+                    let mut clause = vec![formals];
+                    clause.extend(body);
+                    resolved.push(Expression::List(clause.into()));
+                }
+                _ => return None,
+            }
+        }
+
+        Some(resolved)
+    }
+
+    // `else` and `=>` are compared by name when the clauses are evaluated
+    fn cond(&mut self, items: &[Expression]) -> Vec<Expression> {
+        let mut resolved = vec![items[0].clone()];
+        resolved.extend(items[1..].iter().map(|clause| match clause {
+            Expression::List(clause) if !clause.is_empty() => {
+                let test = match &clause[0] {
+                    Expression::Symbol(s) if s == "else" => clause[0].clone(),
+                    test => self.expr(test),
+                };
+                let mut resolved = vec![test];
+                resolved.extend(self.clause_body(&clause[1..]));
+                Expression::List(resolved.into())
+            }
+            clause => clause.clone(),
+        }));
+        resolved
+    }
+
+    fn case(&mut self, items: &[Expression]) -> Option<Vec<Expression>> {
+        let mut resolved = vec![items[0].clone(), self.expr(items.get(1)?)];
+        resolved.extend(items[2..].iter().map(|clause| match clause {
+            Expression::List(clause) if !clause.is_empty() => {
+                let mut resolved = vec![clause[0].clone()];
+                resolved.extend(self.clause_body(&clause[1..]));
+                Expression::List(resolved.into())
+            }
+            clause => clause.clone(),
+        }));
+        Some(resolved)
+    }
+
+    fn clause_body(&mut self, exprs: &[Expression]) -> Vec<Expression> {
+        exprs
+            .iter()
+            .map(|expr| match expr {
+                Expression::Symbol(s) if s == "=>" => expr.clone(),
+                expr => self.expr(expr),
+            })
+            .collect()
+    }
+
+    // A named let binds its name in a scope of its own, which the procedure
+    // that runs the body is called from
+    fn let_form(&mut self, items: &[Expression]) -> Option<Vec<Expression>> {
+        let (name, start) = match items.get(1) {
+            Some(Expression::Symbol(name)) => (Some(name.clone()), 2),
+            _ => (None, 1),
+        };
+        let body = items.get(start + 1..).filter(|body| !body.is_empty())?;
+        let (names, inits) = let_bindings(&items[start]).ok()?;
+
+        let bindings = names
+            .iter()
+            .zip(self.exprs(&inits))
+            .map(|(name, init)| {
+                Expression::List(vec![Expression::Symbol(name.clone()), init].into())
+            })
+            .collect();
+
+        let outer = self.scopes.len();
+        self.scopes.extend(name.map(|name| vec![name]));
+        let mut scope = names;
+        definitions(body, &mut scope);
+        self.scopes.push(scope);
+        let body = self.exprs(body);
+        self.scopes.truncate(outer);
+
+        let mut resolved = items[..start].to_vec();
+        resolved.push(Expression::List(bindings));
+        resolved.extend(body);
+        Some(resolved)
+    }
+
+    // The body of a guard runs in a scope of its own, and its clauses in one
+    // holding the raised object
+    fn guard(&mut self, items: &[Expression]) -> Option<Vec<Expression>> {
+        let (spec, body) = match items {
+            [_, Expression::List(spec), body @ ..] if !body.is_empty() => (spec, body),
+            _ => return None,
+        };
+        let (var, clauses) = match spec.split_first() {
+            Some((Expression::Symbol(var), clauses)) => (var, clauses),
+            _ => return None,
+        };
+
+        let mut scope = Vec::new();
+        definitions(body, &mut scope);
+        self.scopes.push(scope);
+        let body = self.exprs(body);
+        self.scopes.pop();
+
+        let mut scope = vec![var.clone()];
+        definitions(clauses, &mut scope);
+        self.scopes.push(scope);
+        let clauses = self.cond(spec);
+        self.scopes.pop();
+
+        let mut resolved = vec![items[0].clone(), Expression::List(clauses.into())];
+        resolved.extend(body);
+        Some(resolved)
+    }
+
+    // Only the expressions unquoted at the outermost level are evaluated
+    fn template(&mut self, template: &Expression, depth: usize) -> Expression {
+        let items = match template {
+            Expression::List(items) => items,
+            _ => return template.clone(),
+        };
+
+        let resolved = match items.as_slice() {
+            [form @ Expression::Symbol(name), inner]
+                if matches!(name.as_str(), "unquote" | "unquote-splicing" | "quasiquote") =>
+            {
+                let inner = match name.as_str() {
+                    "quasiquote" => self.template(inner, depth + 1),
+                    _ if depth == 1 => self.expr(inner),
+                    _ => self.template(inner, depth - 1),
+                };
+                vec![form.clone(), inner]
+            }
+            items => items
+                .iter()
+                .map(|item| grow_stack(|| self.template(item, depth)))
+                .collect(),
+        };
+
+        Expression::List(List::from(resolved).with_span(items.span()))
+    }
+}
+
+// Collect the names that evaluating `exprs` may define in the current scope,
+// in the order they appear. Naming too many is harmless, as references to
+// them are checked when they are evaluated, but a name left out would be
+// looked up past the scope that holds it.
+fn definitions(exprs: &[Expression], names: &mut Vec<Symbol>) {
+    for expr in exprs {
+        let items = match expr {
+            Expression::List(items) => items,
+            _ => continue,
+        };
+
+        match items.first().and_then(form_name) {
+            Some("quote" | "lambda" | "lambda*" | "case-lambda" | "guard") => {}
+            Some("define" | "define*") => match items.get(1) {
+                Some(Expression::Symbol(name)) => {
+                    add(names, name);
+                    grow_stack(|| definitions(&items[2..], names));
+                }
+                Some(Expression::List(signature)) => {
+                    if let Some(Expression::Symbol(name)) = signature.first() {
+                        add(names, name);
+                    }
+                }
+                _ => {}
+            },
+            // Only the initial values are evaluated in the current scope
+            Some("let") => {
+                let start = if matches!(items.get(1), Some(Expression::Symbol(_))) {
+                    2
+                } else {
+                    1
+                };
+                if let Some(Ok((_, inits))) = items.get(start).map(let_bindings) {
+                    grow_stack(|| definitions(&inits, names));
+                }
+            }
+            Some("do") => {
+                if let Ok(expr) = rewrite_do(items) {
+                    definitions(&[expr], names);
+                }
+            }
+            Some("syntax-case") => definitions(&items[1..2.min(items.len())], names),
+            _ => grow_stack(|| definitions(items, names)),
+        }
+    }
+}
+
+fn add(names: &mut Vec<Symbol>, name: &Symbol) {
+    if !names.contains(name) {
+        names.push(name.clone());
+    }
+}
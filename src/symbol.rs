@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+
+thread_local! {
+    // Every interned name, with the entry its symbols share. An entry leaves
+    // the table when the last symbol pointing to it is dropped, so the names
+    // the expander makes up for each macro use don't pile up.
+    static TABLE: RefCell<HashMap<Box<str>, Weak<Entry>>> = RefCell::new(HashMap::new());
+}
+
+struct Entry {
+    name: Box<str>,
+    interned: bool,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if self.interned {
+            // The table may already be gone if the thread is exiting
+            let _ = TABLE.try_with(|table| table.borrow_mut().remove(&self.name));
+        }
+    }
+}
+
+// A symbol is a pointer to an entry of the symbol table. Symbols with the same
+// name share one entry, so comparing or hashing them never looks at the name.
+#[derive(Clone)]
+pub struct Symbol(Rc<Entry>);
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        TABLE.with(|table| {
+            let mut table = table.borrow_mut();
+
+            if let Some(entry) = table.get(name).and_then(Weak::upgrade) {
+                return Symbol(entry);
+            }
+
+            let entry = Rc::new(Entry {
+                name: name.into(),
+                interned: true,
+            });
+            table.insert(name.into(), Rc::downgrade(&entry));
+            Symbol(entry)
+        })
+    }
+
+    // A symbol that is different from every other one, including interned
+    // symbols with the same name
+    pub fn uninterned(name: &str) -> Symbol {
+        Symbol(Rc::new(Entry {
+            name: name.into(),
+            interned: false,
+        }))
+    }
+
+    pub fn is_interned(&self) -> bool {
+        self.0.interned
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<&Symbol> for Symbol {
+    fn from(symbol: &Symbol) -> Self {
+        symbol.clone()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
@@ -0,0 +1,601 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::budget;
+use crate::env::Environment;
+use crate::error::{Error, ErrorKind, Span};
+use crate::eval::{check_arity, is_keyword, is_true, recursion_error, MAX_DEPTH};
+use crate::gc::{self, Traced, Tracer};
+use crate::parser::{Arity, Expression, List};
+use crate::symbol::Symbol;
+
+// The instructions of the bytecode virtual machine. Values are kept on an
+// operand stack, and local variables are addressed by how many scopes out
+// they are and their slot in that scope, so no names are looked up at run
+// time except for globals.
+#[derive(Debug)]
+pub(crate) enum Instruction {
+    Const(Expression),
+    LocalRef {
+        depth: usize,
+        index: usize,
+        name: Symbol,
+    },
+    LocalSet {
+        depth: usize,
+        index: usize,
+        name: Symbol,
+    },
+    // Bind an internal definition in the innermost scope
+    LocalDefine {
+        index: usize,
+        name: Symbol,
+    },
+    // A global looked up in operator position is reported as an undefined
+    // function rather than an undefined symbol
+    GlobalRef {
+        name: Symbol,
+        operator: bool,
+    },
+    GlobalSet(Symbol),
+    GlobalDefine(Symbol),
+    Pop,
+    Dup,
+    Swap,
+    Jump(usize),
+    JumpIfFalse(usize),
+    // Jump, leaving the tested value on the stack, or pop it and go on
+    JumpIfFalseKeep(usize),
+    JumpIfTrueKeep(usize),
+    // Jump unless the value on top of the stack is one of the data of a
+    // `case` clause, without popping it
+    JumpUnlessMember(List, usize),
+    MakeClosure(Rc<Code>),
+    // Call the procedure below the given number of arguments, with the name
+    // it was called by, if any
+    Call(usize, Option<Symbol>),
+    TailCall(usize, Option<Symbol>),
+    Return,
+}
+
+// The compiled body of a procedure, or of a top-level program
+#[derive(Debug)]
+pub(crate) struct Code {
+    pub name: Option<String>,
+    pub required: usize,
+    pub rest: bool,
+    // The number of slots in a scope of the procedure: its parameters and
+    // internal definitions
+    pub frame_size: usize,
+    // Whether calls show in the call stack of errors. The procedures that
+    // `let` forms are compiled to don't.
+    pub traced: bool,
+    pub instructions: Vec<Instruction>,
+    // The instructions of the calls read from the source, with their spans
+    pub spans: Vec<(Range<usize>, Span)>,
+    pub globals: Environment,
+}
+
+impl Code {
+    fn arity(&self) -> Arity {
+        if self.rest {
+            Arity::AtLeast(self.required)
+        } else {
+            Arity::Exactly(self.required)
+        }
+    }
+
+    // The span of the innermost call around the instruction at `at`. The
+    // call that instruction makes counts only if `calling` is set.
+    fn call_site(&self, at: usize, calling: bool) -> Option<Span> {
+        self.spans
+            .iter()
+            .filter(|(range, _)| range.contains(&at) && (calling || range.end != at + 1))
+            .min_by_key(|(range, _)| range.len())
+            .map(|(_, span)| *span)
+    }
+}
+
+// The variables of one procedure call. A slot is empty until the internal
+// definition that fills it has run.
+struct Scope {
+    slots: RefCell<Vec<Option<Expression>>>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Traced for Scope {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(parent) = &self.parent {
+            tracer.edge(parent);
+        }
+        if let Ok(slots) = self.slots.try_borrow() {
+            slots.iter().flatten().for_each(|value| tracer.expr(value));
+        }
+    }
+
+    fn clear(&self) -> Option<Box<dyn Any>> {
+        let mut slots = self.slots.try_borrow_mut().ok()?;
+        Some(Box::new(mem::take(&mut *slots)))
+    }
+
+    fn size(&self) -> usize {
+        let slots = self.slots.try_borrow().map_or(0, |slots| slots.capacity());
+        mem::size_of::<Scope>() + slots * mem::size_of::<Option<Expression>>()
+    }
+}
+
+impl Traced for Code {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.globals.trace(tracer);
+        for instruction in &self.instructions {
+            match instruction {
+                Instruction::Const(value) => tracer.expr(value),
+                Instruction::MakeClosure(code) => tracer.edge(code),
+                _ => {}
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Code>()
+            + self.instructions.capacity() * mem::size_of::<Instruction>()
+            + self.spans.capacity() * mem::size_of::<(Range<usize>, Span)>()
+    }
+}
+
+// A procedure compiled to bytecode, with the scopes it closes over
+#[derive(Clone)]
+pub struct Closure {
+    code: Rc<Code>,
+    scope: Option<Rc<Scope>>,
+    name: Option<String>,
+}
+
+impl Closure {
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.code);
+        if let Some(scope) = &self.scope {
+            tracer.edge(scope);
+        }
+    }
+}
+
+// Closures are only equal to themselves
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        let same_scope = match (&self.scope, &other.scope) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        Rc::ptr_eq(&self.code, &other.code) && same_scope
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure")
+    }
+}
+
+// Name anonymous procedures after the variable they are bound to
+fn named(value: Expression, name: &str) -> Expression {
+    match value {
+        Expression::Closure(closure) if closure.name.is_none() => Expression::Closure(Closure {
+            name: Some(name.to_string()),
+            ..closure
+        }),
+        Expression::Function(proc) if proc.name.is_none() => {
+            Expression::Function(crate::parser::Procedure {
+                name: Some(name.to_string()),
+                ..proc
+            })
+        }
+        value => value,
+    }
+}
+
+#[derive(Clone)]
+struct CallFrame {
+    code: Rc<Code>,
+    pc: usize,
+    scope: Option<Rc<Scope>>,
+    // Where the operand stack of the call starts
+    base: usize,
+    // The name of the procedure, for the call stack of errors. The frame of
+    // a top-level program has none.
+    name: Option<String>,
+}
+
+// Why the virtual machine stopped running
+pub(crate) enum Exit {
+    // The outermost frame returned
+    Return(Expression),
+    // A procedure that only the tree-walking evaluator can run is called,
+    // such as one that captures the continuation. Its value is passed back
+    // with `resume`, unless it was a tail call that finished the last frame.
+    Apply {
+        proc: Expression,
+        args: Vec<Expression>,
+        name: Option<Symbol>,
+    },
+}
+
+// The state of a run of compiled code. While it waits for a procedure of the
+// evaluator, it sits in a frame of the evaluator's stack, so continuations
+// captured in that procedure take a copy of it along.
+#[derive(Clone)]
+pub(crate) struct Vm {
+    values: Vec<Expression>,
+    frames: Vec<CallFrame>,
+    // The procedure calls in progress in the evaluator that started it
+    outer_calls: usize,
+}
+
+impl Vm {
+    fn new(outer_calls: usize) -> Self {
+        Vm {
+            values: Vec::new(),
+            frames: Vec::new(),
+            outer_calls,
+        }
+    }
+
+    // A machine that runs a compiled top-level program
+    pub(crate) fn program(code: Rc<Code>) -> Self {
+        let mut vm = Vm::new(0);
+        vm.frames.push(CallFrame {
+            code,
+            pc: 0,
+            scope: None,
+            base: 0,
+            name: None,
+        });
+        vm
+    }
+
+    // A machine that calls a compiled procedure from the evaluator
+    pub(crate) fn calling(
+        closure: &Closure,
+        args: Vec<Expression>,
+        name: Option<&str>,
+        outer_calls: usize,
+    ) -> Result<Self, Error> {
+        let mut vm = Vm::new(outer_calls);
+        let argc = args.len();
+        vm.values.push(Expression::Closure(closure.clone()));
+        vm.values.extend(args);
+        vm.call(argc, name, false)?;
+        Ok(vm)
+    }
+
+    // Whether there is nothing left to run
+    pub(crate) fn is_done(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // The number of procedure calls in progress, counting those of the
+    // evaluator below. The frame of a top-level program is not a call.
+    pub(crate) fn call_depth(&self) -> usize {
+        let program = self
+            .frames
+            .first()
+            .is_some_and(|frame| frame.scope.is_none());
+        self.outer_calls + self.frames.len() - program as usize
+    }
+
+    // Continue with the value of the procedure the machine stopped for
+    pub(crate) fn resume(&mut self, value: Expression) {
+        self.values.push(value);
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        self.values.iter().for_each(|value| tracer.expr(value));
+        for frame in &self.frames {
+            tracer.edge(&frame.code);
+            if let Some(scope) = &frame.scope {
+                tracer.edge(scope);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Expression {
+        self.values.pop().unwrap_or(Expression::Void)
+    }
+
+    fn scope_at(&self, depth: usize) -> Rc<Scope> {
+        let mut scope = self.frames.last().and_then(|frame| frame.scope.clone());
+        for _ in 0..depth {
+            scope = scope.and_then(|scope| scope.parent.clone());
+        }
+        scope.expect("compiled code refers to a scope that does not exist")
+    }
+
+    // The names of the procedures being called, innermost first
+    pub(crate) fn call_stack(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .rev()
+            .filter_map(|frame| frame.name.clone())
+            .collect()
+    }
+
+    // The span of the innermost call in progress. After an error, the call
+    // the machine stopped at is one of them. While it waits for the
+    // evaluator, the procedure it handed over is running instead.
+    pub(crate) fn call_site(&self, failed: bool) -> Option<Span> {
+        self.frames.iter().rev().enumerate().find_map(|(i, frame)| {
+            let at = frame.pc.checked_sub(1)?;
+            frame.code.call_site(at, failed && i == 0)
+        })
+    }
+
+    // Run until the outermost frame returns, or until a procedure has to be
+    // handed to the evaluator
+    pub(crate) fn execute(&mut self) -> Result<Exit, Error> {
+        loop {
+            let frame = self.frames.last_mut().expect("no frame to run");
+            let code = frame.code.clone();
+            let pc = frame.pc;
+            frame.pc += 1;
+
+            match &code.instructions[pc] {
+                Instruction::Const(value) => self.values.push(value.clone()),
+                Instruction::LocalRef { depth, index, name } => {
+                    let value = self.scope_at(*depth).slots.borrow()[*index].clone();
+                    match value {
+                        Some(value) => self.values.push(value),
+                        None => {
+                            return Err(Error::new(ErrorKind::UnboundVariable, "Undefined symbol:")
+                                .with_irritants(vec![Expression::Symbol(name.clone())]))
+                        }
+                    }
+                }
+                Instruction::LocalSet { depth, index, name } => {
+                    let value = self.pop();
+                    let scope = self.scope_at(*depth);
+                    let mut slots = scope.slots.borrow_mut();
+                    match &mut slots[*index] {
+                        Some(slot) => *slot = value,
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::UnboundVariable,
+                                "Undefined variable:",
+                            )
+                            .with_irritants(vec![Expression::Symbol(name.clone())]))
+                        }
+                    }
+                    self.values.push(Expression::Void);
+                }
+                Instruction::LocalDefine { index, name } => {
+                    let value = named(self.pop(), name);
+                    self.scope_at(0).slots.borrow_mut()[*index] = Some(value);
+                    self.values.push(Expression::Symbol(name.clone()));
+                }
+                Instruction::GlobalRef { name, operator } => match code.globals.get(name) {
+                    Some(value) => self.values.push(value),
+                    None if !operator && is_keyword(name) => {
+                        self.values.push(Expression::Symbol(name.clone()))
+                    }
+                    None => {
+                        let message = if *operator {
+                            "Undefined function:"
+                        } else {
+                            "Undefined symbol:"
+                        };
+                        return Err(Error::new(ErrorKind::UnboundVariable, message)
+                            .with_irritants(vec![Expression::Symbol(name.clone())]));
+                    }
+                },
+                Instruction::GlobalSet(name) => {
+                    let value = self.pop();
+                    if !code.globals.set(name, value) {
+                        return Err(
+                            Error::new(ErrorKind::UnboundVariable, "Undefined variable:")
+                                .with_irritants(vec![Expression::Symbol(name.clone())]),
+                        );
+                    }
+                    self.values.push(Expression::Void);
+                }
+                Instruction::GlobalDefine(name) => {
+                    let value = named(self.pop(), name);
+                    code.globals.clone().insert(name, value);
+                    self.values.push(Expression::Symbol(name.clone()));
+                }
+                Instruction::Pop => {
+                    self.pop();
+                }
+                Instruction::Dup => {
+                    let value = self.values.last().cloned().unwrap_or(Expression::Void);
+                    self.values.push(value);
+                }
+                Instruction::Swap => {
+                    let len = self.values.len();
+                    self.values.swap(len - 1, len - 2);
+                }
+                Instruction::Jump(target) => self.jump(*target),
+                Instruction::JumpIfFalse(target) => {
+                    if !is_true(&self.pop()) {
+                        self.jump(*target);
+                    }
+                }
+                Instruction::JumpIfFalseKeep(target) => {
+                    if self.values.last().is_some_and(|value| !is_true(value)) {
+                        self.jump(*target);
+                    } else {
+                        self.pop();
+                    }
+                }
+                Instruction::JumpIfTrueKeep(target) => {
+                    if self.values.last().is_some_and(is_true) {
+                        self.jump(*target);
+                    } else {
+                        self.pop();
+                    }
+                }
+                Instruction::JumpUnlessMember(data, target) => {
+                    if !self.values.last().is_some_and(|key| data.contains(key)) {
+                        self.jump(*target);
+                    }
+                }
+                Instruction::MakeClosure(code) => {
+                    let scope = self.frames.last().and_then(|frame| frame.scope.clone());
+                    self.values.push(Expression::Closure(Closure {
+                        code: code.clone(),
+                        scope,
+                        name: code.name.clone(),
+                    }));
+                }
+                Instruction::Call(argc, name) => {
+                    if let Some(exit) = self.call(*argc, name.as_deref(), false)? {
+                        return Ok(exit);
+                    }
+                }
+                Instruction::TailCall(argc, name) => {
+                    if let Some(exit) = self.call(*argc, name.as_deref(), true)? {
+                        return Ok(exit);
+                    }
+                }
+                Instruction::Return => {
+                    if let Some(value) = self.return_from_frame() {
+                        return Ok(Exit::Return(value));
+                    }
+                }
+            }
+        }
+    }
+
+    fn jump(&mut self, target: usize) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc = target;
+        }
+    }
+
+    // Pop the current frame and pass the value on top of the stack to its
+    // caller, or return it if it was the outermost frame
+    fn return_from_frame(&mut self) -> Option<Expression> {
+        let value = self.pop();
+        let frame = self.frames.pop().expect("no frame to return from");
+        self.values.truncate(frame.base);
+
+        if self.frames.is_empty() {
+            Some(value)
+        } else {
+            self.values.push(value);
+            None
+        }
+    }
+
+    // Call the procedure below `argc` arguments on the stack. The body of a
+    // closure runs in a new frame, which replaces the current one for a tail
+    // call. Builtins are called right away, and the other procedures are
+    // handed to the evaluator, after finishing the current frame for a tail
+    // call.
+    fn call(&mut self, argc: usize, name: Option<&str>, tail: bool) -> Result<Option<Exit>, Error> {
+        budget::step(|| self.call_depth())?;
+
+        let callee_index = self.values.len() - argc - 1;
+        let args: Vec<Expression> = self.values.drain(callee_index + 1..).collect();
+        let callee = self.pop();
+
+        let value = match callee {
+            Expression::Closure(closure) => {
+                let proc_name = closure
+                    .name
+                    .as_deref()
+                    .or(name)
+                    .unwrap_or("anonymous procedure")
+                    .to_string();
+                check_arity(&proc_name, closure.code.arity(), args.len())?;
+                let frame_name = closure.code.traced.then_some(proc_name);
+
+                let mut slots = vec![None; closure.code.frame_size];
+                let mut args = args.into_iter();
+                for slot in slots.iter_mut().take(closure.code.required) {
+                    *slot = args.next();
+                }
+                if closure.code.rest {
+                    slots[closure.code.required] = Some(Expression::List(args.collect()));
+                }
+
+                let scope = Rc::new(Scope {
+                    slots: RefCell::new(slots),
+                    parent: closure.scope.clone(),
+                });
+                gc::register(&scope);
+
+                let base = if tail {
+                    let frame = self.frames.pop().expect("no frame to replace");
+                    self.values.truncate(frame.base);
+                    frame.base
+                } else if self.frames.len() >= MAX_DEPTH {
+                    return Err(recursion_error());
+                } else {
+                    self.values.len()
+                };
+
+                self.frames.push(CallFrame {
+                    code: closure.code,
+                    pc: 0,
+                    scope: Some(scope),
+                    base,
+                    name: frame_name,
+                });
+                return Ok(None);
+            }
+            Expression::Func(builtin) => {
+                check_arity(builtin.name, builtin.arity, args.len())?;
+                let value = (builtin.func)(&args).map_err(|e| Error {
+                    message: format!("{}: {}", builtin.name, e.message),
+                    stack: vec![builtin.name.to_string()],
+                    ..e
+                })?;
+                budget::allocate_result(&value);
+                value
+            }
+            proc @ (Expression::Function(_)
+            | Expression::CaseLambda(_)
+            | Expression::Control(_)
+            | Expression::Continuation(_)) => {
+                if tail {
+                    let frame = self.frames.pop().expect("no frame to finish");
+                    self.values.truncate(frame.base);
+                }
+                return Ok(Some(Exit::Apply {
+                    proc,
+                    args,
+                    name: name.map(Symbol::from),
+                }));
+            }
+            callee => {
+                return Err(
+                    Error::new(ErrorKind::Type, "Not a procedure:").with_irritants(vec![callee])
+                )
+            }
+        };
+
+        self.values.push(value);
+        if tail {
+            // Nothing is left to do in the current frame. The outermost frame
+            // has no caller to return to, so the value stays on the stack for
+            // its Return.
+            if self.frames.len() > 1 {
+                self.return_from_frame();
+            } else {
+                self.jump_to_return();
+            }
+        }
+        Ok(None)
+    }
+
+    // Continue at the end of the outermost frame's code
+    fn jump_to_return(&mut self) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc = frame.code.instructions.len() - 1;
+        }
+    }
+}
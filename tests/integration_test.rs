@@ -1,9 +1,13 @@
+use rustyscm::budget::{interrupt_flag, Budget};
 use rustyscm::env::standard_env;
-use rustyscm::eval::eval;
+use rustyscm::error::{next_source, ErrorKind};
+use rustyscm::eval::{eval, eval_with, eval_with_budget, Engine};
 use rustyscm::parser::{parse, Expression};
 
 use std::f64::consts::PI;
-
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_parse1() {
@@ -11,11 +15,14 @@ fn test_parse1() {
 
     let actual_parsed_expr = parse(input).unwrap();
 
-    let expected_expr = Expression::List(vec![
-        Expression::Symbol("define".to_string()),
-        Expression::Symbol("r".to_string()),
-        Expression::Number(10.0),
-    ]);
+    let expected_expr = Expression::List(
+        vec![
+            Expression::Symbol("define".into()),
+            Expression::Symbol("r".into()),
+            Expression::Number(10.0),
+        ]
+        .into(),
+    );
 
     assert_eq!(actual_parsed_expr, expected_expr);
 }
@@ -30,23 +37,35 @@ fn test_parse2() {
 
     let actual_parsed_expr = parse(input).unwrap();
 
-    let expected_expr = Expression::List(vec![
-        Expression::List(vec![
-            Expression::Symbol("define".to_string()),
-            Expression::Symbol("x".to_string()),
-            Expression::Number(5.0),
-        ]),
-        Expression::List(vec![
-            Expression::Symbol("define".to_string()),
-            Expression::Symbol("y".to_string()),
-            Expression::Number(10.0),
-        ]),
-        Expression::List(vec![
-            Expression::Symbol("*".to_string()),
-            Expression::Symbol("x".to_string()),
-            Expression::Symbol("y".to_string()),
-        ]),
-    ]);
+    let expected_expr = Expression::List(
+        vec![
+            Expression::List(
+                vec![
+                    Expression::Symbol("define".into()),
+                    Expression::Symbol("x".into()),
+                    Expression::Number(5.0),
+                ]
+                .into(),
+            ),
+            Expression::List(
+                vec![
+                    Expression::Symbol("define".into()),
+                    Expression::Symbol("y".into()),
+                    Expression::Number(10.0),
+                ]
+                .into(),
+            ),
+            Expression::List(
+                vec![
+                    Expression::Symbol("*".into()),
+                    Expression::Symbol("x".into()),
+                    Expression::Symbol("y".into()),
+                ]
+                .into(),
+            ),
+        ]
+        .into(),
+    );
 
     assert_eq!(actual_parsed_expr, expected_expr);
 }
@@ -226,7 +245,7 @@ fn test_define() {
     let input = "(define r 10)";
     let result = eval(input, &mut env).unwrap();
 
-    assert_eq!(result, Expression::Symbol("r".to_string()));
+    assert_eq!(result, Expression::Symbol("r".into()));
 }
 
 #[test]
@@ -236,7 +255,7 @@ fn test_function_definition() {
     let input1 = "(define (square x) (* x x))";
     let result1 = eval(input1, &mut env).unwrap();
 
-    assert_eq!(result1, Expression::Symbol("square".to_string()));
+    assert_eq!(result1, Expression::Symbol("square".into()));
 
     let input2 = "(square 5)";
     let result2 = eval(input2, &mut env).unwrap();
@@ -251,7 +270,7 @@ fn test_fibonacci() {
     let input1 = "(define (fib n) (if (< n 2) 1 (+ (fib (- n 1)) (fib (- n 2)))))";
     let result1 = eval(input1, &mut env).unwrap();
 
-    assert_eq!(result1, Expression::Symbol("fib".to_string()));
+    assert_eq!(result1, Expression::Symbol("fib".into()));
 
     let input2 = "(fib 10)";
     let result2 = eval(input2, &mut env).unwrap();
@@ -266,7 +285,7 @@ fn test_factorial() {
     let input1 = "(define (fact n) (if (<= n 1) 1 (* n (fact (- n 1)))))";
     let result1 = eval(input1, &mut env).unwrap();
 
-    assert_eq!(result1, Expression::Symbol("fact".to_string()));
+    assert_eq!(result1, Expression::Symbol("fact".into()));
 
     let input2 = "(fact 5)";
     let result2 = eval(input2, &mut env).unwrap();
@@ -280,7 +299,7 @@ fn test_area_of_a_circle() {
 
     let input1 = "(define r 10)";
     let result1 = eval(input1, &mut env).unwrap();
-    assert_eq!(result1, Expression::Symbol("r".to_string()));
+    assert_eq!(result1, Expression::Symbol("r".into()));
 
     let input2 = "(* pi (* r r))";
     let result2 = eval(input2, &mut env).unwrap();
@@ -295,10 +314,2539 @@ fn test_nested_functions() {
     let input1 = "(define (cube x) (define (square x) (* x x)) (* x (square x)))";
     let result1 = eval(input1, &mut env).unwrap();
 
-    assert_eq!(result1, Expression::Symbol("cube".to_string()));
+    assert_eq!(result1, Expression::Symbol("cube".into()));
 
     let input2 = "(cube 3)";
     let result2 = eval(input2, &mut env).unwrap();
 
     assert_eq!(result2, Expression::Number(27.0));
 }
+
+#[test]
+fn test_if_without_else() {
+    let mut env = standard_env();
+
+    let input1 = "(if (= 1 1) 10)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(10.0));
+
+    let input2 = "(if (= 1 2) 10)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Void);
+}
+
+#[test]
+fn test_cond_expression() {
+    let mut env = standard_env();
+
+    let input1 = "(define (sign n) (cond ((< n 0) -1) ((= n 0) 0) (else 1)))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Symbol("sign".into()));
+
+    let input2 = "(sign -5)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(-1.0));
+
+    let input3 = "(sign 0)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(0.0));
+
+    let input4 = "(sign 5)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(1.0));
+
+    let input5 = "(cond ((> 1 2) 1) ((> 2 1)))";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Bool(true));
+}
+
+#[test]
+fn test_cond_arrow_clause() {
+    let mut env = standard_env();
+
+    let input1 = "(define (negate b) (if b 0 1))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(cond ((> 1 2) 10) ((< 1 2) => negate))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(0.0));
+}
+
+#[test]
+fn test_case_expression() {
+    let mut env = standard_env();
+
+    let input1 = "(define (classify n) (case n ((1 2 3) 10) ((4 5 6) 20) (else 30)))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(classify 2)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(10.0));
+
+    let input3 = "(classify 5)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(20.0));
+
+    let input4 = "(classify 9)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(30.0));
+}
+
+#[test]
+fn test_case_else_arrow_clause() {
+    let mut env = standard_env();
+
+    let input1 = "(define (double n) (* n 2))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(case (* 2 3) ((2 3 5 7) 1) (else => double))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(12.0));
+}
+
+#[test]
+fn test_when_and_unless() {
+    let mut env = standard_env();
+
+    let input1 = "(when (< 1 2) 1 2 3)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(3.0));
+
+    let input2 = "(when (> 1 2) 1 2 3)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Void);
+
+    let input3 = "(unless (> 1 2) 4 5)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(5.0));
+}
+
+#[test]
+fn test_and_expression() {
+    let mut env = standard_env();
+
+    let input1 = "(and)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Bool(true));
+
+    let input2 = "(and (= 1 1) (+ 2 3))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(5.0));
+
+    // Short-circuits before reaching the undefined symbol
+    let input3 = "(and (= 1 2) undefined-symbol)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Bool(false));
+}
+
+#[test]
+fn test_or_expression() {
+    let mut env = standard_env();
+
+    let input1 = "(or)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Bool(false));
+
+    let input2 = "(or (= 1 2) (+ 2 3))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(5.0));
+
+    let input3 = "(or (= 1 1) undefined-symbol)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Bool(true));
+}
+
+#[test]
+fn test_truthiness_in_if() {
+    let mut env = standard_env();
+
+    let input1 = "(if 0 10 20)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(10.0));
+
+    let input2 = "(if #f 10 20)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(20.0));
+
+    let input3 = "(define (id x) x)";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(if (id 1) 10 20)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(10.0));
+}
+
+#[test]
+fn test_truthiness_in_conditionals() {
+    let mut env = standard_env();
+
+    let input1 = "(and 1 2 3)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(3.0));
+
+    let input2 = "(or #f 2 3)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(2.0));
+
+    let input3 = "(cond (#f 1) (7))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(7.0));
+
+    let input4 = "(when 0 1)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(1.0));
+
+    let input5 = "(unless #f 2)";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Number(2.0));
+}
+
+#[test]
+fn test_begin_expression() {
+    let mut env = standard_env();
+
+    let input1 = "(begin 1 2 3)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(3.0));
+
+    let input2 = "(if #t (begin (define x 1) x))";
+    let result2 = eval(input2, &mut env);
+
+    assert!(result2.is_err());
+}
+
+#[test]
+fn test_top_level_begin() {
+    let mut env = standard_env();
+
+    let input1 = "(begin (define x 1) (define y 2) (+ x y))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(3.0));
+
+    let input2 = "(* x y)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(2.0));
+}
+
+#[test]
+fn test_begin_in_body() {
+    let mut env = standard_env();
+
+    let input1 = "(define (f) (begin (define a 1) (define b 2)) (+ a b))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(f)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(3.0));
+}
+
+#[test]
+fn test_do_loop() {
+    let mut env = standard_env();
+
+    let input1 = "(do ((i 0 (+ i 1)) (acc 0 (+ acc i))) ((= i 5) acc))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(10.0));
+
+    let input2 = "(define (sum-to n) (do ((i n (- i 1)) (acc 0 (+ acc i))) ((= i 0) acc)))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(sum-to 100)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(5050.0));
+
+    let input4 = "(do ((i 0 (+ i 1))) ((= i 3)))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Void);
+}
+
+#[test]
+fn test_lambda_expression() {
+    let mut env = standard_env();
+
+    let input1 = "((lambda (x y) (+ x y)) 3 4)";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(7.0));
+
+    let input2 = "(define square (lambda (x) (* x x)))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(square 6)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(36.0));
+}
+
+#[test]
+fn test_rest_parameters() {
+    let mut env = standard_env();
+
+    let input1 = "(define (f a . rest) rest)";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(f 1 2 3)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(
+        result2,
+        Expression::List(vec![Expression::Number(2.0), Expression::Number(3.0)].into())
+    );
+
+    let input3 = "(f 1)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::List(vec![].into()));
+
+    let input4 = "((lambda args args) 1 2)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(
+        result4,
+        Expression::List(vec![Expression::Number(1.0), Expression::Number(2.0)].into())
+    );
+}
+
+#[test]
+fn test_case_lambda() {
+    let mut env = standard_env();
+
+    let input1 = "(define area (case-lambda ((r) (* 3 r r)) ((w h) (* w h)) ((a b . rest) rest)))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(area 2)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(12.0));
+
+    let input3 = "(area 2 5)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(10.0));
+
+    let input4 = "(area 1 2 3)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(
+        result4,
+        Expression::List(vec![Expression::Number(3.0)].into())
+    );
+
+    let input5 = "(area)";
+    let result5 = eval(input5, &mut env);
+
+    assert!(result5.is_err());
+}
+
+#[test]
+fn test_optional_parameters() {
+    let mut env = standard_env();
+
+    let input1 = "(define* (f a (b 10) (c (+ a b))) (+ a b c))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(f 1)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(22.0));
+
+    let input3 = "(f 1 2)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(6.0));
+
+    let input4 = "(f 1 2 3)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(6.0));
+}
+
+#[test]
+fn test_named_parameters() {
+    let mut env = standard_env();
+
+    let input1 = "(define* (area (width: w 1) (height: h 2)) (* w h))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(area)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(2.0));
+
+    let input3 = "(area height: 5 width: 3)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(15.0));
+
+    let input4 = "((lambda* (x (scale: s 10)) (* x s)) 4 scale: 3)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(12.0));
+
+    let input5 = "(area depth: 5)";
+    let result5 = eval(input5, &mut env);
+
+    assert!(result5.is_err());
+}
+
+#[test]
+fn test_procedure_arity_errors() {
+    let mut env = standard_env();
+
+    let input1 = "(define (sum a b) (+ a b))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(sum 1)";
+    let result2 = eval(input2, &mut env);
+
+    let error2 = result2.unwrap_err();
+
+    assert_eq!(error2.kind, ErrorKind::Arity);
+    assert_eq!(
+        error2.to_string(),
+        "Wrong number of arguments to sum: expected 2, got 1"
+    );
+
+    let input3 = "(sum 1 2 3)";
+    let result3 = eval(input3, &mut env);
+
+    let error3 = result3.unwrap_err();
+
+    assert_eq!(error3.kind, ErrorKind::Arity);
+    assert_eq!(
+        error3.to_string(),
+        "Wrong number of arguments to sum: expected 2, got 3"
+    );
+
+    let input4 = "(define (f a . rest) a)";
+    eval(input4, &mut env).unwrap();
+
+    let input5 = "(f)";
+    let result5 = eval(input5, &mut env);
+
+    let error5 = result5.unwrap_err();
+
+    assert_eq!(error5.kind, ErrorKind::Arity);
+    assert_eq!(
+        error5.to_string(),
+        "Wrong number of arguments to f: expected at least 1, got 0"
+    );
+
+    let input6 = "(define g (lambda (x) x))";
+    eval(input6, &mut env).unwrap();
+
+    let input7 = "(g)";
+    let result7 = eval(input7, &mut env);
+
+    let error7 = result7.unwrap_err();
+
+    assert_eq!(error7.kind, ErrorKind::Arity);
+    assert_eq!(
+        error7.to_string(),
+        "Wrong number of arguments to g: expected 1, got 0"
+    );
+}
+
+#[test]
+fn test_builtin_arity_errors() {
+    let mut env = standard_env();
+
+    let input1 = "(pow 2)";
+    let result1 = eval(input1, &mut env);
+
+    let error1 = result1.unwrap_err();
+
+    assert_eq!(error1.kind, ErrorKind::Arity);
+    assert_eq!(
+        error1.to_string(),
+        "Wrong number of arguments to pow: expected 2, got 1"
+    );
+
+    let input2 = "(-)";
+    let result2 = eval(input2, &mut env);
+
+    let error2 = result2.unwrap_err();
+
+    assert_eq!(error2.kind, ErrorKind::Arity);
+    assert_eq!(
+        error2.to_string(),
+        "Wrong number of arguments to -: expected at least 1, got 0"
+    );
+}
+
+#[test]
+fn test_let_expression() {
+    let mut env = standard_env();
+
+    let input1 = "(let ((x 2) (y 3)) (* x y))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(6.0));
+
+    let input2 = "(let loop ((i 0) (acc 1)) (if (= i 5) acc (loop (+ i 1) (* acc 2))))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(32.0));
+}
+
+#[test]
+fn test_tail_recursive_procedure() {
+    let mut env = standard_env();
+
+    let input1 = "(define (count n acc) (if (= n 0) acc (count (- n 1) (+ acc 1))))";
+    eval(input1, &mut env).unwrap();
+
+    // Far deeper than the native stack would allow for non-tail calls
+    let input2 = "(count 50000 0)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(50000.0));
+}
+
+#[test]
+fn test_tail_calls_in_special_forms() {
+    let mut env = standard_env();
+
+    let input1 = "(define (count n)
+                    (cond ((= n 0) 0)
+                          (else (let ((m (- n 1)))
+                                  (begin (and #t (or #f (when #t (count m)))))))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(count 20000)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(0.0));
+
+    let input3 = "(let loop ((i 0)) (if (< i 50000) (loop (+ i 1)) i))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(50000.0));
+}
+
+#[test]
+fn test_define_syntax() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax swap-args (syntax-rules () ((_ f a b) (f b a))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(swap-args - 1 10)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(9.0));
+}
+
+#[test]
+fn test_syntax_rules_ellipsis() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax my-let
+                    (syntax-rules ()
+                      ((_ ((name value) ...) body1 body2 ...)
+                       ((lambda (name ...) body1 body2 ...) value ...))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(my-let ((x 2) (y 3)) (* x y))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(6.0));
+}
+
+#[test]
+fn test_syntax_rules_nested_ellipsis() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax sum-all (syntax-rules () ((_ (a ...) ...) (+ a ... ...))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(sum-all (1 2) (3) (4 5 6))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(21.0));
+
+    let input3 = "(define-syntax products
+                    (syntax-rules () ((_ (f a ...) ...) (+ (* f a ...) ...))))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(products (1 2 3) (4 5))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(26.0));
+}
+
+#[test]
+fn test_syntax_rules_literals() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax choose
+                    (syntax-rules (first second)
+                      ((_ first a b) a)
+                      ((_ second a b) b)))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(choose second 1 2)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(2.0));
+
+    let input3 = "(choose third 1 2)";
+    let result3 = eval(input3, &mut env);
+
+    assert!(result3.is_err());
+}
+
+#[test]
+fn test_syntax_rules_custom_ellipsis() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax my-list-sum (syntax-rules ::: () ((_ x :::) (+ x :::))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(my-list-sum 1 2 3 4)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(10.0));
+}
+
+#[test]
+fn test_recursive_macro() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax my-and
+                    (syntax-rules ()
+                      ((_) #t)
+                      ((_ e) e)
+                      ((_ e1 e2 ...) (if e1 (my-and e2 ...) #f))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(my-and 1 2 3)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(3.0));
+
+    let input3 = "(my-and 1 #f 3)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Bool(false));
+}
+
+#[test]
+fn test_macro_hygiene() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax my-or
+                    (syntax-rules ()
+                      ((_) #f)
+                      ((_ e) e)
+                      ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define t 5)";
+    eval(input2, &mut env).unwrap();
+
+    // The `t` bound by the macro must not capture the user's `t`
+    let input3 = "(my-or #f t)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(5.0));
+
+    let input4 = "(let ((t 7)) (my-or #f t))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(7.0));
+
+    // Nor may the user's locals capture the free identifiers of a template
+    let input5 = "(define-syntax first (syntax-rules () ((_ x) (car x))))";
+    eval(input5, &mut env).unwrap();
+
+    let input6 = "(let ((car cdr)) (first '(1 2)))";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(result6, Expression::Number(1.0));
+
+    let input7 = "(define (second car) (first (cdr car)))";
+    eval(input7, &mut env).unwrap();
+
+    let input8 = "(second '(1 2))";
+    let result8 = eval(input8, &mut env).unwrap();
+
+    assert_eq!(result8, Expression::Number(2.0));
+
+    // A local variable named like a special form is an ordinary variable
+    let input9 = "(let ((if list)) (if 1 2))";
+    let result9 = eval(input9, &mut env).unwrap();
+
+    assert_eq!(result9, parse("(1 2)").unwrap());
+}
+
+#[test]
+fn test_let_syntax() {
+    let mut env = standard_env();
+
+    let input1 = "(let ((x 1))
+                    (let-syntax ((add-x (syntax-rules () ((_ e) (+ e x)))))
+                      (add-x 10)))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(11.0));
+
+    // The `x` of the template is the one around the macro, not the one
+    // around its use
+    let input2 = "(let ((x 1))
+                    (let-syntax ((get-x (syntax-rules () ((_) x))))
+                      (let ((x 2))
+                        (get-x))))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(1.0));
+}
+
+#[test]
+fn test_letrec_syntax() {
+    let mut env = standard_env();
+
+    let input1 = "(letrec-syntax ((my-or (syntax-rules ()
+                                           ((_) #f)
+                                           ((_ e) e)
+                                           ((_ e r ...) (if e e (my-or r ...))))))
+                    (my-or #f #f 4))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(4.0));
+}
+
+#[test]
+fn test_internal_define_syntax() {
+    let mut env = standard_env();
+
+    let input1 = "(define (f x)
+                    (define-syntax double (syntax-rules () ((_ e) (* 2 e))))
+                    (define y (double x))
+                    (+ y 1))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(f 5)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(11.0));
+}
+
+#[test]
+fn test_quote() {
+    let mut env = standard_env();
+
+    let input1 = "(quote (a b 1))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(
+        result1,
+        Expression::List(
+            vec![
+                Expression::Symbol("a".into()),
+                Expression::Symbol("b".into()),
+                Expression::Number(1.0),
+            ]
+            .into()
+        )
+    );
+
+    let input2 = "(symbol? 'a)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Bool(true));
+}
+
+#[test]
+fn test_syntax_case() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax my-or
+                    (lambda (stx)
+                      (syntax-case stx ()
+                        ((_) #'#f)
+                        ((_ e) #'e)
+                        ((_ e r ...) #'(let ((t e)) (if t t (my-or r ...)))))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define t 5)";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(my-or #f t)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(5.0));
+
+    let input4 = "(my-or)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Bool(false));
+}
+
+#[test]
+fn test_syntax_case_fenders_and_literals() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax bind
+                    (lambda (stx)
+                      (syntax-case stx (to)
+                        ((_ name to val body) (identifier? #'name)
+                         #'(let ((name val)) body)))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(bind x to 3 (* x x))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(9.0));
+
+    // The fender rejects a name that is not an identifier
+    let input3 = "(bind 1 to 3 4)";
+    let result3 = eval(input3, &mut env).unwrap_err();
+
+    assert_eq!(result3.kind, ErrorKind::Syntax);
+    assert!(result3.message.contains("No matching syntax-case clause"));
+
+    // `to` is a literal and must appear as is
+    let input4 = "(bind x from 3 x)";
+    assert!(eval(input4, &mut env).is_err());
+}
+
+#[test]
+fn test_syntax_case_shadowed_literals() {
+    let mut env = standard_env();
+
+    let input1 = "(define-syntax is-else
+                    (lambda (stx)
+                      (syntax-case stx ()
+                        ((_ id) (if (free-identifier=? #'id #'else) #''yes #''no)))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(is-else else)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Symbol("yes".into()));
+
+    // A local `else` is a different binding from the one the macro knows
+    let input3 = "(let ((else 1)) (is-else else))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Symbol("no".into()));
+
+    let input4 = "(define-syntax lit
+                    (lambda (stx)
+                      (syntax-case stx (=>)
+                        ((_ => e) #'e)
+                        ((_ other e) #''no))))";
+    eval(input4, &mut env).unwrap();
+
+    let input5 = "(lit => 5)";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Number(5.0));
+
+    let input6 = "(let ((=> 1)) (lit => 5))";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(result6, Expression::Symbol("no".into()));
+
+    // So does a local `else` bound by the transformer's own expansion
+    let input7 = "(define-syntax with-else
+                    (syntax-rules ()
+                      ((_ e) (let ((else 2)) (is-else else)))))";
+    eval(input7, &mut env).unwrap();
+
+    let input8 = "(with-else 0)";
+    let result8 = eval(input8, &mut env).unwrap();
+
+    assert_eq!(result8, Expression::Symbol("no".into()));
+}
+
+#[test]
+fn test_syntax_case_computes_expansion() {
+    let mut env = standard_env();
+
+    // Fold additions of literal numbers at expansion time
+    let input1 = "(define-syntax fold-add
+                    (lambda (stx)
+                      (syntax-case stx ()
+                        ((_ a b) (and (number? (syntax->datum #'a))
+                                      (number? (syntax->datum #'b)))
+                         (+ (syntax->datum #'a) (syntax->datum #'b)))
+                        ((_ a b) #'(+ a b)))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(fold-add 2 3)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(5.0));
+
+    let input3 = "(define x 4)";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(fold-add x 1)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(5.0));
+}
+
+#[test]
+fn test_datum_to_syntax_and_with_syntax() {
+    let mut env = standard_env();
+
+    // `it` takes the lexical context of the macro use, so the body can see it
+    let input1 = "(define-syntax with-it
+                    (lambda (stx)
+                      (syntax-case stx ()
+                        ((k val body)
+                         (with-syntax ((it (datum->syntax #'k 'it)))
+                           #'(let ((it val)) body))))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(with-it 5 (* it 2))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(10.0));
+
+    let input3 = "(define-syntax same-name?
+                    (lambda (stx)
+                      (syntax-case stx ()
+                        ((_ a b) (if (free-identifier=? #'a #'b) #'#t #'#f)))))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(same-name? car car)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Bool(true));
+
+    let input5 = "(same-name? car cdr)";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Bool(false));
+}
+
+#[test]
+fn test_quasiquote() {
+    let mut env = standard_env();
+
+    let input1 = "(define xs (list 2 3))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(cons 1 `(,(+ 1 1) ,@xs 4))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(
+        result2,
+        Expression::List(
+            vec![
+                Expression::Number(1.0),
+                Expression::Number(2.0),
+                Expression::Number(2.0),
+                Expression::Number(3.0),
+                Expression::Number(4.0),
+            ]
+            .into()
+        )
+    );
+}
+
+#[test]
+fn test_define_macro() {
+    let mut env = standard_env();
+
+    let input1 = "(define-macro (my-unless test . body)
+                    `(if ,test #f (begin ,@body)))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(my-unless (> 1 2) 1 2)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(2.0));
+
+    // define-macro is not hygienic, so `it` is visible to the body
+    let input3 = "(define-macro (aif test then else)
+                    `(let ((it ,test)) (if it ,then ,else)))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(aif (+ 1 2) (* it 10) 0)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(30.0));
+}
+
+#[test]
+fn test_define_macro_with_gensym() {
+    let mut env = standard_env();
+
+    let input1 = "(define-macro (my-or2 a b)
+                    (let ((tmp (gensym)))
+                      `(let ((,tmp ,a)) (if ,tmp ,tmp ,b))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define tmp 5)";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(my-or2 #f tmp)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(5.0));
+
+    let input4 = "(symbol? (gensym))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Bool(true));
+}
+
+#[test]
+fn test_macroexpand() {
+    let mut env = standard_env();
+
+    let input1 = "(define-macro (my-when test . body) `(if ,test (begin ,@body) #f))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define-syntax my-when2 (syntax-rules () ((_ t e) (my-when t e))))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(macroexpand-1 '(my-when2 a b))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3.to_string(), "(my-when a b)");
+
+    let input4 = "(macroexpand '(my-when2 a b))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4.to_string(), "(if a (begin b) false)");
+
+    // Forms that are not macro uses are returned as they are
+    let input5 = "(macroexpand '(+ 1 2))";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5.to_string(), "(+ 1 2)");
+}
+
+#[test]
+fn test_call_cc_escape() {
+    let mut env = standard_env();
+
+    let input1 = "(+ 1 (call/cc (lambda (k) (+ 10 (k 2)))))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(3.0));
+
+    // Leave a deep, non-tail recursion as soon as a zero is found
+    let input2 = "(define (product lst)
+                    (call-with-current-continuation
+                      (lambda (return)
+                        (let loop ((l lst))
+                          (cond ((null? l) 1)
+                                ((= (car l) 0) (return 0))
+                                (else (* (car l) (loop (cdr l)))))))))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(product (list 1 2 3 4))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(24.0));
+
+    let input4 = "(product (list 1 2 0 x))";
+    let result4 = eval(input4, &mut env);
+
+    assert!(result4.is_err());
+
+    let input5 = "(product (list 1 2 0 4))";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Number(0.0));
+}
+
+#[test]
+fn test_call_cc_reentry() {
+    let mut env = standard_env();
+
+    // Resuming the continuation runs the rest of the body again each time
+    let input1 = "(begin
+                    (define count 0)
+                    (define k (call/cc (lambda (c) c)))
+                    (define count (+ count 1))
+                    (if (< count 3) (k k) count))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(3.0));
+
+    // A continuation can be resumed after the expression that captured it returned
+    let input2 = "(define k (call/cc (lambda (c) c)))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(k 5)";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(+ k 1)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(6.0));
+}
+
+#[test]
+fn test_set() {
+    let mut env = standard_env();
+
+    let input1 = "(define x 1)";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(let () (set! x (+ x 1)) x)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(2.0));
+
+    let input3 = "(set! undefined-var 1)";
+    let result3 = eval(input3, &mut env);
+
+    assert!(result3.is_err());
+}
+
+#[test]
+fn test_dynamic_wind() {
+    let mut env = standard_env();
+
+    let input1 = "(begin
+                    (define trace (list))
+                    (define (note x) (set! trace (cons x trace))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(dynamic-wind
+                    (lambda () (note 'in))
+                    (lambda () (note 'during) 42)
+                    (lambda () (note 'out)))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(42.0));
+
+    let input3 = "(begin trace)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(
+        result3,
+        Expression::List(
+            vec![
+                Expression::Symbol("out".into()),
+                Expression::Symbol("during".into()),
+                Expression::Symbol("in".into()),
+            ]
+            .into()
+        )
+    );
+}
+
+#[test]
+fn test_dynamic_wind_with_continuations() {
+    let mut env = standard_env();
+
+    let input1 = "(begin
+                    (define trace (list))
+                    (define (note x) (set! trace (cons x trace))))";
+    eval(input1, &mut env).unwrap();
+
+    // Escaping runs the after thunk
+    let input2 = "(call/cc
+                    (lambda (k)
+                      (dynamic-wind
+                        (lambda () (note 'in))
+                        (lambda () (k 1) (note 'unreached))
+                        (lambda () (note 'out)))))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(1.0));
+
+    let input3 = "(begin trace)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(
+        result3,
+        Expression::List(
+            vec![
+                Expression::Symbol("out".into()),
+                Expression::Symbol("in".into()),
+            ]
+            .into()
+        )
+    );
+
+    // Re-entering runs the before thunk again
+    let input4 = "(begin
+                    (set! trace (list))
+                    (define k #f)
+                    (define n 0)
+                    (dynamic-wind
+                      (lambda () (note 'in))
+                      (lambda () (call/cc (lambda (c) (set! k c))))
+                      (lambda () (note 'out))))";
+    eval(input4, &mut env).unwrap();
+
+    let input5 = "(begin (set! n (+ n 1)) (if (< n 2) (k 'again)))";
+    eval(input5, &mut env).unwrap();
+
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(
+        result3,
+        Expression::List(
+            vec![
+                Expression::Symbol("out".into()),
+                Expression::Symbol("in".into()),
+                Expression::Symbol("out".into()),
+                Expression::Symbol("in".into()),
+            ]
+            .into()
+        )
+    );
+}
+
+#[test]
+fn test_dynamic_wind_unwinds_errors() {
+    let mut env = standard_env();
+
+    let input1 = "(define trace (list))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(dynamic-wind
+                    (lambda () #t)
+                    (lambda () (undefined-fn))
+                    (lambda () (set! trace (cons 'out trace))))";
+    let result2 = eval(input2, &mut env);
+
+    assert!(result2.is_err());
+
+    let input3 = "(begin trace)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(
+        result3,
+        Expression::List(vec![Expression::Symbol("out".into())].into())
+    );
+}
+
+#[test]
+fn test_reset_shift() {
+    let mut env = standard_env();
+
+    let input1 = "(+ 1 (reset (+ 10 (shift k (k (k 100))))))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(121.0));
+
+    // The body of shift replaces the whole reset
+    let input2 = "(+ 1 (reset (+ 10 (shift k 5))))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(6.0));
+
+    // A captured continuation composes with the one it is called from
+    let input3 = "(define add-one (reset (+ 1 (shift k k))))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(* 2 (add-one 10))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(22.0));
+
+    let input5 = "(+ 1 (shift k 1))";
+    let result5 = eval(input5, &mut env);
+
+    assert!(result5.is_err());
+}
+
+#[test]
+fn test_continuation_prompts() {
+    let mut env = standard_env();
+
+    let input1 = "(define tag (make-continuation-prompt-tag 'demo))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(call-with-continuation-prompt
+                    (lambda () (+ 1 (abort-current-continuation tag 5 6)))
+                    tag
+                    (lambda (a b) (* a b)))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(30.0));
+
+    // An abort skips the prompts with other tags
+    let input3 = "(call-with-continuation-prompt
+                    (lambda () (reset (+ 1 (abort-current-continuation tag 2 3))))
+                    tag
+                    +)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(5.0));
+
+    let input4 = "(call-with-continuation-prompt
+                    (lambda () (+ 1 (abort-current-continuation (default-continuation-prompt-tag) 7))))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(7.0));
+
+    let input5 = "(abort-current-continuation tag 1)";
+    let result5 = eval(input5, &mut env);
+
+    assert!(result5.is_err());
+}
+
+#[test]
+fn test_delimited_continuations_with_dynamic_wind() {
+    let mut env = standard_env();
+
+    let input1 = "(begin
+                    (define trace (list))
+                    (define (note x) (set! trace (cons x trace))))";
+    eval(input1, &mut env).unwrap();
+
+    // Capturing leaves the dynamic-wind and calling the continuation enters it again
+    let input2 = "(define k
+                    (reset
+                      (dynamic-wind
+                        (lambda () (note 'in))
+                        (lambda () (shift k (note 'shift) k))
+                        (lambda () (note 'out)))))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(k 1)";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Number(1.0));
+
+    let input4 = "(begin trace)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(
+        result4,
+        Expression::List(
+            vec![
+                Expression::Symbol("out".into()),
+                Expression::Symbol("in".into()),
+                Expression::Symbol("shift".into()),
+                Expression::Symbol("out".into()),
+                Expression::Symbol("in".into()),
+            ]
+            .into()
+        )
+    );
+
+    // Aborting leaves it too
+    let input5 = "(begin
+                    (set! trace (list))
+                    (call-with-continuation-prompt
+                      (lambda ()
+                        (dynamic-wind
+                          (lambda () (note 'in))
+                          (lambda () (abort-current-continuation (default-continuation-prompt-tag) 'aborted))
+                          (lambda () (note 'out))))))";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Symbol("aborted".into()));
+
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(
+        result4,
+        Expression::List(
+            vec![
+                Expression::Symbol("out".into()),
+                Expression::Symbol("in".into()),
+            ]
+            .into()
+        )
+    );
+}
+
+#[test]
+fn test_guard() {
+    let mut env = standard_env();
+
+    let input1 = "(guard (e ((symbol? e) (list 'caught e))) (+ 1 (raise 'oops)))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(
+        result1,
+        Expression::List(
+            vec![
+                Expression::Symbol("caught".into()),
+                Expression::Symbol("oops".into()),
+            ]
+            .into()
+        )
+    );
+
+    let input2 = "(guard (e ((error-object? e)
+                             (cons (error-object-message e) (error-object-irritants e))))
+                    (error 'bad-thing 1 2))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(
+        result2,
+        Expression::List(
+            vec![
+                Expression::String("bad-thing".to_string()),
+                Expression::Number(1.0),
+                Expression::Number(2.0),
+            ]
+            .into()
+        )
+    );
+
+    // Without a matching clause the object goes to the enclosing guard
+    let input3 = "(guard (e ((symbol? e) 'outer))
+                    (guard (e ((number? e) 'inner))
+                      (raise 'x)))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Symbol("outer".into()));
+
+    let input4 = "(guard (e ((number? e) e)) (raise 'x))";
+    let result4 = eval(input4, &mut env);
+
+    assert!(result4.is_err());
+
+    let input5 = "(error 'bad-thing 1 2)";
+    let result5 = eval(input5, &mut env);
+
+    let error5 = result5.unwrap_err();
+
+    assert_eq!(error5.kind, ErrorKind::User);
+    assert_eq!(error5.to_string(), "bad-thing 1 2");
+
+    // An `else` clause catches whatever the other clauses don't
+    let input6 = "(guard (e ((string? e) 's) (else 'other)) (raise 1))";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(result6, Expression::Symbol("other".into()));
+
+    let input7 = "(guard (e (else (error-object-message e))) (error \"x\"))";
+    let result7 = eval(input7, &mut env).unwrap();
+
+    assert_eq!(result7, Expression::String("x".to_string()));
+
+    // Without one, the object is raised again where it was first raised, so
+    // the handler's value goes back to `raise-continuable` inside the guard
+    let input8 = "(define trace (list))";
+    let input9 = "(define (note x) (set! trace (cons x trace)))";
+    let input10 = "(with-exception-handler
+                     (lambda (e) (note 'handler) (* e 2))
+                     (lambda ()
+                       (guard (e ((string? e) 's))
+                         (dynamic-wind
+                           (lambda () (note 'in))
+                           (lambda () (+ 1 (raise-continuable 5)))
+                           (lambda () (note 'out))))))";
+    let input11 = "trace";
+    eval(input8, &mut env).unwrap();
+    eval(input9, &mut env).unwrap();
+    let result10 = eval(input10, &mut env).unwrap();
+    let result11 = eval(input11, &mut env).unwrap();
+
+    assert_eq!(result10, Expression::Number(11.0));
+    assert_eq!(result11, parse("(out handler in out in)").unwrap());
+
+    // A handler returning from a non-continuable `raise` is still an error
+    let input12 = "(with-exception-handler
+                     (lambda (e) 10)
+                     (lambda () (guard (e ((string? e) 's)) (raise 5))))";
+    let result12 = eval(input12, &mut env).unwrap_err();
+
+    assert_eq!(result12.kind, ErrorKind::Raise);
+}
+
+#[test]
+fn test_guard_catches_evaluator_errors() {
+    let mut env = standard_env();
+
+    let input1 = "(guard (e ((error-object? e) 'caught)) (undefined-fn))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Symbol("caught".into()));
+
+    // Type errors from builtins are error objects too
+    let input2 = "(guard (e ((file-error? e) 'file)
+                            ((read-error? e) 'read)
+                            ((error-object? e) 'type))
+                    (+ 1 (list 1 2)))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Symbol("type".into()));
+
+    // Leaving the body through a guard runs the after thunks on the way
+    let input3 = "(define trace (list))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(guard (e (#t (cons 'caught trace)))
+                    (dynamic-wind
+                      (lambda () #t)
+                      (lambda () (raise 'boom))
+                      (lambda () (set! trace (cons 'out trace)))))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(
+        result4,
+        Expression::List(
+            vec![
+                Expression::Symbol("caught".into()),
+                Expression::Symbol("out".into()),
+            ]
+            .into()
+        )
+    );
+}
+
+#[test]
+fn test_with_exception_handler() {
+    let mut env = standard_env();
+
+    let input1 = "(with-exception-handler
+                    (lambda (c) 42)
+                    (lambda () (+ (raise-continuable 'c) 1)))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Number(43.0));
+
+    // A handler sees the handlers installed outside of its own
+    let input2 = "(with-exception-handler
+                    (lambda (e) 10)
+                    (lambda ()
+                      (with-exception-handler
+                        (lambda (e) (+ 1 (raise-continuable e)))
+                        (lambda () (raise-continuable 'x)))))";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(11.0));
+
+    let input3 = "(call/cc
+                    (lambda (k)
+                      (with-exception-handler
+                        (lambda (e) (k (list 'handled e)))
+                        (lambda () (raise 'boom)))))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(
+        result3,
+        Expression::List(
+            vec![
+                Expression::Symbol("handled".into()),
+                Expression::Symbol("boom".into()),
+            ]
+            .into()
+        )
+    );
+
+    // Returning from the handler of a non-continuable raise is an error
+    let input4 = "(with-exception-handler (lambda (e) 0) (lambda () (raise 'boom)))";
+    let result4 = eval(input4, &mut env);
+
+    assert!(result4.is_err());
+}
+
+#[test]
+fn test_builtin_errors() {
+    let mut env = standard_env();
+
+    let input1 = "(define foo-list (list 1 2))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(+ 1 foo-list)";
+    let result2 = eval(input2, &mut env);
+
+    let error2 = result2.unwrap_err();
+
+    assert_eq!(error2.kind, ErrorKind::Type);
+    assert_eq!(error2.to_string(), "+: Expected a number: (1 2)");
+    assert_eq!(
+        error2.irritants,
+        vec![Expression::List(
+            vec![Expression::Number(1.0), Expression::Number(2.0)].into()
+        )]
+    );
+
+    let input3 = "(/ 1 0)";
+    let result3 = eval(input3, &mut env);
+
+    let error3 = result3.unwrap_err();
+
+    assert_eq!(error3.kind, ErrorKind::DivideByZero);
+    assert_eq!(error3.to_string(), "/: Cannot divide by zero");
+
+    let input4 = "(- foo-list 1)";
+    let result4 = eval(input4, &mut env);
+
+    assert!(result4.is_err());
+
+    let input5 = "(car (list))";
+    let result5 = eval(input5, &mut env);
+
+    assert!(result5.is_err());
+
+    // The environment is still usable afterwards
+    let input6 = "(+ 1 (car foo-list))";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(result6, Expression::Number(2.0));
+}
+
+#[test]
+fn test_error_kinds() {
+    let mut env = standard_env();
+
+    let input1 = "(+ 1 undefined-var)";
+    let error1 = eval(input1, &mut env).unwrap_err();
+
+    assert_eq!(error1.kind, ErrorKind::UnboundVariable);
+    assert_eq!(
+        error1.irritants,
+        vec![Expression::Symbol("undefined-var".into())]
+    );
+    assert_eq!(error1.to_string(), "Undefined symbol: undefined-var");
+
+    let input2 = "(1 2)";
+    let error2 = eval(input2, &mut env).unwrap_err();
+
+    assert_eq!(error2.kind, ErrorKind::Type);
+
+    let input3 = "(if)";
+    let error3 = eval(input3, &mut env).unwrap_err();
+
+    assert_eq!(error3.kind, ErrorKind::Syntax);
+
+    let input4 = "(raise 'oops)";
+    let error4 = eval(input4, &mut env).unwrap_err();
+
+    assert_eq!(error4.kind, ErrorKind::Raise);
+    assert_eq!(error4.irritants, vec![Expression::Symbol("oops".into())]);
+
+    // Errors work with the standard error machinery
+    let error: Box<dyn std::error::Error> = Box::new(error4);
+
+    assert_eq!(error.to_string(), "Uncaught exception: oops");
+}
+
+#[test]
+fn test_parse_errors() {
+    let mut env = standard_env();
+
+    let input1 = "(define x (+ 1 2)";
+    let error1 = eval(input1, &mut env).unwrap_err();
+
+    assert_eq!(error1.kind, ErrorKind::Parse);
+    assert_eq!(error1.to_string(), "Unclosed parenthesis");
+    assert_eq!(
+        error1.span.map(|span| (span.line, span.column)),
+        Some((1, 1))
+    );
+
+    let input2 = "(+ 1 2))";
+    let error2 = eval(input2, &mut env).unwrap_err();
+
+    assert_eq!(error2.kind, ErrorKind::Parse);
+    assert_eq!(error2.span.map(|span| span.start), Some(7));
+
+    let input3 = "(list 1\n  ')";
+    let error3 = eval(input3, &mut env).unwrap_err();
+
+    assert_eq!(error3.kind, ErrorKind::Parse);
+    assert_eq!(
+        error3.span.map(|span| (span.line, span.column)),
+        Some((2, 4))
+    );
+
+    // The environment is still usable, and a single datum is a program too
+    let input4 = "(define x 5)";
+    eval(input4, &mut env).unwrap();
+
+    let input5 = "x";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Number(5.0));
+}
+
+#[test]
+fn test_error_call_stack() {
+    let mut env = standard_env();
+
+    let input1 = "(begin
+                    (define (inner x) (+ x y))
+                    (define (middle x) (* 2 (inner x)))
+                    (define (outer x) (+ 1 (middle x))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(outer 1)";
+    let error2 = eval(input2, &mut env).unwrap_err();
+
+    assert_eq!(error2.to_string(), "Undefined symbol: y");
+    assert_eq!(
+        error2.stack,
+        vec![
+            "inner".to_string(),
+            "middle".to_string(),
+            "outer".to_string()
+        ]
+    );
+
+    // A tail call takes the place of its caller
+    let input3 = "(define (tail x) (middle x))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(tail 1)";
+    let error4 = eval(input4, &mut env).unwrap_err();
+
+    assert_eq!(
+        error4.stack,
+        vec!["inner".to_string(), "middle".to_string()]
+    );
+
+    // Builtins that fail are on the stack too
+    let input5 = "(define (first-of lst) (car lst))";
+    eval(input5, &mut env).unwrap();
+
+    let input6 = "(+ 1 (first-of (list)))";
+    let error6 = eval(input6, &mut env).unwrap_err();
+
+    assert_eq!(
+        error6.stack,
+        vec!["car".to_string(), "first-of".to_string()]
+    );
+}
+
+#[test]
+fn test_bytecode_engine() {
+    // Both engines give the same results for the same programs
+    for engine in [Engine::TreeWalker, Engine::Bytecode] {
+        let mut env = standard_env();
+
+        let input1 = "(define (fact n) (if (<= n 1) 1 (* n (fact (- n 1)))))";
+        let input2 = "(fact 10)";
+        eval_with(input1, &mut env, engine).unwrap();
+        let result2 = eval_with(input2, &mut env, engine).unwrap();
+        assert_eq!(result2, Expression::Number(3628800.0));
+
+        // Tail calls run in constant space
+        let input3 = "(let loop ((i 0) (acc 0)) (if (= i 10000) acc (loop (+ i 1) (+ acc i))))";
+        let result3 = eval_with(input3, &mut env, engine).unwrap();
+        assert_eq!(result3, Expression::Number(49995000.0));
+
+        let input4 = "(define (make-counter)
+                        (define count 0)
+                        (lambda () (set! count (+ count 1)) count))";
+        let input5 = "(define counter (make-counter))";
+        let input6 = "(begin (counter) (counter) (counter))";
+        eval_with(input4, &mut env, engine).unwrap();
+        eval_with(input5, &mut env, engine).unwrap();
+        let result6 = eval_with(input6, &mut env, engine).unwrap();
+        assert_eq!(result6, Expression::Number(3.0));
+
+        let input7 = "(list (cond ((> 1 2) 'a) ((car (list 5)) => (lambda (x) (* x 2))))
+                             (case (* 2 3) ((2 3 5 7) 'prime) ((1 4 6 8 9) 'composite))
+                             (and 1 2) (or #f 3) (unless #f 4)
+                             (do ((i 0 (+ i 1)) (acc (list) (cons i acc))) ((= i 3) acc)))";
+        let result7 = eval_with(input7, &mut env, engine).unwrap();
+        assert_eq!(result7, parse("(10 composite 2 3 4 (2 1 0))").unwrap());
+
+        let input8 = "((lambda (a . rest) (cons a rest)) 1 2 3)";
+        let result8 = eval_with(input8, &mut env, engine).unwrap();
+        assert_eq!(result8, parse("(1 2 3)").unwrap());
+    }
+}
+
+#[test]
+fn test_bytecode_engine_falls_back() {
+    let mut env = standard_env();
+
+    // Forms the compiler doesn't handle run on the tree-walker, and the two
+    // kinds of procedures can call each other
+    let input1 = "(define (square x) (* x x))";
+    eval_with(input1, &mut env, Engine::Bytecode).unwrap();
+
+    let input2 = "(call/cc (lambda (k) (square (k 7))))";
+    let result2 = eval_with(input2, &mut env, Engine::Bytecode).unwrap();
+    assert_eq!(result2, Expression::Number(7.0));
+
+    let input3 = "(define* (scale x (factor 3)) (square (* x factor)))";
+    eval_with(input3, &mut env, Engine::Bytecode).unwrap();
+
+    let input4 = "(map-square (scale 1))";
+    let input5 = "(define (map-square x) (square x))";
+    eval_with(input5, &mut env, Engine::Bytecode).unwrap();
+    let result4 = eval_with(input4, &mut env, Engine::Bytecode).unwrap();
+    assert_eq!(result4, Expression::Number(81.0));
+
+    let input6 = "(guard (e (#t (error-object-message e))) (square 'a))";
+    let result6 = eval_with(input6, &mut env, Engine::Bytecode).unwrap();
+    assert_eq!(
+        result6,
+        Expression::String("*: Expected a number:".to_string())
+    );
+
+    let input7 = "(guard (e (#t (error-object-irritants e))) (square 'a))";
+    let result7 = eval_with(input7, &mut env, Engine::Bytecode).unwrap();
+    assert_eq!(
+        result7,
+        Expression::List(vec![Expression::Symbol("a".into())].into())
+    );
+
+    // The strict engine reports those forms instead, naming the one it
+    // stopped at, and runs the programs it compiles as usual
+    let input8 = "(+ 1 (guard (e (#t 0)) (square 'a)))";
+    let result8 = eval_with(input8, &mut env, Engine::BytecodeOnly).unwrap_err();
+    assert_eq!(result8.kind, ErrorKind::Unsupported);
+    assert_eq!(
+        result8.to_string(),
+        "The bytecode compiler does not handle: guard"
+    );
+    assert_eq!(result8.span.map(|span| span.start), Some(0));
+
+    let input9 = "(define (pair x) `(,x ,(square x)))";
+    let result9 = eval_with(input9, &mut env, Engine::BytecodeOnly).unwrap_err();
+    assert_eq!(result9.kind, ErrorKind::Unsupported);
+
+    // Nothing was defined by the program that was refused
+    let input10 = "(pair 2)";
+    let result10 = eval_with(input10, &mut env, Engine::Bytecode).unwrap_err();
+    assert_eq!(result10.kind, ErrorKind::UnboundVariable);
+
+    let input11 = "(map-square 4)";
+    let result11 = eval_with(input11, &mut env, Engine::BytecodeOnly).unwrap();
+    assert_eq!(result11, Expression::Number(16.0));
+}
+
+#[test]
+fn test_bytecode_engine_control() {
+    for engine in [Engine::TreeWalker, Engine::Bytecode] {
+        let mut env = standard_env();
+
+        // Compiled procedures can be given the procedures that work on the
+        // evaluator's state, such as call/cc, and the results are the same
+        let input1 = "(define (app f x) (f x))";
+        eval_with(input1, &mut env, engine).unwrap();
+
+        let input2 = "(app call/cc (lambda (k) (k 1)))";
+        let result2 = eval_with(input2, &mut env, engine).unwrap();
+        assert_eq!(result2, Expression::Number(1.0));
+
+        let input3 = "(with-exception-handler
+                        (lambda (e) 42)
+                        (lambda () (+ 1 (app raise-continuable 'c))))";
+        let result3 = eval_with(input3, &mut env, engine).unwrap();
+        assert_eq!(result3, Expression::Number(43.0));
+
+        // A continuation captured under compiled code can be re-entered
+        let input4 = "(let ((n 0) (again #f))
+                        (let ((v (+ 1 (app call/cc (lambda (k) (set! again k) 0)))))
+                          (set! n (+ n 1))
+                          (if (< v 3) (again v) (list v n))))";
+        let result4 = eval_with(input4, &mut env, engine).unwrap();
+        assert_eq!(result4, parse("(3 3)").unwrap());
+
+        let input5 = "(let ((trail '()))
+                        (dynamic-wind
+                          (lambda () (set! trail (cons 'in trail)))
+                          (lambda () (app (lambda (x) (set! trail (cons x trail))) 'body))
+                          (lambda () (set! trail (cons 'out trail))))
+                        trail)";
+        let result5 = eval_with(input5, &mut env, engine).unwrap();
+        assert_eq!(result5, parse("(out body in)").unwrap());
+
+        let input6 = "(call-with-continuation-prompt
+                        (lambda () (+ 1 (app (lambda (v) (abort-current-continuation 'tag v)) 5)))
+                        'tag
+                        (lambda (v) (* v 10)))";
+        let result6 = eval_with(input6, &mut env, engine).unwrap();
+        assert_eq!(result6, Expression::Number(50.0));
+
+        let input7 = "(guard (e (#t (list 'caught e))) (app raise 'oops))";
+        let result7 = eval_with(input7, &mut env, engine).unwrap();
+        assert_eq!(result7, parse("(caught oops)").unwrap());
+    }
+}
+
+#[test]
+fn test_bytecode_engine_errors() {
+    for engine in [Engine::TreeWalker, Engine::Bytecode] {
+        let mut env = standard_env();
+
+        let input1 = "(begin (define (inner x) (car x))
+                             (define (outer x) (+ 1 (inner x))))";
+        let source1 = next_source();
+        eval_with(input1, &mut env, engine).unwrap();
+
+        let input2 = "(outer 1)";
+        let error2 = eval_with(input2, &mut env, engine).unwrap_err();
+        assert_eq!(error2.kind, ErrorKind::Type);
+        assert_eq!(error2.to_string(), "car: Expected a non-empty list: 1");
+        assert_eq!(
+            error2.stack,
+            vec!["car".to_string(), "inner".to_string(), "outer".to_string()]
+        );
+
+        let input3 = "(inner 1 2)";
+        let error3 = eval_with(input3, &mut env, engine).unwrap_err();
+        assert_eq!(error3.kind, ErrorKind::Arity);
+        assert_eq!(
+            error3.to_string(),
+            "Wrong number of arguments to inner: expected 1, got 2"
+        );
+
+        let input4 = "(undefined-function 1)";
+        let error4 = eval_with(input4, &mut env, engine).unwrap_err();
+        assert_eq!(error4.kind, ErrorKind::UnboundVariable);
+        assert_eq!(error4.to_string(), "Undefined function: undefined-function");
+
+        // Errors point at the innermost call they happen in, in the text
+        // that call was read from
+        let input5 = "(+ 1\n   (inner 1))";
+        let error5 = eval_with(input5, &mut env, engine).unwrap_err();
+        let span5 = error5.span.unwrap();
+        assert_eq!((span5.line, span5.column), (1, 26));
+        assert_eq!(&input1[span5.start..span5.end], "(car x)");
+        assert_eq!(span5.source, source1);
+
+        let input6 = "(+ 1\n   (car 1))";
+        let source6 = next_source();
+        let error6 = eval_with(input6, &mut env, engine).unwrap_err();
+        let span6 = error6.span.unwrap();
+        assert_eq!(span6.source, source6);
+        assert_ne!(source6, source1);
+        assert_eq!((span6.line, span6.column), (2, 4));
+        assert_eq!(&input6[span6.start..span6.end], "(car 1)");
+
+        let input7 = "(list 1 (* 2 y))";
+        let error7 = eval_with(input7, &mut env, engine).unwrap_err();
+        assert_eq!(error7.span.map(|span| span.start), Some(8));
+
+        // Tail calls take the place of their callers in both engines, and a
+        // guard that doesn't catch an error keeps where it happened
+        let input8 = "(begin (define (h) (error \"boom\"))
+                             (define (g) (h))
+                             (define (f) (g))
+                             (define (guarded) (guard (e (#f 1)) (+ 1 (inner 1)))))";
+        eval_with(input8, &mut env, engine).unwrap();
+
+        let input9 = "(f)";
+        let error9 = eval_with(input9, &mut env, engine).unwrap_err();
+        assert_eq!(error9.stack, vec!["h".to_string()]);
+
+        let input10 = "(guarded)";
+        let error10 = eval_with(input10, &mut env, engine).unwrap_err();
+        assert_eq!(
+            error10.stack,
+            vec![
+                "car".to_string(),
+                "inner".to_string(),
+                "guarded".to_string()
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_local_scopes() {
+    for engine in [Engine::TreeWalker, Engine::Bytecode] {
+        let mut env = standard_env();
+
+        // Internal definitions and assignments replace the binding in the
+        // innermost scope, and inner scopes shadow outer ones
+        let input1 = "(define x 'global)";
+        let input2 = "(define (f x)
+                        (define y x)
+                        (define x 2)
+                        (set! y (+ y 10))
+                        (let ((x (* x 100)))
+                          (list x y)))";
+        let input3 = "(list (f 1) x)";
+        eval_with(input1, &mut env, engine).unwrap();
+        eval_with(input2, &mut env, engine).unwrap();
+        let result3 = eval_with(input3, &mut env, engine).unwrap();
+
+        assert_eq!(result3, parse("((200 11) global)").unwrap());
+    }
+}
+
+#[test]
+fn test_resolved_references() {
+    for engine in [Engine::TreeWalker, Engine::Bytecode] {
+        let mut env = standard_env();
+
+        // A definition that doesn't run moves the ones after it to other
+        // slots, and a global defined after a procedure is found when it is
+        // called
+        let input1 = "(define (f c)
+                        (when c (define y 1))
+                        (define z 2)
+                        (list z later))";
+        let input2 = "(define later 3)";
+        let input3 = "(list (f #t) (f #f))";
+        eval_with(input1, &mut env, engine).unwrap();
+        eval_with(input2, &mut env, engine).unwrap();
+        let result3 = eval_with(input3, &mut env, engine).unwrap();
+
+        assert_eq!(result3, parse("((2 3) (2 3))").unwrap());
+
+        // Named lets, do loops, guards, optional parameters and unquoted
+        // expressions each bind variables in scopes of their own
+        let input5 = "(define* (g a (b (* a 2)))
+                        (let loop ((i 0) (acc '()))
+                          (if (= i 2)
+                              (do ((j 0 (+ j 1)) (sum 0 (+ sum j)))
+                                  ((= j a) `(,acc ,sum ,b ,(guard (e (#t (list e a))) (raise i)))))
+                              (loop (+ i 1) (cons i acc)))))";
+        let input6 = "(g 3)";
+        eval_with(input5, &mut env, engine).unwrap();
+        let result6 = eval_with(input6, &mut env, engine).unwrap();
+
+        assert_eq!(result6, parse("((1 0) 3 6 (2 3))").unwrap());
+
+        // An unbound variable in a procedure is still reported by name
+        let input7 = "(define (h y) (+ y missing))";
+        let input8 = "(h 1)";
+        eval_with(input7, &mut env, engine).unwrap();
+        let result8 = eval_with(input8, &mut env, engine).unwrap_err();
+
+        assert_eq!(result8.kind, ErrorKind::UnboundVariable);
+        assert_eq!(result8.irritants, vec![parse("missing").unwrap()]);
+    }
+}
+
+#[test]
+fn test_interned_symbols() {
+    let mut env = standard_env();
+
+    let input1 = "(eq? 'apple (string->symbol \"apple\"))";
+    let result1 = eval(input1, &mut env).unwrap();
+
+    assert_eq!(result1, Expression::Bool(true));
+
+    let input2 = "(symbol->string 'apple)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::String("apple".to_string()));
+
+    let input3 = "(symbol=? 'a 'a (string->symbol \"a\"))";
+    let result3 = eval(input3, &mut env).unwrap();
+
+    assert_eq!(result3, Expression::Bool(true));
+
+    let input4 = "(list (eq? 'a 'b) (eq? 2 2) (eq? '() '()) (string? \"a\") (string? 'a))";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(
+        result4,
+        Expression::List(
+            vec![
+                Expression::Bool(false),
+                Expression::Bool(true),
+                Expression::Bool(true),
+                Expression::Bool(true),
+                Expression::Bool(false),
+            ]
+            .into()
+        )
+    );
+
+    // An uninterned symbol is only the same as itself, even to a symbol made
+    // from its own name
+    let input5 = "(define g (generate-uninterned-symbol))";
+    eval(input5, &mut env).unwrap();
+
+    let input6 = "(list (eq? g g) (eq? g (string->symbol (symbol->string g))) (symbol? g))";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(
+        result6,
+        Expression::List(
+            vec![
+                Expression::Bool(true),
+                Expression::Bool(false),
+                Expression::Bool(true),
+            ]
+            .into()
+        )
+    );
+
+    let input7 = "(eq? (gensym 'x) (gensym 'x))";
+    let result7 = eval(input7, &mut env).unwrap();
+
+    assert_eq!(result7, Expression::Bool(false));
+
+    let input8 = "(string->symbol 'a)";
+    let result8 = eval(input8, &mut env).unwrap_err();
+
+    assert_eq!(result8.kind, ErrorKind::Type);
+
+    // A space would make a symbol look like an identifier renamed by a macro
+    let input9 = "(define-macro (spaced) `(quote ,(string->symbol \"foo 7\")))";
+    eval(input9, &mut env).unwrap();
+
+    let input10 = "(spaced)";
+    let result10 = eval(input10, &mut env).unwrap_err();
+
+    assert_eq!(result10.kind, ErrorKind::Type);
+
+    let input11 = "(generate-uninterned-symbol \"foo 7\")";
+    let result11 = eval(input11, &mut env).unwrap_err();
+
+    assert_eq!(result11.kind, ErrorKind::Type);
+}
+
+#[test]
+fn test_garbage_collection() {
+    let mut env = standard_env();
+
+    // Each call leaves behind a scope that holds a procedure closing over it
+    let input1 = "(define (make-cycle) (define (self) self) self)";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define keep (make-cycle))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(define (churn n) (if (= n 0) 'done (begin (make-cycle) (churn (- n 1)))))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(churn 100)";
+    eval(input4, &mut env).unwrap();
+
+    let input5 = "(>= (gc) 100)";
+    let result5 = eval(input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Bool(true));
+
+    // What is still reachable survives
+    let input6 = "(eq? (keep) keep)";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(result6, Expression::Bool(true));
+
+    let input7 = "(gc)";
+    let result7 = eval(input7, &mut env).unwrap();
+
+    assert_eq!(result7, Expression::Number(0.0));
+
+    // Cycles made by bytecode and through continuations are found too
+    let input8 = "(define (loop n) (let next ((i n)) (if (= i 0) 'done (next (- i 1)))))";
+    eval_with(input8, &mut env, Engine::Bytecode).unwrap();
+
+    let input9 = "(loop 10)";
+    eval_with(input9, &mut env, Engine::Bytecode).unwrap();
+
+    let input10 = "(define (capture) (define k #f) (call/cc (lambda (c) (set! k c))) k)";
+    eval(input10, &mut env).unwrap();
+
+    let input11 = "(capture)";
+    eval(input11, &mut env).unwrap();
+
+    let input12 = "(> (gc) 0)";
+    let result12 = eval(input12, &mut env).unwrap();
+
+    assert_eq!(result12, Expression::Bool(true));
+
+    let input13 = "(car (car (gc-stats)))";
+    let result13 = eval(input13, &mut env).unwrap();
+
+    assert_eq!(result13, Expression::Symbol("objects".into()));
+
+    // Dropping the environment and collecting frees its scopes as well
+    let objects =
+        |env: &mut rustyscm::env::Environment| match eval("(car (cdr (car (gc-stats))))", env)
+            .unwrap()
+        {
+            Expression::Number(n) => n,
+            _ => panic!("gc-stats should report the number of objects"),
+        };
+    let before = objects(&mut env);
+    drop(env);
+
+    let mut env = standard_env();
+    eval("(gc)", &mut env).unwrap();
+
+    assert!(objects(&mut env) < before);
+}
+
+#[test]
+fn test_deep_recursion() {
+    let mut env = standard_env();
+
+    let input1 = "(define (count n) (if (= n 0) 0 (+ 1 (count (- n 1)))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(count 100000)";
+    let result2 = eval(input2, &mut env).unwrap();
+
+    assert_eq!(result2, Expression::Number(100000.0));
+
+    let input3 = "(define (count-down n) (if (= n 0) 0 (+ 1 (count-down (- n 1)))))";
+    eval_with(input3, &mut env, Engine::Bytecode).unwrap();
+
+    let input4 = "(count-down 100000)";
+    let result4 = eval_with(input4, &mut env, Engine::Bytecode).unwrap();
+
+    assert_eq!(result4, Expression::Number(100000.0));
+
+    // Deeply nested expressions are read, expanded, evaluated, compared,
+    // printed and dropped
+    let depth = 50_000;
+    let input5 = format!("{}0{}", "(+ 1 ".repeat(depth), ")".repeat(depth));
+    let result5 = eval(&input5, &mut env).unwrap();
+
+    assert_eq!(result5, Expression::Number(depth as f64));
+
+    let result5 = eval_with(&input5, &mut env, Engine::Bytecode).unwrap();
+
+    assert_eq!(result5, Expression::Number(depth as f64));
+
+    let nested = format!("{}0{}", "(".repeat(depth), ")".repeat(depth));
+    let parsed = parse(&nested).unwrap();
+
+    assert_eq!(parsed, parse(&nested).unwrap());
+    assert_ne!(parsed, parse(&nested.replacen('0', "1", 1)).unwrap());
+    assert_eq!(parsed.to_string(), nested);
+
+    let input6 = format!("(list (quote {0}) (quasiquote {0}))", nested);
+    let result6 = eval(&input6, &mut env).unwrap();
+
+    assert_eq!(
+        result6,
+        Expression::List(vec![parsed.clone(), parsed].into())
+    );
+
+    // Procedures of the two engines calling each other forever, through
+    // the default of an optional parameter that is evaluated on a machine
+    // of its own, run out of depth with an error that can be caught
+    let input7 = "(define (ping) (pong))";
+    eval_with(input7, &mut env, Engine::Bytecode).unwrap();
+
+    let input8 = "(define* (pong (x (ping))) x)";
+    eval(input8, &mut env).unwrap();
+
+    let input9 = "(guard (e (#t (error-object-message e))) (ping))";
+    let result9 = eval(input9, &mut env).unwrap();
+
+    assert_eq!(
+        result9,
+        Expression::String("Maximum recursion depth exceeded".to_string())
+    );
+
+    let input10 = "(ping)";
+    let result10 = eval(input10, &mut env).unwrap_err();
+
+    assert_eq!(result10.kind, ErrorKind::Recursion);
+}
+
+#[test]
+fn test_eval_budget_depth() {
+    let mut env = standard_env();
+
+    // The depth counts procedure calls in progress, in either engine
+    let input1 = "(define (count n) (if (= n 0) 0 (+ 1 (count (- n 1)))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define (count2 n) (if (= n 0) 0 (+ 1 (count2 (- n 1)))))";
+    eval_with(input2, &mut env, Engine::Bytecode).unwrap();
+
+    let depth = Budget {
+        depth: Some(500),
+        ..Budget::default()
+    };
+
+    let input3 = "(count 499)";
+    let result3 = eval_with_budget(input3, &mut env, Engine::TreeWalker, depth).unwrap();
+
+    assert_eq!(result3, Expression::Number(499.0));
+
+    let input4 = "(count 500)";
+    let result4 = eval_with_budget(input4, &mut env, Engine::TreeWalker, depth).unwrap_err();
+
+    assert_eq!(result4.kind, ErrorKind::Limit);
+
+    let input5 = "(count2 499)";
+    let result5 = eval_with_budget(input5, &mut env, Engine::Bytecode, depth).unwrap();
+
+    assert_eq!(result5, Expression::Number(499.0));
+
+    let input6 = "(count2 500)";
+    let result6 = eval_with_budget(input6, &mut env, Engine::Bytecode, depth).unwrap_err();
+
+    assert_eq!(result6.kind, ErrorKind::Limit);
+
+    // Tail calls don't add to it
+    let input7 = "(define (loop n) (if (= n 0) 'done (loop (- n 1))))";
+    eval(input7, &mut env).unwrap();
+
+    let input8 = "(loop 10000)";
+    let result8 = eval_with_budget(input8, &mut env, Engine::TreeWalker, depth).unwrap();
+
+    assert_eq!(result8, Expression::Symbol("done".into()));
+}
+
+#[test]
+fn test_eval_budget() {
+    let mut env = standard_env();
+
+    let input1 = "(define (spin n) (spin (+ n 1)))";
+    eval(input1, &mut env).unwrap();
+
+    let steps = Budget {
+        steps: Some(1000),
+        ..Budget::default()
+    };
+
+    let input2 = "(spin 0)";
+    let result2 = eval_with_budget(input2, &mut env, Engine::TreeWalker, steps).unwrap_err();
+
+    assert_eq!(result2.kind, ErrorKind::Limit);
+
+    let input3 = "(define (spin-fast n) (spin-fast (+ n 1)))";
+    eval_with(input3, &mut env, Engine::Bytecode).unwrap();
+
+    let input4 = "(spin-fast 0)";
+    let result4 = eval_with_budget(input4, &mut env, Engine::Bytecode, steps).unwrap_err();
+
+    assert_eq!(result4.kind, ErrorKind::Limit);
+
+    // Handlers don't get to see that the budget ran out
+    let input5 = "(guard (e (#t 'caught)) (spin 0))";
+    let result5 = eval_with_budget(input5, &mut env, Engine::TreeWalker, steps).unwrap_err();
+
+    assert_eq!(result5.kind, ErrorKind::Limit);
+
+    let input6 = "(define (grow xs) (grow (cons 1 xs)))";
+    eval(input6, &mut env).unwrap();
+
+    let allocations = Budget {
+        allocations: Some(10000),
+        ..Budget::default()
+    };
+
+    let input7 = "(grow '())";
+    let result7 = eval_with_budget(input7, &mut env, Engine::TreeWalker, allocations).unwrap_err();
+
+    assert_eq!(result7.kind, ErrorKind::Limit);
+
+    let input8 = "(define (count n) (if (= n 0) 0 (+ 1 (count (- n 1)))))";
+    eval(input8, &mut env).unwrap();
+
+    let depth = Budget {
+        depth: Some(500),
+        ..Budget::default()
+    };
+
+    let input9 = "(count 100)";
+    let result9 = eval_with_budget(input9, &mut env, Engine::TreeWalker, depth).unwrap();
+
+    assert_eq!(result9, Expression::Number(100.0));
+
+    let input10 = "(count 10000)";
+    let result10 = eval_with_budget(input10, &mut env, Engine::TreeWalker, depth).unwrap_err();
+
+    assert_eq!(result10.kind, ErrorKind::Limit);
+
+    let deadline = Budget {
+        deadline: Some(Instant::now() + Duration::from_millis(50)),
+        ..Budget::default()
+    };
+
+    let input11 = "(spin 0)";
+    let result11 = eval_with_budget(input11, &mut env, Engine::TreeWalker, deadline).unwrap_err();
+
+    assert_eq!(result11.kind, ErrorKind::Limit);
+
+    // The environment is still usable, and plain evaluation has no limits
+    let input12 = "(count 10000)";
+    let result12 = eval(input12, &mut env).unwrap();
+
+    assert_eq!(result12, Expression::Number(10000.0));
+
+    // Macro expansion is metered as well
+    let input13 = "(define-syntax forever (syntax-rules () ((_ x) (forever x))))";
+    eval(input13, &mut env).unwrap();
+
+    let steps_and_deadline = Budget {
+        steps: Some(1000),
+        deadline: Some(Instant::now() + Duration::from_millis(200)),
+        ..Budget::default()
+    };
+
+    let input14 = "(forever 1)";
+    let result14 =
+        eval_with_budget(input14, &mut env, Engine::TreeWalker, steps_and_deadline).unwrap_err();
+
+    assert_eq!(result14.kind, ErrorKind::Limit);
+    assert_eq!(result14.to_string(), "Step limit exceeded: 1000");
+
+    let input15 = "(define-syntax swell (syntax-rules () ((_ x) (swell (x x)))))";
+    eval(input15, &mut env).unwrap();
+
+    let input16 = "(swell 1)";
+    let result16 = eval_with_budget(input16, &mut env, Engine::Bytecode, allocations).unwrap_err();
+
+    assert_eq!(result16.kind, ErrorKind::Limit);
+    assert_eq!(result16.to_string(), "Allocation limit exceeded: 10000");
+}
+
+#[test]
+fn test_interrupt() {
+    let mut env = standard_env();
+
+    let input1 = "(define x 42)";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define (loop) (loop))";
+    eval(input2, &mut env).unwrap();
+
+    // Interrupt the loop from another thread, as the Ctrl-C handler does
+    let interrupt = || {
+        let flag = interrupt_flag();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flag.store(true, Ordering::Relaxed);
+        })
+    };
+
+    let interrupter = interrupt();
+    let input3 = "(guard (e (#t 'caught)) (loop))";
+    let result3 = eval(input3, &mut env).unwrap_err();
+    interrupter.join().unwrap();
+
+    assert_eq!(result3.kind, ErrorKind::Interrupted);
+
+    let input4 = "(define (spin) (spin))";
+    eval_with(input4, &mut env, Engine::Bytecode).unwrap();
+
+    let interrupter = interrupt();
+    let input5 = "(spin)";
+    let result5 = eval_with(input5, &mut env, Engine::Bytecode).unwrap_err();
+    interrupter.join().unwrap();
+
+    assert_eq!(result5.kind, ErrorKind::Interrupted);
+
+    // Expanding a macro that never stops can be interrupted too
+    let input6 = "(define-syntax forever (syntax-rules () ((_ x) (forever x))))";
+    eval(input6, &mut env).unwrap();
+
+    let interrupter = interrupt();
+    let input7 = "(forever 1)";
+    let result7 = eval(input7, &mut env).unwrap_err();
+    interrupter.join().unwrap();
+
+    assert_eq!(result7.kind, ErrorKind::Interrupted);
+
+    // The definitions are still there, and an interrupt that came while
+    // nothing was running doesn't stop the next evaluation
+    interrupt_flag().store(true, Ordering::Relaxed);
+
+    let input8 = "(+ x 1)";
+    let result8 = eval(input8, &mut env).unwrap();
+
+    assert_eq!(result8, Expression::Number(43.0));
+}
+
+#[test]
+fn test_shared_lists() {
+    let mut env = standard_env();
+
+    // Passing a list around and looking it up doesn't copy it, so building a
+    // deep one takes time in proportion to its depth
+    let input1 = "(define (nest n acc) (if (= n 0) acc (nest (- n 1) (list acc))))";
+    eval(input1, &mut env).unwrap();
+
+    let input2 = "(define nested (nest 10000 0))";
+    eval(input2, &mut env).unwrap();
+
+    let input3 = "(define (depth x) (if (number? x) 0 (+ 1 (depth (car x)))))";
+    eval(input3, &mut env).unwrap();
+
+    let input4 = "(depth nested)";
+    let result4 = eval(input4, &mut env).unwrap();
+
+    assert_eq!(result4, Expression::Number(10000.0));
+
+    // A list is the same object wherever it goes, and so is its tail
+    let input5 = "(define xs (list 1 2 3))";
+    eval(input5, &mut env).unwrap();
+
+    let input6 = "(let ((ys xs)) (list (eq? xs ys) (eq? (cdr xs) (cdr ys)) (eq? xs (list 1 2 3))))";
+    let result6 = eval(input6, &mut env).unwrap();
+
+    assert_eq!(
+        result6,
+        Expression::List(
+            vec![
+                Expression::Bool(true),
+                Expression::Bool(true),
+                Expression::Bool(false)
+            ]
+            .into()
+        )
+    );
+
+    let input7 = "(cdr (cdr xs))";
+    let result7 = eval(input7, &mut env).unwrap();
+
+    assert_eq!(
+        result7,
+        Expression::List(vec![Expression::Number(3.0)].into())
+    );
+
+    // `cons` shares the list it extends, so building a long list one
+    // element at a time takes time in proportion to its length
+    let input8 = "(define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))";
+    let input9 = "(define (size xs n) (if (null? xs) n (size (cdr xs) (+ n 1))))";
+    let input10 = "(define long (build 50000 '()))";
+    let input11 = "(list (car long) (car (cdr long)) (size long 0))";
+    eval(input8, &mut env).unwrap();
+    eval(input9, &mut env).unwrap();
+    eval(input10, &mut env).unwrap();
+    let result11 = eval(input11, &mut env).unwrap();
+
+    assert_eq!(result11, parse("(1 2 50000)").unwrap());
+
+    // Lists consed onto the same one don't see each other's elements
+    let input12 = "(define a (cons 1 xs))";
+    let input13 = "(define b (cons 2 xs))";
+    let input14 = "(list a b (cons 3 (cdr a)) xs)";
+    eval(input12, &mut env).unwrap();
+    eval(input13, &mut env).unwrap();
+    let result14 = eval(input14, &mut env).unwrap();
+
+    assert_eq!(
+        result14,
+        parse("((1 1 2 3) (2 1 2 3) (3 1 2 3) (1 2 3))").unwrap()
+    );
+}